@@ -0,0 +1,349 @@
+use std::{env, fmt, fs, sync::{atomic::{AtomicU64, Ordering}, OnceLock}};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Variable de entorno con la ruta del archivo de la clave compartida. Sin ella no se firman
+/// los mensajes ni se exige que lleguen firmados
+pub const AUTH_KEY_FILE_VAR: &str = "AUTH_KEY_FILE";
+/// Largo de la firma de un frame: sesión, número de secuencia, nodo que lo escribió, desafío de
+/// quien lo lee y HMAC-SHA256
+pub const SIGNATURE_LEN: usize = 8 + 8 + 8 + 8 + MAC_LEN;
+const MAC_LEN: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+static KEY: OnceLock<Option<Vec<u8>>> = OnceLock::new();
+// Id al azar de este proceso, que va en la firma de cada frame que escribe
+static NODE_ID: OnceLock<u64> = OnceLock::new();
+static UNSIGNED: AtomicU64 = AtomicU64::new(0);
+static FORGED: AtomicU64 = AtomicU64::new(0);
+static REPLAYED: AtomicU64 = AtomicU64::new(0);
+static REFLECTED: AtomicU64 = AtomicU64::new(0);
+
+/// Shared key read from the file in `AUTH_KEY_FILE`, or None if messages are not authenticated.
+/// Panics if the file can't be read or is empty, so a node is never started without the key it
+/// was configured with.
+pub fn shared_key() -> Option<&'static [u8]> {
+    KEY.get_or_init(|| {
+        let path = env::var(AUTH_KEY_FILE_VAR).ok()?;
+        let key = fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("[ERROR] Couldn't read the shared key from {}: {}", path, e));
+        let key = key.trim();
+        if key.is_empty() {
+            panic!("[ERROR] The shared key in {} is empty", path);
+        }
+        Some(key.as_bytes().to_vec())
+    })
+    .as_deref()
+}
+
+fn node_id() -> u64 {
+    *NODE_ID.get_or_init(rand::random)
+}
+
+/// Reason why a received frame was dropped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rejection {
+    Unsigned,
+    Forged,
+    Replayed,
+    Reflected,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::Unsigned => write!(f, "unsigned"),
+            Rejection::Forged => write!(f, "forged"),
+            Rejection::Replayed => write!(f, "replayed"),
+            Rejection::Reflected => write!(f, "reflected"),
+        }
+    }
+}
+
+/// Frames rejected by this process since it started, by reason.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RejectionCounts {
+    pub unsigned: u64,
+    pub forged: u64,
+    pub replayed: u64,
+    pub reflected: u64,
+}
+
+impl fmt::Display for RejectionCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unsigned: {}, forged: {}, replayed: {}, reflected: {}",
+            self.unsigned, self.forged, self.replayed, self.reflected
+        )
+    }
+}
+
+/// Current rejection counters of this process.
+pub fn rejections() -> RejectionCounts {
+    RejectionCounts {
+        unsigned: UNSIGNED.load(Ordering::Relaxed),
+        forged: FORGED.load(Ordering::Relaxed),
+        replayed: REPLAYED.load(Ordering::Relaxed),
+        reflected: REFLECTED.load(Ordering::Relaxed),
+    }
+}
+
+/// Counts the rejection and logs it with the counters.
+fn reject(rejection: Rejection) -> Rejection {
+    let counter = match rejection {
+        Rejection::Unsigned => &UNSIGNED,
+        Rejection::Forged => &FORGED,
+        Rejection::Replayed => &REPLAYED,
+        Rejection::Reflected => &REFLECTED,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+    println!("[AUTH] Rejected a message: {} ({})", rejection, rejections());
+    rejection
+}
+
+fn mac(key: &[u8], tag: u8, session: u64, seq: u64, node: u64, challenge: u64, payload: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(&[tag]);
+    mac.update(&session.to_be_bytes());
+    mac.update(&seq.to_be_bytes());
+    mac.update(&node.to_be_bytes());
+    mac.update(&challenge.to_be_bytes());
+    mac.update(payload);
+    mac
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes(bytes.try_into().unwrap_or_default())
+}
+
+/// Signer signs the frames written on a connection. Every connection has a random session, and
+/// every frame a sequence number one higher than the previous one, so a frame can't be sent
+/// again on the same connection. Once the other end sent its challenge in the handshake it is
+/// signed in every frame, so a frame can't be sent again on another connection. The id of the
+/// node that wrote the frame is signed too, so it can't be sent back to it.
+#[derive(Debug)]
+pub struct Signer {
+    key: &'static [u8],
+    node: u64,
+    session: u64,
+    seq: u64,
+    // Desafío del otro extremo, 0 hasta que lo manda en el handshake
+    challenge: u64,
+}
+
+impl Signer {
+    /// Signer for a new connection, or None if messages are not authenticated.
+    pub fn new() -> Option<Self> {
+        shared_key().map(|key| Signer::with_key(key, node_id()))
+    }
+
+    fn with_key(key: &'static [u8], node: u64) -> Self {
+        Signer { key, node, session: rand::random(), seq: 0, challenge: 0 }
+    }
+
+    /// Signs the next frames for the other end that sent `challenge`.
+    pub fn answer(&mut self, challenge: u64) {
+        self.challenge = challenge;
+    }
+
+    /// Signature of the next frame, with content `payload` and header tag `tag`.
+    pub fn sign(&mut self, tag: u8, payload: &[u8]) -> Vec<u8> {
+        self.seq += 1;
+        let mac = mac(self.key, tag, self.session, self.seq, self.node, self.challenge, payload);
+        let mut signature = Vec::with_capacity(SIGNATURE_LEN);
+        signature.extend_from_slice(&self.session.to_be_bytes());
+        signature.extend_from_slice(&self.seq.to_be_bytes());
+        signature.extend_from_slice(&self.node.to_be_bytes());
+        signature.extend_from_slice(&self.challenge.to_be_bytes());
+        signature.extend_from_slice(&mac.finalize().into_bytes());
+        signature
+    }
+}
+
+/// Verifier checks the frames read on a connection: they must be signed with the shared key,
+/// by another node, for the random challenge of this connection, and belong to the session of
+/// the first frame of the connection, with a sequence number higher than the last one received.
+/// Only the first frame may come without the challenge: it is the Hello of the other end, sent
+/// before it knew it.
+#[derive(Debug)]
+pub struct Verifier {
+    key: Option<&'static [u8]>,
+    node: u64,
+    challenge: u64,
+    // Sesión del otro extremo y último número de secuencia recibido de ella
+    session: Option<u64>,
+    last_seq: u64,
+}
+
+impl Default for Verifier {
+    fn default() -> Self {
+        Verifier::new()
+    }
+}
+
+impl Verifier {
+    /// Verifier for a new connection.
+    pub fn new() -> Self {
+        Verifier::with_key(shared_key(), node_id())
+    }
+
+    fn with_key(key: Option<&'static [u8]>, node: u64) -> Self {
+        Verifier { key, node, challenge: rand::random::<u64>().max(1), session: None, last_seq: 0 }
+    }
+
+    /// Challenge the other end has to sign in its frames, sent to it in the handshake.
+    pub fn challenge(&self) -> u64 {
+        self.challenge
+    }
+
+    /// Checks a frame, and returns its payload without the signature. If messages are not
+    /// authenticated every frame is accepted.
+    pub fn verify<'a>(&mut self, signed: bool, tag: u8, content: &'a [u8]) -> Result<&'a [u8], Rejection> {
+        let Some(key) = self.key else {
+            return Ok(if signed { content.get(SIGNATURE_LEN..).unwrap_or_default() } else { content });
+        };
+        if !signed || content.len() < SIGNATURE_LEN {
+            return Err(reject(Rejection::Unsigned));
+        }
+        let (signature, payload) = content.split_at(SIGNATURE_LEN);
+        let session = read_u64(&signature[..8]);
+        let seq = read_u64(&signature[8..16]);
+        let node = read_u64(&signature[16..24]);
+        let challenge = read_u64(&signature[24..32]);
+        if mac(key, tag, session, seq, node, challenge, payload).verify_slice(&signature[32..]).is_err() {
+            return Err(reject(Rejection::Forged));
+        }
+        if node == self.node {
+            return Err(reject(Rejection::Reflected));
+        }
+        // Un frame con otro desafío o de otra sesión viene de otra conexión
+        let answered = challenge == self.challenge || (challenge == 0 && self.session.is_none());
+        if !answered || self.session.is_some_and(|known| known != session) || seq <= self.last_seq {
+            return Err(reject(Rejection::Replayed));
+        }
+        self.session = Some(session);
+        self.last_seq = seq;
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"clave de prueba";
+    const TAG: u8 = 1;
+    const PAYLOAD: &[u8] = br#"{"message":"Heartbeat"}"#;
+
+    fn signed(signer: &mut Signer, payload: &[u8]) -> Vec<u8> {
+        let mut content = signer.sign(TAG, payload);
+        content.extend_from_slice(payload);
+        content
+    }
+
+    // Signer del otro extremo, después de recibir el desafío del Verifier en el handshake
+    fn answering(verifier: &Verifier) -> Signer {
+        let mut signer = Signer::with_key(KEY, 1);
+        signer.answer(verifier.challenge());
+        signer
+    }
+
+    #[test]
+    fn frames_in_order_are_accepted() {
+        let mut verifier = Verifier::with_key(Some(KEY), 2);
+        let mut signer = answering(&verifier);
+        for _ in 0..3 {
+            let content = signed(&mut signer, PAYLOAD);
+            assert_eq!(verifier.verify(true, TAG, &content), Ok(PAYLOAD));
+        }
+    }
+
+    #[test]
+    fn unsigned_frame_is_rejected() {
+        let mut verifier = Verifier::with_key(Some(KEY), 2);
+        assert_eq!(verifier.verify(false, TAG, PAYLOAD), Err(Rejection::Unsigned));
+    }
+
+    #[test]
+    fn forged_frame_is_rejected() {
+        let mut verifier = Verifier::with_key(Some(KEY), 2);
+        let mut content = signed(&mut Signer::with_key(KEY, 1), PAYLOAD);
+        *content.last_mut().unwrap() ^= 1;
+        assert_eq!(verifier.verify(true, TAG, &content), Err(Rejection::Forged));
+
+        let content = signed(&mut Signer::with_key(b"otra clave", 1), PAYLOAD);
+        assert_eq!(verifier.verify(true, TAG, &content), Err(Rejection::Forged));
+    }
+
+    #[test]
+    fn replayed_frame_is_rejected() {
+        let mut verifier = Verifier::with_key(Some(KEY), 2);
+        let mut signer = answering(&verifier);
+        let first = signed(&mut signer, PAYLOAD);
+        let second = signed(&mut signer, PAYLOAD);
+        assert!(verifier.verify(true, TAG, &second).is_ok());
+        assert_eq!(verifier.verify(true, TAG, &second), Err(Rejection::Replayed));
+        assert_eq!(verifier.verify(true, TAG, &first), Err(Rejection::Replayed));
+    }
+
+    #[test]
+    fn frame_from_another_connection_is_rejected() {
+        let mut verifier = Verifier::with_key(Some(KEY), 2);
+        let content = signed(&mut answering(&verifier), PAYLOAD);
+        assert!(verifier.verify(true, TAG, &content).is_ok());
+
+        // Un número de secuencia más alto, pero de otra sesión
+        let mut other = answering(&verifier);
+        other.sign(TAG, PAYLOAD);
+        let content = signed(&mut other, PAYLOAD);
+        assert_eq!(verifier.verify(true, TAG, &content), Err(Rejection::Replayed));
+    }
+
+    #[test]
+    fn captured_connection_replayed_on_a_new_one_is_rejected() {
+        let mut first = Verifier::with_key(Some(KEY), 2);
+        let mut signer = Signer::with_key(KEY, 1);
+        let hello = signed(&mut signer, PAYLOAD);
+        signer.answer(first.challenge());
+        let captured = [signed(&mut signer, PAYLOAD), signed(&mut signer, PAYLOAD)];
+        assert!(first.verify(true, TAG, &hello).is_ok());
+        for content in &captured {
+            assert!(first.verify(true, TAG, content).is_ok());
+        }
+
+        // Sólo el Hello pasa: los demás frames firmaron el desafío de la primera conexión
+        let mut second = Verifier::with_key(Some(KEY), 2);
+        assert!(second.verify(true, TAG, &hello).is_ok());
+        for content in &captured {
+            assert_eq!(second.verify(true, TAG, content), Err(Rejection::Replayed));
+        }
+    }
+
+    #[test]
+    fn only_the_first_frame_may_come_without_the_challenge() {
+        let mut verifier = Verifier::with_key(Some(KEY), 2);
+        let mut signer = Signer::with_key(KEY, 1);
+        let hello = signed(&mut signer, PAYLOAD);
+        let next = signed(&mut signer, PAYLOAD);
+        assert!(verifier.verify(true, TAG, &hello).is_ok());
+        assert_eq!(verifier.verify(true, TAG, &next), Err(Rejection::Replayed));
+    }
+
+    #[test]
+    fn reflected_frame_is_rejected() {
+        let mut verifier = Verifier::with_key(Some(KEY), 1);
+        let content = signed(&mut Signer::with_key(KEY, 1), PAYLOAD);
+        assert_eq!(verifier.verify(true, TAG, &content), Err(Rejection::Reflected));
+    }
+
+    #[test]
+    fn without_key_the_signature_is_stripped() {
+        let mut verifier = Verifier::with_key(None, 2);
+        let content = signed(&mut Signer::with_key(KEY, 1), PAYLOAD);
+        assert_eq!(verifier.verify(true, TAG, &content), Ok(PAYLOAD));
+        assert_eq!(verifier.verify(false, TAG, PAYLOAD), Ok(PAYLOAD));
+    }
+}
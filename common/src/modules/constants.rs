@@ -1,4 +1,6 @@
 pub const GATEWAY_PORT: u16 = 20_000;
+/// Puerto donde atiende el Gateway de respaldo cuando reemplaza al principal
+pub const GATEWAY_BACKUP_PORT: u16 = 20_001;
 pub const ROBOT_0_PORT: u16 = 30_000;
 
 pub const ROBOT_COUNT: usize = 3;
@@ -1,14 +1,18 @@
 use serde::{Serialize, Deserialize};
 
+use super::logical_clock::ClockStamp;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Disconnect {
     pub ip: String,
     pub id: String,
-    pub message: String
+    pub message: String,
+    #[serde(default)]
+    pub clock: ClockStamp
 }
 
 impl Disconnect {
     pub fn new(_ip: String, _id: String) -> Self {
-        Disconnect { ip: _ip, id: _id, message: "Disconnect".to_string() }
+        Disconnect { ip: _ip, id: _id, message: "Disconnect".to_string(), clock: ClockStamp::default() }
     }
 }
\ No newline at end of file
@@ -0,0 +1,67 @@
+use serde::{Deserialize, Serialize};
+
+use super::logical_clock::ClockStamp;
+
+pub const INITIAL_FLAVOUR_AMOUNT: f64 = 10.0;
+pub const FLAVOURS: [&str; 3] = ["Vainilla", "Dulce de leche", "Tramontana"];
+
+/// Flavour structure.
+/// It is passed down to the next Robot in the ring to be consumed if needed.
+/// `epoch` is increased every time a Robot forwards the token, so the copy with the highest
+/// epoch is the most recent one.
+/// `tickets` is the waiting list of the Orders that need the Flavour, served in ticket order
+/// instead of ring position.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Flavour {
+    pub name: String,
+    pub amount: f64,
+    #[serde(default)]
+    pub epoch: u64,
+    #[serde(default)]
+    pub tickets: Vec<Ticket>,
+    #[serde(default)]
+    pub clock: ClockStamp,
+}
+
+impl Flavour {
+    /// Flavour constructor.
+    pub fn new(name: String, amount: f64) -> Self {
+        Flavour { name, amount, epoch: 0, tickets: Vec::new(), clock: ClockStamp::default() }
+    }
+}
+
+/// Place of an Order in the waiting list of a Flavour.
+/// Tickets are ordered by priority, then by the Lamport timestamp of the Order and then by Robot
+/// id, so every Robot sees the same order.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Ticket {
+    pub robot: usize,
+    pub screen: String,
+    pub order_id: usize,
+    pub stamp: u64,
+    pub priority: u64,
+    pub amount: f64,
+    pub epoch: u64, // Epoch del token la última vez que lo vio su Robot, para descartar tickets de Robots caídos
+}
+
+impl Ticket {
+    /// Key to sort the waiting list: higher priority first, then older Orders.
+    pub fn sort_key(&self) -> (std::cmp::Reverse<u64>, u64, usize) {
+        (std::cmp::Reverse(self.priority), self.stamp, self.robot)
+    }
+}
+
+/// FlavourAck message, sent back to the previous Robot once a Flavour token arrived.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FlavourAck {
+    pub message: String, // "FlavourAck"
+    pub flavour: String,
+    #[serde(default)]
+    pub clock: ClockStamp,
+}
+
+impl FlavourAck {
+    pub fn new(flavour: String) -> Self {
+        FlavourAck { message: "FlavourAck".to_string(), flavour, clock: ClockStamp::default() }
+    }
+}
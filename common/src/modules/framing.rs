@@ -0,0 +1,353 @@
+use std::{env, io::{Error, ErrorKind}};
+
+use bytes::{Buf, BufMut, BytesMut};
+use serde_json::Value;
+use tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio_util::codec::{Decoder, Encoder, FramedRead};
+
+use super::auth::{Signer, Verifier};
+use super::logical_clock::without_vector_clock;
+
+/// Variable de entorno que elige el formato de los mensajes: "json" (por defecto) o "msgpack"
+pub const WIRE_CODEC_VAR: &str = "WIRE_CODEC";
+/// Largo máximo del contenido de un frame, para no reservar memoria por un largo corrupto
+pub const MAX_FRAME_LEN: usize = 1 << 20;
+// Largo (u32 big endian) + formato del contenido
+const HEADER_LEN: usize = 5;
+// Bit del formato que indica que el contenido empieza con una firma
+const SIGNED: u8 = 0x80;
+
+/// Format of the content of a frame. Every frame says its format, so peers configured with
+/// different formats still understand each other.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WireFormat {
+    Json,
+    MessagePack,
+}
+
+impl WireFormat {
+    /// Format chosen with the `WIRE_CODEC` environment variable. JSON unless it is "msgpack".
+    pub fn from_env() -> Self {
+        match env::var(WIRE_CODEC_VAR) {
+            Ok(codec) if codec.eq_ignore_ascii_case("msgpack") => WireFormat::MessagePack,
+            _ => WireFormat::Json,
+        }
+    }
+
+    fn tag(&self) -> u8 {
+        match self {
+            WireFormat::Json => 0,
+            WireFormat::MessagePack => 1,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<Self, Error> {
+        match tag {
+            0 => Ok(WireFormat::Json),
+            1 => Ok(WireFormat::MessagePack),
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("unknown frame format {}", tag))),
+        }
+    }
+}
+
+/// FrameCodec splits a connection into length-prefixed frames, each one carrying a JSON
+/// message. Messages are still handled as JSON strings by the actors; only their encoding on
+/// the wire depends on the format.
+/// If a shared key is configured, the frames written are signed and the frames read must be
+/// signed; a rejected one is an error, and ends the connection.
+/// Without `vector_clocks`, the vector clocks are taken out of the messages written and read.
+#[derive(Debug)]
+pub struct FrameCodec {
+    format: WireFormat,
+    vector_clocks: bool,
+    signer: Option<Signer>,
+    verifier: Verifier,
+}
+
+impl FrameCodec {
+    pub fn new(format: WireFormat) -> Self {
+        FrameCodec { format, vector_clocks: true, signer: Signer::new(), verifier: Verifier::new() }
+    }
+
+    /// Encodes the message as a whole frame, ready to be written on a connection.
+    pub fn frame(&mut self, message: &str) -> Result<Vec<u8>, Error> {
+        let mut frame = BytesMut::new();
+        self.encode(message.to_string(), &mut frame)?;
+        Ok(frame.to_vec())
+    }
+
+    /// Splits the codec of a connection in the one of its reading half, that keeps the
+    /// verifier, and the one of its writing half, that keeps the signer.
+    fn split(self) -> (FrameCodec, FrameCodec) {
+        let reading = FrameCodec { signer: None, ..self };
+        let writing = FrameCodec { verifier: Verifier::new(), ..self };
+        (reading, writing)
+    }
+
+    fn encode_payload(&self, message: &str) -> Result<Vec<u8>, Error> {
+        let message = message.trim_end();
+        match self.format {
+            WireFormat::Json => Ok(message.as_bytes().to_vec()),
+            WireFormat::MessagePack => {
+                let value: Value = serde_json::from_str(message)
+                    .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+                rmp_serde::to_vec(&value).map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+            }
+        }
+    }
+
+    fn decode_payload(format: WireFormat, payload: &[u8]) -> Result<String, Error> {
+        match format {
+            WireFormat::Json => String::from_utf8(payload.to_vec())
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+            WireFormat::MessagePack => {
+                let value: Value = rmp_serde::from_slice(payload)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                serde_json::to_string(&value).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+            }
+        }
+    }
+}
+
+/// Length of the content of a frame and its tag, read from its header.
+fn parse_header(header: &[u8]) -> Result<(usize, u8), Error> {
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(Error::new(ErrorKind::InvalidData, format!("frame of {} bytes is too long", len)));
+    }
+    Ok((len, header[4]))
+}
+
+impl Decoder for FrameCodec {
+    type Item = String;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<String>, Error> {
+        if src.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let (len, tag) = parse_header(&src[..HEADER_LEN])?;
+        if src.len() < HEADER_LEN + len {
+            src.reserve(HEADER_LEN + len - src.len());
+            return Ok(None);
+        }
+        src.advance(HEADER_LEN);
+        let content = src.split_to(len);
+        let payload = self
+            .verifier
+            .verify(tag & SIGNED != 0, tag, &content)
+            .map_err(|rejection| Error::new(ErrorKind::InvalidData, format!("message rejected: {}", rejection)))?;
+        let message = FrameCodec::decode_payload(WireFormat::from_tag(tag & !SIGNED)?, payload)?;
+        Ok(Some(if self.vector_clocks { message } else { without_vector_clock(message) }))
+    }
+}
+
+impl Encoder<String> for FrameCodec {
+    type Error = Error;
+
+    fn encode(&mut self, message: String, dst: &mut BytesMut) -> Result<(), Error> {
+        let message = if self.vector_clocks { message } else { without_vector_clock(message) };
+        let payload = self.encode_payload(&message)?;
+        let (tag, signature) = match self.signer.as_mut() {
+            Some(signer) => {
+                let tag = self.format.tag() | SIGNED;
+                (tag, signer.sign(tag, &payload))
+            }
+            None => (self.format.tag(), Vec::new()),
+        };
+        let len = signature.len() + payload.len();
+        if len > MAX_FRAME_LEN {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("message of {} bytes is too long", len)));
+        }
+        dst.reserve(HEADER_LEN + len);
+        dst.put_u32(len as u32);
+        dst.put_u8(tag);
+        dst.put_slice(&signature);
+        dst.put_slice(&payload);
+        Ok(())
+    }
+}
+
+/// Stream of the messages read from a connection, one per frame.
+pub type FrameReader<R> = FramedRead<R, FrameCodec>;
+
+/// FrameWriter writes every message as a whole frame, so two messages can't be merged.
+pub struct FrameWriter<W> {
+    write: W,
+    codec: FrameCodec,
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    /// Writes the message as a frame. A TLS connection keeps what is written until it is
+    /// flushed, so the frame is flushed right away.
+    pub async fn send(&mut self, message: &str) -> Result<(), Error> {
+        let frame = self.codec.frame(message)?;
+        self.write.write_all(&frame).await?;
+        self.write.flush().await
+    }
+
+    /// Closes the connection for writing, so the other end reads its end.
+    pub async fn close(&mut self) -> Result<(), Error> {
+        self.write.shutdown().await
+    }
+}
+
+/// FramedStream is a new connection, during its handshake. It has the one codec of the
+/// connection: the handshake is written in JSON, and then the codec uses the features both ends
+/// agreed on. Once the handshake is over it is split into the reader and the writer of the
+/// connection, which keep using it.
+pub struct FramedStream<S> {
+    stream: S,
+    codec: FrameCodec,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> FramedStream<S> {
+    pub fn new(stream: S) -> Self {
+        FramedStream { stream, codec: FrameCodec::new(WireFormat::Json) }
+    }
+
+    /// Uses the optional features both ends agreed on for the rest of the connection: the
+    /// format chosen with `WIRE_CODEC` only if both read MessagePack, and vector clocks only if
+    /// both use them.
+    pub fn agree(&mut self, features: &[String]) {
+        let agreed = |feature: &str| features.iter().any(|agreed| agreed == feature);
+        self.codec.format = if agreed("msgpack") { WireFormat::from_env() } else { WireFormat::Json };
+        self.codec.vector_clocks = agreed("vector_clocks");
+    }
+
+    /// Challenge the other end has to sign in the frames it writes on this connection.
+    pub fn challenge(&self) -> u64 {
+        self.codec.verifier.challenge()
+    }
+
+    /// Signs the next frames with the challenge the other end sent in the handshake.
+    pub fn answer(&mut self, challenge: u64) {
+        if let Some(signer) = self.codec.signer.as_mut() {
+            signer.answer(challenge);
+        }
+    }
+
+    /// The connection, to get its addresses.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Writes the message as a frame.
+    pub async fn send(&mut self, message: &str) -> Result<(), Error> {
+        let frame = self.codec.frame(message)?;
+        self.stream.write_all(&frame).await?;
+        self.stream.flush().await
+    }
+
+    /// Reads the next message. Only its frame is read, the ones after it are left for the
+    /// reader of the connection. Fails if the connection ends first.
+    pub async fn next(&mut self) -> Result<String, Error> {
+        let mut frame = BytesMut::zeroed(HEADER_LEN);
+        self.stream.read_exact(&mut frame).await?;
+        let (len, _) = parse_header(&frame)?;
+        frame.resize(HEADER_LEN + len, 0);
+        self.stream.read_exact(&mut frame[HEADER_LEN..]).await?;
+        self.codec.decode(&mut frame)?.ok_or_else(|| Error::new(ErrorKind::InvalidData, "incomplete frame"))
+    }
+
+    /// Reader and writer of the rest of the connection.
+    pub fn split(self) -> (FrameReader<ReadHalf<S>>, FrameWriter<WriteHalf<S>>) {
+        let (read, write) = split(self.stream);
+        let (reading, writing) = self.codec.split();
+        (FramedRead::new(read, reading), FrameWriter { write, codec: writing })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: &str = r#"{"message":"Heartbeat"}"#;
+
+    fn codec(format: WireFormat) -> FrameCodec {
+        FrameCodec { format, vector_clocks: true, signer: None, verifier: Verifier::new() }
+    }
+
+    #[test]
+    fn frame_split_in_two_reads_is_decoded_once_complete() {
+        let frame = codec(WireFormat::Json).frame(MESSAGE).unwrap();
+        let mut reader = codec(WireFormat::Json);
+        let mut buffer = BytesMut::from(&frame[..3]);
+        assert_eq!(reader.decode(&mut buffer).unwrap(), None);
+        buffer.extend_from_slice(&frame[3..HEADER_LEN + 4]);
+        assert_eq!(reader.decode(&mut buffer).unwrap(), None);
+        buffer.extend_from_slice(&frame[HEADER_LEN + 4..]);
+        assert_eq!(reader.decode(&mut buffer).unwrap(), Some(MESSAGE.to_string()));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn frames_read_together_are_decoded_in_order() {
+        let mut writer = codec(WireFormat::Json);
+        let mut buffer = BytesMut::from(&writer.frame(MESSAGE).unwrap()[..]);
+        buffer.extend_from_slice(&writer.frame(r#"{"message":"Other"}"#).unwrap());
+        let mut reader = codec(WireFormat::Json);
+        assert_eq!(reader.decode(&mut buffer).unwrap(), Some(MESSAGE.to_string()));
+        assert_eq!(reader.decode(&mut buffer).unwrap(), Some(r#"{"message":"Other"}"#.to_string()));
+        assert_eq!(reader.decode(&mut buffer).unwrap(), None);
+    }
+
+    #[test]
+    fn oversized_frame_is_an_error() {
+        let mut buffer = BytesMut::new();
+        buffer.put_u32(MAX_FRAME_LEN as u32 + 1);
+        buffer.put_u8(WireFormat::Json.tag());
+        let error = codec(WireFormat::Json).decode(&mut buffer).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn oversized_message_is_not_written() {
+        let message = format!(r#"{{"message":"{}"}}"#, "a".repeat(MAX_FRAME_LEN));
+        let error = codec(WireFormat::Json).frame(&message).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn unknown_format_is_an_error() {
+        let mut buffer = BytesMut::new();
+        buffer.put_u32(2);
+        buffer.put_u8(7);
+        buffer.put_slice(b"{}");
+        assert!(codec(WireFormat::Json).decode(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn message_pack_frame_is_read_as_json() {
+        let frame = codec(WireFormat::MessagePack).frame(MESSAGE).unwrap();
+        assert_eq!(frame[HEADER_LEN - 1], WireFormat::MessagePack.tag());
+        let mut buffer = BytesMut::from(&frame[..]);
+        // Quien lee entiende el formato del frame, aunque escriba JSON
+        assert_eq!(codec(WireFormat::Json).decode(&mut buffer).unwrap(), Some(MESSAGE.to_string()));
+    }
+
+    #[test]
+    fn vector_clocks_are_left_out_unless_agreed() {
+        let message = r#"{"message":"Heartbeat","clock":{"lamport":3,"vector":{"GATEWAY":3}}}"#;
+        let mut writer = codec(WireFormat::Json);
+        writer.vector_clocks = false;
+        let mut buffer = BytesMut::from(&writer.frame(message).unwrap()[..]);
+        assert_eq!(codec(WireFormat::Json).decode(&mut buffer).unwrap(), Some(r#"{"clock":{"lamport":3},"message":"Heartbeat"}"#.to_string()));
+
+        let mut buffer = BytesMut::from(&codec(WireFormat::Json).frame(message).unwrap()[..]);
+        let mut reader = codec(WireFormat::Json);
+        reader.vector_clocks = false;
+        assert!(!reader.decode(&mut buffer).unwrap().unwrap().contains("vector"));
+    }
+
+    #[test]
+    fn message_pack_is_only_used_if_agreed() {
+        let mut stream = FramedStream::new(tokio::io::empty());
+        stream.agree(&["vector_clocks".to_string()]);
+        assert_eq!(stream.codec.format, WireFormat::Json);
+        assert!(stream.codec.vector_clocks);
+        stream.agree(&["msgpack".to_string()]);
+        assert_eq!(stream.codec.format, WireFormat::from_env());
+        assert!(!stream.codec.vector_clocks);
+    }
+}
@@ -0,0 +1,152 @@
+use std::{io::{Error, ErrorKind}, time::Duration};
+
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::timeout;
+
+use super::framing::FramedStream;
+use super::hello::{Hello, FEATURES, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use super::welcome::Welcome;
+
+/// Tiempo máximo que tiene el otro extremo para presentarse o responder el Hello
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The other end of a connection as it introduced itself, with the protocol version and the
+/// features both ends agreed on.
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub role: String,
+    pub id: String,
+    pub version: u32,
+    pub features: Vec<String>,
+    // Época del Gateway que respondió el Hello, 0 para los demás nodos
+    pub epoch: u64,
+}
+
+/// Highest protocol version spoken by both this node and the one that sent the Hello, and the
+/// features both support. Returns why they can't talk if there is no common version.
+pub fn negotiate(hello: &Hello) -> Result<(u32, Vec<String>), String> {
+    if hello.max_version == 0 {
+        return Err("the node doesn't negotiate a protocol version, it is older than this one".to_string());
+    }
+    let version = PROTOCOL_VERSION.min(hello.max_version);
+    if version < MIN_PROTOCOL_VERSION.max(hello.min_version) {
+        return Err(format!(
+            "protocol versions {}-{} are not supported, this node speaks {}-{}",
+            hello.min_version, hello.max_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ));
+    }
+    let features = hello
+        .features
+        .iter()
+        .filter(|feature| FEATURES.contains(&feature.as_str()))
+        .cloned()
+        .collect();
+    Ok((version, features))
+}
+
+fn parse<T: DeserializeOwned>(message: &str, name: &str) -> Result<T, Error> {
+    serde_json::from_str(message)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("expected a {}: {}", name, e)))
+}
+
+fn timed_out(waiting: &str) -> Error {
+    Error::new(ErrorKind::TimedOut, format!("no {} in {} seconds", waiting, HANDSHAKE_TIMEOUT.as_secs()))
+}
+
+/// Checks the answer to the Hello of this node.
+fn check_welcome(welcome: Welcome) -> Result<PeerInfo, Error> {
+    if let Some(error) = welcome.error {
+        return Err(Error::new(ErrorKind::ConnectionRefused, format!("refused by {} {}: {}", welcome.role, welcome.id, error)));
+    }
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&welcome.version) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{} {} chose the unsupported protocol version {}", welcome.role, welcome.id, welcome.version),
+        ));
+    }
+    Ok(PeerInfo { role: welcome.role, id: welcome.id, version: welcome.version, features: welcome.features, epoch: welcome.epoch })
+}
+
+/// Introduces this node with a Hello on a connection it opened, and waits for the Welcome.
+/// Fails if the other end refused the connection or didn't answer in time.
+pub async fn introduce<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut FramedStream<S>, role: &str, id: String) -> Result<PeerInfo, Error> {
+    let hello = Hello { challenge: stream.challenge(), ..Hello::new(role, id) };
+    stream.send(&hello.to_json()).await?;
+    let welcome = timeout(HANDSHAKE_TIMEOUT, stream.next()).await.map_err(|_| timed_out("Welcome"))??;
+    let welcome: Welcome = parse(&welcome, "Welcome")?;
+    stream.answer(welcome.challenge);
+    let peer = check_welcome(welcome)?;
+    stream.agree(&peer.features);
+    Ok(peer)
+}
+
+/// Waits for the Hello of a connection this node accepted and answers it with a Welcome. The
+/// connection is refused if the role of the other end is not one of `roles` or there is no
+/// protocol version both speak; the Welcome tells the other end why.
+pub async fn welcome<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut FramedStream<S>, role: &str, id: String, roles: &[&str]) -> Result<PeerInfo, Error> {
+    welcome_with_epoch(stream, role, id, roles, 0).await
+}
+
+/// Like `welcome`, for a Gateway serving as primary: the Welcome has the epoch of its term, so
+/// the other end can tell a Gateway that isn't the primary anymore apart.
+pub async fn welcome_with_epoch<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut FramedStream<S>, role: &str, id: String, roles: &[&str], epoch: u64) -> Result<PeerInfo, Error> {
+    let hello = timeout(HANDSHAKE_TIMEOUT, stream.next()).await.map_err(|_| timed_out("Hello"))??;
+    let hello: Hello = parse(&hello, "Hello")?;
+    // También el Welcome que rechaza la conexión tiene que llegar firmado para el otro extremo
+    stream.answer(hello.challenge);
+    let negotiated = if roles.contains(&hello.role.as_str()) {
+        negotiate(&hello)
+    } else {
+        Err(format!("{} connections are not accepted", hello.role))
+    };
+    match negotiated {
+        Ok((version, features)) => {
+            let welcome = Welcome { epoch, challenge: stream.challenge(), ..Welcome::new(role, id, version, features.clone()) };
+            stream.send(&welcome.to_json()).await?;
+            stream.agree(&features);
+            Ok(PeerInfo { role: hello.role, id: hello.id, version, features, epoch: 0 })
+        }
+        Err(error) => {
+            // Si no le llega el motivo, igual se le cierra la conexión
+            let _ = stream.send(&Welcome::refuse(role, id, error.clone()).to_json()).await;
+            Err(Error::new(ErrorKind::ConnectionRefused, format!("{} {}: {}", hello.role, hello.id, error)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(min_version: u32, max_version: u32, features: &[&str]) -> Hello {
+        Hello {
+            min_version,
+            max_version,
+            features: features.iter().map(|feature| feature.to_string()).collect(),
+            ..Hello::new("screen", "0".to_string())
+        }
+    }
+
+    #[test]
+    fn highest_common_version_is_chosen() {
+        let (version, _) = negotiate(&hello(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION + 3, &[])).unwrap();
+        assert_eq!(version, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn node_without_versions_is_refused() {
+        assert!(negotiate(&hello(0, 0, &[])).is_err());
+    }
+
+    #[test]
+    fn node_without_common_version_is_refused() {
+        assert!(negotiate(&hello(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 2, &[])).is_err());
+    }
+
+    #[test]
+    fn only_features_both_support_are_agreed() {
+        let (_, features) = negotiate(&hello(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, &["msgpack", "compression"])).unwrap();
+        assert_eq!(features, vec!["msgpack".to_string()]);
+    }
+}
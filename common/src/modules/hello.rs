@@ -0,0 +1,58 @@
+use serde::{Serialize, Deserialize};
+
+use super::logical_clock::ClockStamp;
+
+/// Role de una Pantalla
+pub const ROLE_SCREEN: &str = "screen";
+/// Role de un Robot
+pub const ROLE_ROBOT: &str = "robot";
+/// Role de un Gateway
+pub const ROLE_GATEWAY: &str = "gateway";
+
+/// Versión del protocolo que habla este nodo
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Versión más vieja del protocolo con la que este nodo todavía se entiende
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Funcionalidades opcionales que soporta este nodo
+pub const FEATURES: [&str; 2] = ["msgpack", "vector_clocks"];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Hello struct is the first message sent on every new connection, so the actor that accepts it
+/// knows if a Screen, a Robot or a Gateway is connecting, and which protocol versions it speaks.
+/// A Hello without versions comes from a node older than the negotiation, and is refused.
+/// `challenge` is the one the other end has to sign in its frames.
+pub struct Hello {
+    pub message: String, // "Hello"
+    pub role: String,
+    pub id: String,
+    #[serde(default)]
+    pub min_version: u32,
+    #[serde(default)]
+    pub max_version: u32,
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default)]
+    pub challenge: u64,
+    #[serde(default)]
+    pub clock: ClockStamp,
+}
+
+impl Hello {
+    pub fn new(role: &str, id: String) -> Self {
+        Hello {
+            message: "Hello".to_string(),
+            role: role.to_string(),
+            id,
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            features: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+            challenge: 0,
+            clock: ClockStamp::default()
+        }
+    }
+
+    /// Hello serialized, ready to be written as the first frame of a new connection.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
@@ -0,0 +1,67 @@
+use serde::{Serialize, Deserialize};
+
+use super::flavour::Flavour;
+use super::logical_clock::ClockStamp;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// InventoryQuery struct circulates once through the ring, collecting the latest copy of each
+/// Flavour seen by every Robot. `ip` identifies who asked for the inventory (empty if it was
+/// asked through the Robot's terminal) and `origin` is the id of the Robot that started the round.
+pub struct InventoryQuery {
+    pub message: String, // "InventoryQuery"
+    pub ip: String,
+    pub id: usize,
+    pub origin: Option<usize>,
+    pub flavours: Vec<Flavour>,
+    #[serde(default)]
+    pub clock: ClockStamp,
+}
+
+impl InventoryQuery {
+    /// Create a new InventoryQuery instance.
+    pub fn new(ip: String, id: usize) -> Self {
+        InventoryQuery {
+            message: "InventoryQuery".to_string(),
+            ip,
+            id,
+            origin: None,
+            flavours: Vec::new(),
+            clock: ClockStamp::default(),
+        }
+    }
+
+    /// Merges the copies of the Flavours seen by a Robot, keeping the one with the highest epoch.
+    pub fn merge<'a>(&mut self, seen: impl Iterator<Item = &'a Flavour>) {
+        for flavour in seen {
+            match self.flavours.iter_mut().find(|f| f.name == flavour.name) {
+                Some(known) if known.epoch < flavour.epoch => *known = flavour.clone(),
+                Some(_) => {}
+                None => self.flavours.push(flavour.clone()),
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// InventoryReport struct is the answer to an InventoryQuery, with the amount and epoch of every Flavour.
+pub struct InventoryReport {
+    pub message: String, // "InventoryReport"
+    pub ip: String,
+    pub id: usize,
+    pub flavours: Vec<Flavour>,
+    #[serde(default)]
+    pub clock: ClockStamp,
+}
+
+impl InventoryReport {
+    /// Create a new InventoryReport instance.
+    pub fn new(ip: String, id: usize, flavours: Vec<Flavour>) -> Self {
+        InventoryReport {
+            message: "InventoryReport".to_string(),
+            ip,
+            id,
+            flavours,
+            clock: ClockStamp::default(),
+        }
+    }
+}
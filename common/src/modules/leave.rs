@@ -0,0 +1,34 @@
+use std::collections::HashMap;
+
+use serde::{Serialize, Deserialize};
+
+use super::logical_clock::ClockStamp;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Leave struct is sent by a Robot that is leaving the ring to its previous Robot, so it
+/// connects directly to the Robot that comes after the one leaving. `returns` has the stock of
+/// Flavours the leaving Robot still has to give back to their tokens, which the previous Robot
+/// gives back instead.
+pub struct Leave {
+    pub message: String, // "Leave"
+    pub ip: String,
+    pub id: usize,
+    pub successor: usize,
+    #[serde(default)]
+    pub returns: HashMap<String, f64>,
+    #[serde(default)]
+    pub clock: ClockStamp,
+}
+
+impl Leave {
+    pub fn new(ip: String, id: usize, successor: usize) -> Self {
+        Leave {
+            message: "Leave".to_string(),
+            ip,
+            id,
+            successor,
+            returns: HashMap::new(),
+            clock: ClockStamp::default()
+        }
+    }
+}
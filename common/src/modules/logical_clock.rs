@@ -0,0 +1,115 @@
+use std::{collections::BTreeMap, env, fmt};
+
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+/// Si está definida, los relojes también llevan un reloj vectorial además del de Lamport
+pub const VECTOR_CLOCKS_VAR: &str = "VECTOR_CLOCKS";
+
+/// Logical time carried by every message. `vector` is only present if vector clocks are enabled.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ClockStamp {
+    pub lamport: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vector: Option<BTreeMap<String, u64>>,
+}
+
+impl fmt::Display for ClockStamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "L{}", self.lamport)?;
+        if let Some(vector) = &self.vector {
+            let entries: Vec<String> = vector.iter().map(|(process, time)| format!("{}:{}", process, time)).collect();
+            write!(f, " V{{{}}}", entries.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+/// The message without the vector clock of its `clock` field, for a peer that didn't agree to
+/// use vector clocks. Other messages are returned unchanged.
+pub fn without_vector_clock(message: String) -> String {
+    if !message.contains("\"vector\"") {
+        return message;
+    }
+    match serde_json::from_str::<Value>(&message) {
+        Ok(Value::Object(mut fields)) => {
+            if let Some(Value::Object(clock)) = fields.get_mut("clock") {
+                clock.remove("vector");
+            }
+            Value::Object(fields).to_string()
+        }
+        _ => message,
+    }
+}
+
+/// Logical clock of a process (gateway, screen or robot). It is ticked on every send, and
+/// updated with the clock of every received message, so the logs of every process can be
+/// ordered afterwards.
+pub struct LogicalClock {
+    process: String,
+    lamport: u64,
+    vector: Option<BTreeMap<String, u64>>,
+}
+
+impl LogicalClock {
+    /// Create a new LogicalClock for the given process. Vector clocks are enabled with the
+    /// `VECTOR_CLOCKS` environment variable.
+    pub fn new(process: String) -> Self {
+        let vector = env::var(VECTOR_CLOCKS_VAR).ok().map(|_| BTreeMap::new());
+        LogicalClock { process, lamport: 0, vector }
+    }
+
+    /// Current time of the clock.
+    pub fn now(&self) -> ClockStamp {
+        ClockStamp { lamport: self.lamport, vector: self.vector.clone() }
+    }
+
+    /// Advances the clock for a local event, and returns the new time.
+    pub fn tick(&mut self) -> ClockStamp {
+        self.lamport += 1;
+        if let Some(vector) = self.vector.as_mut() {
+            *vector.entry(self.process.to_string()).or_insert(0) += 1;
+        }
+        self.now()
+    }
+
+    /// Moves the Lamport clock forward to a time seen outside a message, like a restored state.
+    pub fn witness(&mut self, lamport: u64) {
+        self.lamport = self.lamport.max(lamport);
+    }
+
+    /// Updates the clock with the time of a received message and ticks it.
+    pub fn observe(&mut self, stamp: &ClockStamp) -> ClockStamp {
+        self.witness(stamp.lamport);
+        if let (Some(vector), Some(received)) = (self.vector.as_mut(), stamp.vector.as_ref()) {
+            for (process, time) in received {
+                let known = vector.entry(process.to_string()).or_insert(0);
+                *known = (*known).max(*time);
+            }
+        }
+        self.tick()
+    }
+
+    /// Ticks the clock and writes its time in the `clock` field of a JSON message. Messages that
+    /// are not JSON objects are returned unchanged, without trailing whitespace.
+    pub fn stamp(&mut self, msg: &str) -> String {
+        let msg = msg.trim_end();
+        match serde_json::from_str::<Value>(msg) {
+            Ok(Value::Object(mut fields)) => {
+                let stamp = self.tick();
+                fields.insert("clock".to_string(), serde_json::to_value(stamp).unwrap_or_default());
+                Value::Object(fields).to_string()
+            }
+            _ => msg.to_string(),
+        }
+    }
+
+    /// Reads the `clock` field of a received message and updates the clock with it. Returns
+    /// the time of the sender, if the message had one.
+    pub fn receive(&mut self, line: &str) -> Option<ClockStamp> {
+        let value = serde_json::from_str::<Value>(line).ok()?;
+        let stamp: ClockStamp = serde_json::from_value(value.get("clock")?.clone()).ok()?;
+        self.observe(&stamp);
+        Some(stamp)
+    }
+}
@@ -1,7 +1,23 @@
 pub mod order_prep;
 pub mod order_json;
 pub mod order_request;
+pub mod order_release;
+pub mod priority;
 pub mod payment_capture;
 pub mod payment_confirmation;
+pub mod payment_void;
+pub mod payment_ack;
 pub mod disconnect;
-pub mod constants;
\ No newline at end of file
+pub mod hello;
+pub mod welcome;
+pub mod handshake;
+pub mod leave;
+pub mod system_shutdown;
+pub mod stock_update;
+pub mod flavour;
+pub mod inventory;
+pub mod constants;
+pub mod logical_clock;
+pub mod framing;
+pub mod auth;
+pub mod tls;
\ No newline at end of file
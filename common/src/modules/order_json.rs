@@ -1,8 +1,12 @@
 use serde::Deserialize;
 
+use super::priority::Priority;
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct OrderJSON {
     pub id: usize,
     pub size: usize,
-    pub flavours: Vec<String>
+    pub flavours: Vec<String>,
+    #[serde(default)]
+    pub priority: Priority
 }
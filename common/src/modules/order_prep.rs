@@ -1,11 +1,14 @@
 use serde::{Serialize, Deserialize};
 
 use super::order_json::OrderJSON;
+use super::priority::Priority;
+use super::logical_clock::ClockStamp;
 
 // Flags
 pub const ORDER_SUCCESS:  u8 = 0;
 pub const ORDER_FAILED:   u8 = 1; // No hay helado
 pub const ROBOT_OCCUPIED: u8 = 2;
+pub const ORDER_ACCEPTED: u8 = 3; // El Robot encoló la Order, todavía no está lista
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct OrderPrep {
@@ -15,6 +18,12 @@ pub struct OrderPrep {
     pub size: usize,
     pub flavours: Vec<String>,
     pub fail_flag: usize,
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default)]
+    pub stamp: u64, // Timestamp de Lamport que le asigna el Robot al aceptarla
+    #[serde(default)]
+    pub clock: ClockStamp,
 }
 
 impl From<OrderJSON> for OrderPrep {
@@ -26,7 +35,10 @@ impl From<OrderJSON> for OrderPrep {
             id: order_json.id,
             size: order_json.size,
             flavours: order_json.flavours,
-            fail_flag: 0
+            fail_flag: 0,
+            priority: order_json.priority,
+            stamp: 0,
+            clock: ClockStamp::default()
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,28 @@
+use serde::{Serialize, Deserialize};
+
+use super::logical_clock::ClockStamp;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// OrderRelease struct is sent by a Screen to the robots that answered an OrderRequest but were
+/// not chosen, so they can drop the reservation they made for the order. It is also sent to the
+/// robot preparing an order the Screen gave up on, so it cancels it.
+pub struct OrderRelease {
+    pub message: String, // "OrderRelease"
+    pub ip: String,
+    pub id: usize,
+    pub order_id: usize,
+    #[serde(default)]
+    pub clock: ClockStamp,
+}
+
+impl OrderRelease {
+    pub fn new(ip: String, id: usize, order_id: usize) -> Self {
+        OrderRelease {
+            message: "OrderRelease".to_string(),
+            ip,
+            id,
+            order_id,
+            clock: ClockStamp::default()
+        }
+    }
+}
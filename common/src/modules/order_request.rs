@@ -1,18 +1,44 @@
+use std::collections::HashMap;
+
 use serde::{Serialize, Deserialize};
 
+use super::logical_clock::ClockStamp;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct OrderRequest {
     pub message: String, // "OrderRequest"
     pub ip: String,
     pub id: usize,
+    #[serde(default)]
+    pub order_id: usize,
+    #[serde(default)]
+    pub capacity: usize, // Lugar libre del Robot, lo completa el Robot en su respuesta
+    // Carga del Robot, la completa el Robot en su respuesta
+    #[serde(default)]
+    pub queue_len: usize,
+    #[serde(default)]
+    pub waiting_flavours: Vec<String>,
+    #[serde(default)]
+    pub token_epochs: HashMap<String, u64>, // Epoch de la última copia vista de cada token
+    #[serde(default)]
+    pub ring_rtt_ms: Option<u64>, // Última vuelta al anillo medida por el Robot
+    #[serde(default)]
+    pub clock: ClockStamp,
 }
 
 impl OrderRequest {
-    pub fn new(ip: String, id: usize) -> Self {
+    pub fn new(ip: String, id: usize, order_id: usize) -> Self {
         OrderRequest {
             message: "OrderRequest".to_string(),
             ip,
-            id
+            id,
+            order_id,
+            capacity: 0,
+            queue_len: 0,
+            waiting_flavours: Vec::new(),
+            token_epochs: HashMap::new(),
+            ring_rtt_ms: None,
+            clock: ClockStamp::default()
         }
     }
 }
\ No newline at end of file
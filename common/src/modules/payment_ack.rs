@@ -0,0 +1,29 @@
+use serde::{Serialize, Deserialize};
+
+use super::logical_clock::ClockStamp;
+
+#[derive(Serialize, Deserialize, Debug)]
+/// PaymentAck struct is the answer of the Gateway to a PaymentConfirmation or a PaymentVoid, once the payment (the kind of message in `payment`) of the order is safely stored.
+pub struct PaymentAck {
+    pub ip: String,
+    pub id: String,
+    pub message: String, // "PaymentAck"
+    pub order_id: usize,
+    pub payment: String,
+    #[serde(default)]
+    pub clock: ClockStamp,
+}
+
+impl PaymentAck {
+    /// Create a new PaymentAck instance.
+    pub fn new(ip: String, id: String, order_id: usize, payment: &str) -> Self {
+        PaymentAck {
+            ip,
+            id,
+            message: "PaymentAck".to_string(),
+            order_id,
+            payment: payment.to_string(),
+            clock: ClockStamp::default()
+        }
+    }
+}
@@ -2,23 +2,31 @@ use actix::Message;
 use serde::{Serialize, Deserialize};
 use rand::Rng;
 
+use super::logical_clock::ClockStamp;
+
 const CAPTURE_PROBABILITY: usize = 90;
 
 #[derive(Serialize, Deserialize, Debug)]
 #[derive(Message)]
 #[rtype(result = "usize")]
-/// PaymentCapture struct has information of the sender (ip & id), the message (PaymentCapture) and a valid flag that is sent on 'true' by default.
+/// PaymentCapture struct has information of the sender (ip & id), the message (PaymentCapture), the order being paid, the id the Screen gave to this payment and a valid flag that is sent on 'true' by default.
 pub struct PaymentCapture {
     pub ip: String,
     pub id: String,
     pub message: String,
-    pub valid: bool
+    #[serde(default)]
+    pub order_id: usize,
+    #[serde(default)]
+    pub payment_id: String,
+    pub valid: bool,
+    #[serde(default)]
+    pub clock: ClockStamp
 }
 
 impl PaymentCapture {
     /// Create a new PaymentCapture instance.
-    pub fn new(_ip: String, _id: String, _valid: bool) -> Self {
-        PaymentCapture { ip: _ip, id: _id, message: "PaymentCapture".to_string(), valid: _valid }
+    pub fn new(_ip: String, _id: String, _order_id: usize, _payment_id: String, _valid: bool) -> Self {
+        PaymentCapture { ip: _ip, id: _id, message: "PaymentCapture".to_string(), order_id: _order_id, payment_id: _payment_id, valid: _valid, clock: ClockStamp::default() }
     }
     
     /// Capture a payment, with a 10% probability of failing.
@@ -4,36 +4,43 @@ use serde::{Serialize, Deserialize};
 use chrono::Local;
 
 use super::order_prep::OrderPrep;
+use super::logical_clock::ClockStamp;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[derive(Message)]
 #[rtype(result = "usize")]
-/// PaymentConfirmation struct has information of the sender (ip & id), the message (PaymentCapture) and the copy itself (to log to disk).
+/// PaymentConfirmation struct has information of the sender (ip & id), the message (PaymentCapture), the copy itself (to log to disk) and the id of the payment it confirms.
 pub struct PaymentConfirmation {
     pub ip: String,
     pub id: String,
     pub message: String,
     pub order_data: OrderPrep,
+    #[serde(default)]
+    pub payment_id: String,
+    #[serde(default)]
+    pub clock: ClockStamp,
 }
 
 impl PaymentConfirmation {
     /// Create a new PaymentConfirmation instance.
-    pub fn new(_ip: String, _id: String, order_data: OrderPrep) -> Self {
+    pub fn new(_ip: String, _id: String, order_data: OrderPrep, payment_id: String) -> Self {
         PaymentConfirmation { 
             ip: _ip, 
             id: _id, 
             message: "PaymentConfirmation".to_string(),
-            order_data 
+            order_data,
+            payment_id,
+            clock: ClockStamp::default()
         }
     }
     
-    /// Confirm a payment, by logging the order and screen information to disk.
-    pub fn confirm_payment(confirmation: &Self) -> Result<(), Error> {
-        let confirmation_str = confirmation.id.clone() + &" completed order of size ".to_string() +  &confirmation.order_data.size.to_string() + &" with flavours ".to_string() + &confirmation.order_data.flavours.join(",").to_string();
+    /// Confirm a payment, by logging the order and screen information to the `journal` file.
+    pub fn confirm_payment(confirmation: &Self, journal: &str) -> Result<(), Error> {
+        let confirmation_str = format!("{} completed order of size {} with flavours {} (payment {})", confirmation.id, confirmation.order_data.size, confirmation.order_data.flavours.join(","), confirmation.payment_id);
         let mut file = OpenOptions::new()
             .append(true)
             .create(true)
-            .open("log.txt")?;
+            .open(journal)?;
         let log_str = Local::now().format("%Y-%m-%d %H:%M:%S").to_string() + " " + &confirmation_str;
         writeln!(file, "{}", log_str)?;
 
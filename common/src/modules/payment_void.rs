@@ -0,0 +1,48 @@
+use std::{fs::OpenOptions, io::{Error, Write}};
+use actix::Message;
+use serde::{Serialize, Deserialize};
+use chrono::Local;
+
+use super::logical_clock::ClockStamp;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Message)]
+#[rtype(result = "usize")]
+/// PaymentVoid struct has information of the sender (ip & id), the message (PaymentVoid), the order whose captured payment is released and the id of that payment.
+pub struct PaymentVoid {
+    pub ip: String,
+    pub id: String,
+    pub message: String,
+    pub order_id: usize,
+    #[serde(default)]
+    pub payment_id: String,
+    #[serde(default)]
+    pub clock: ClockStamp,
+}
+
+impl PaymentVoid {
+    /// Create a new PaymentVoid instance.
+    pub fn new(ip: String, id: String, order_id: usize, payment_id: String) -> Self {
+        PaymentVoid {
+            ip,
+            id,
+            message: "PaymentVoid".to_string(),
+            order_id,
+            payment_id,
+            clock: ClockStamp::default()
+        }
+    }
+
+    /// Void a captured payment, by logging the order and screen information to the `journal` file.
+    pub fn void_payment(void: &Self, journal: &str) -> Result<(), Error> {
+        let void_str = format!("{} voided the payment of order {} (payment {})", void.id, void.order_id, void.payment_id);
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(journal)?;
+        let log_str = Local::now().format("%Y-%m-%d %H:%M:%S").to_string() + " " + &void_str;
+        writeln!(file, "{}", log_str)?;
+
+        Ok(())
+    }
+}
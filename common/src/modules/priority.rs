@@ -0,0 +1,33 @@
+use std::time::Duration;
+
+use serde::{Serialize, Deserialize};
+
+/// Tiempo de espera que sube a una Order un nivel de prioridad, para que no se posterguen siempre
+pub const AGING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Priority class of an Order. Express orders are served before normal ones, and staff orders
+/// are served after every customer order.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Staff,
+    #[default]
+    Normal,
+    Express,
+}
+
+impl Priority {
+    fn weight(&self) -> u64 {
+        match self {
+            Priority::Staff => 0,
+            Priority::Normal => 1,
+            Priority::Express => 2,
+        }
+    }
+
+    /// Priority of an Order that has been waiting for the given time. It grows one level every
+    /// `AGING_INTERVAL`, so low priority Orders are not starved.
+    pub fn aged(&self, waited: Duration) -> u64 {
+        self.weight() + waited.as_secs() / AGING_INTERVAL.as_secs()
+    }
+}
@@ -0,0 +1,49 @@
+use serde::{Serialize, Deserialize};
+
+use super::logical_clock::ClockStamp;
+
+// Stock levels
+pub const STOCK_AVAILABLE: u8 = 0;
+pub const STOCK_LOW:       u8 = 1; // Queda menos que LOW_STOCK_THRESHOLD
+pub const STOCK_SOLD_OUT:  u8 = 2;
+
+pub const LOW_STOCK_THRESHOLD: f64 = 3.0;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// StockUpdate struct is sent by a Robot to the Screens when the stock level of a flavour changes.
+pub struct StockUpdate {
+    pub message: String, // "StockUpdate"
+    pub ip: String,
+    pub id: usize,
+    pub flavour: String,
+    pub amount: f64,
+    pub level: u8,
+    #[serde(default)]
+    pub clock: ClockStamp,
+}
+
+impl StockUpdate {
+    /// Create a new StockUpdate instance, with the level that corresponds to the amount.
+    pub fn new(ip: String, id: usize, flavour: String, amount: f64) -> Self {
+        StockUpdate {
+            message: "StockUpdate".to_string(),
+            ip,
+            id,
+            flavour,
+            amount,
+            level: StockUpdate::stock_level(amount),
+            clock: ClockStamp::default()
+        }
+    }
+
+    /// Returns the stock level of a flavour given its available amount.
+    pub fn stock_level(amount: f64) -> u8 {
+        if amount <= 0.0 {
+            STOCK_SOLD_OUT
+        } else if amount < LOW_STOCK_THRESHOLD {
+            STOCK_LOW
+        } else {
+            STOCK_AVAILABLE
+        }
+    }
+}
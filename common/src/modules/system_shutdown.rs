@@ -0,0 +1,34 @@
+use serde::{Serialize, Deserialize};
+
+use super::logical_clock::ClockStamp;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// SystemShutdown struct asks the whole shop to shut down. Every actor forwards it to its peers
+/// the first time it receives it: Screens drain and exit, the Robots stop the token ring, and the
+/// Gateway flushes its journal once every Screen disconnected.
+pub struct SystemShutdown {
+    pub message: String, // "SystemShutdown"
+    pub ip: String,
+    pub id: String,
+    // Robot que hace circular el mensaje por el anillo, si lo envía un Robot
+    #[serde(default)]
+    pub origin: Option<usize>,
+    // El anillo ya se detuvo y los Robots pueden salir
+    #[serde(default)]
+    pub ring_stopped: bool,
+    #[serde(default)]
+    pub clock: ClockStamp,
+}
+
+impl SystemShutdown {
+    pub fn new(ip: String, id: String) -> Self {
+        SystemShutdown {
+            message: "SystemShutdown".to_string(),
+            ip,
+            id,
+            origin: None,
+            ring_stopped: false,
+            clock: ClockStamp::default()
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use std::{env, io::{Error, ErrorKind}, net::SocketAddr, pin::Pin, sync::{Arc, OnceLock}, task::{Context, Poll}};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+
+use super::handshake::HANDSHAKE_TIMEOUT;
+
+/// Variable de entorno con la ruta del certificado (PEM) de este nodo: el del Gateway, o el que
+/// presenta la Pantalla si el Gateway pide certificados de cliente
+pub const TLS_CERT_FILE_VAR: &str = "TLS_CERT_FILE";
+/// Variable de entorno con la ruta de la clave privada (PEM) del certificado de `TLS_CERT_FILE`
+pub const TLS_KEY_FILE_VAR: &str = "TLS_KEY_FILE";
+/// Variable de entorno con la ruta de los certificados (PEM) en los que confía la Pantalla para
+/// reconocer al Gateway. Con ella la Pantalla se conecta al Gateway con TLS
+pub const TLS_CA_FILE_VAR: &str = "TLS_CA_FILE";
+/// Variable de entorno con la ruta de los certificados (PEM) que firman los de las Pantallas
+/// conocidas. Con ella el Gateway rechaza a las Pantallas sin un certificado firmado por ellos
+pub const TLS_CLIENT_CA_FILE_VAR: &str = "TLS_CLIENT_CA_FILE";
+
+static ACCEPTOR: OnceLock<Option<TlsAcceptor>> = OnceLock::new();
+static CONNECTOR: OnceLock<Option<TlsConnector>> = OnceLock::new();
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn required_var(var: &str, because: &str) -> String {
+    env::var(var).unwrap_or_else(|_| panic!("[ERROR] {} is set, so {} must be set too", because, var))
+}
+
+fn certificates(path: &str) -> Vec<CertificateDer<'static>> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .unwrap_or_else(|e| panic!("[ERROR] Couldn't read the certificates from {}: {}", path, e));
+    if certificates.is_empty() {
+        panic!("[ERROR] There are no certificates in {}", path);
+    }
+    certificates
+}
+
+fn private_key(path: &str) -> PrivateKeyDer<'static> {
+    PrivateKeyDer::from_pem_file(path)
+        .unwrap_or_else(|e| panic!("[ERROR] Couldn't read the private key from {}: {}", path, e))
+}
+
+fn roots(path: &str) -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    for certificate in certificates(path) {
+        roots
+            .add(certificate)
+            .unwrap_or_else(|e| panic!("[ERROR] Invalid certificate in {}: {}", path, e));
+    }
+    roots
+}
+
+/// TLS configuration of the Gateway server, read from `TLS_CERT_FILE`, `TLS_KEY_FILE` and
+/// `TLS_CLIENT_CA_FILE`, or None if the connections of the Screens are not encrypted.
+/// Panics if the files can't be read, so a Gateway is never started without the certificates it
+/// was configured with.
+pub fn acceptor() -> Option<&'static TlsAcceptor> {
+    ACCEPTOR
+        .get_or_init(|| {
+            let cert_path = env::var(TLS_CERT_FILE_VAR).ok()?;
+            let key_path = required_var(TLS_KEY_FILE_VAR, TLS_CERT_FILE_VAR);
+            let builder = ServerConfig::builder_with_provider(provider())
+                .with_safe_default_protocol_versions()
+                .expect("[ERROR] The TLS provider doesn't support the default protocol versions");
+            let builder = match env::var(TLS_CLIENT_CA_FILE_VAR) {
+                Ok(ca_path) => {
+                    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots(&ca_path)), provider())
+                        .build()
+                        .unwrap_or_else(|e| panic!("[ERROR] Couldn't check client certificates with {}: {}", ca_path, e));
+                    builder.with_client_cert_verifier(verifier)
+                }
+                Err(_) => builder.with_no_client_auth(),
+            };
+            let config = builder
+                .with_single_cert(certificates(&cert_path), private_key(&key_path))
+                .unwrap_or_else(|e| panic!("[ERROR] Invalid certificate or key in {} and {}: {}", cert_path, key_path, e));
+            Some(TlsAcceptor::from(Arc::new(config)))
+        })
+        .as_ref()
+}
+
+/// Whether the Gateway server only accepts Screens with a known client certificate.
+pub fn checks_client_certificates() -> bool {
+    acceptor().is_some() && env::var(TLS_CLIENT_CA_FILE_VAR).is_ok()
+}
+
+/// TLS configuration of the connections of a Screen to the Gateway, read from `TLS_CA_FILE`,
+/// and `TLS_CERT_FILE` and `TLS_KEY_FILE` for its client certificate, or None if they are not
+/// encrypted. Panics if the files can't be read.
+pub fn connector() -> Option<&'static TlsConnector> {
+    CONNECTOR
+        .get_or_init(|| {
+            let ca_path = env::var(TLS_CA_FILE_VAR).ok()?;
+            let builder = ClientConfig::builder_with_provider(provider())
+                .with_safe_default_protocol_versions()
+                .expect("[ERROR] The TLS provider doesn't support the default protocol versions")
+                .with_root_certificates(roots(&ca_path));
+            let config = match env::var(TLS_CERT_FILE_VAR) {
+                Ok(cert_path) => {
+                    let key_path = required_var(TLS_KEY_FILE_VAR, TLS_CERT_FILE_VAR);
+                    builder
+                        .with_client_auth_cert(certificates(&cert_path), private_key(&key_path))
+                        .unwrap_or_else(|e| panic!("[ERROR] Invalid certificate or key in {} and {}: {}", cert_path, key_path, e))
+                }
+                Err(_) => builder.with_no_client_auth(),
+            };
+            Some(TlsConnector::from(Arc::new(config)))
+        })
+        .as_ref()
+}
+
+fn timed_out() -> Error {
+    Error::new(ErrorKind::TimedOut, format!("no TLS handshake in {} seconds", HANDSHAKE_TIMEOUT.as_secs()))
+}
+
+/// Transport is a connection with another node: plain TCP, or TLS over TCP between the Screens
+/// and the Gateway if it is configured.
+#[derive(Debug)]
+pub enum Transport {
+    Tcp(TcpStream),
+    Server(Box<server::TlsStream<TcpStream>>),
+    Client(Box<client::TlsStream<TcpStream>>),
+}
+
+impl Transport {
+    /// Connection accepted by the Gateway server, after the TLS handshake if it is configured.
+    /// Fails if the other end doesn't speak TLS or, when client certificates are checked,
+    /// doesn't have a known one.
+    pub async fn accept(stream: TcpStream) -> Result<Self, Error> {
+        match acceptor() {
+            Some(acceptor) => {
+                let stream = timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await.map_err(|_| timed_out())??;
+                Ok(Transport::Server(Box::new(stream)))
+            }
+            None => Ok(Transport::Tcp(stream)),
+        }
+    }
+
+    /// Connection of a Screen to the Gateway, after the TLS handshake if it is configured. The
+    /// certificate of the Gateway must be valid for the IP address it was reached at.
+    pub async fn connect(stream: TcpStream) -> Result<Self, Error> {
+        match connector() {
+            Some(connector) => {
+                let name = ServerName::IpAddress(stream.peer_addr()?.ip().into());
+                let stream = timeout(HANDSHAKE_TIMEOUT, connector.connect(name, stream)).await.map_err(|_| timed_out())??;
+                Ok(Transport::Client(Box::new(stream)))
+            }
+            None => Ok(Transport::Tcp(stream)),
+        }
+    }
+
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Transport::Tcp(stream) => stream,
+            Transport::Server(stream) => stream.get_ref().0,
+            Transport::Client(stream) => stream.get_ref().0,
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        !matches!(self, Transport::Tcp(_))
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.tcp().local_addr()
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr, Error> {
+        self.tcp().peer_addr()
+    }
+}
+
+impl From<TcpStream> for Transport {
+    fn from(stream: TcpStream) -> Self {
+        Transport::Tcp(stream)
+    }
+}
+
+impl AsyncRead for Transport {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Transport::Server(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+            Transport::Client(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Transport {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Transport::Server(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+            Transport::Client(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Transport::Server(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+            Transport::Client(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Transport::Server(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+            Transport::Client(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use serde::{Serialize, Deserialize};
+
+use super::logical_clock::ClockStamp;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Welcome struct is the answer to a Hello. It has the role and id of the actor that accepted
+/// the connection, the protocol version both ends will use and the features both support.
+/// If the connection is refused, `error` says why and the connection is closed. A Gateway also
+/// sends the epoch of its term as primary. `challenge` is the one the other end has to sign in
+/// its frames.
+pub struct Welcome {
+    pub message: String, // "Welcome"
+    pub role: String,
+    pub id: String,
+    pub version: u32,
+    pub features: Vec<String>,
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub epoch: u64,
+    #[serde(default)]
+    pub challenge: u64,
+    #[serde(default)]
+    pub clock: ClockStamp,
+}
+
+impl Welcome {
+    pub fn new(role: &str, id: String, version: u32, features: Vec<String>) -> Self {
+        Welcome {
+            message: "Welcome".to_string(),
+            role: role.to_string(),
+            id,
+            version,
+            features,
+            error: None,
+            epoch: 0,
+            challenge: 0,
+            clock: ClockStamp::default()
+        }
+    }
+
+    /// Welcome that refuses the connection.
+    pub fn refuse(role: &str, id: String, error: String) -> Self {
+        Welcome { error: Some(error), ..Welcome::new(role, id, 0, Vec::new()) }
+    }
+
+    /// Welcome serialized, ready to be written as the answer to a Hello.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
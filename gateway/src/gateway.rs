@@ -1,100 +1,642 @@
 use crate::disconnect::Disconnect;
+use crate::framing::{FrameReader, FrameWriter, FramedStream};
+use crate::handshake::welcome_with_epoch;
+use crate::hello::{ROLE_GATEWAY, ROLE_SCREEN};
+use crate::ledger::{Ledger, LedgerEntry, LedgerRecord};
+use crate::logical_clock::LogicalClock;
+use crate::payment_ack::PaymentAck;
 use crate::payment_capture::PaymentCapture;
 use crate::payment_confirmation::PaymentConfirmation;
-use crate::{GATEWAY_ID, GATEWAY_IP};
+use crate::payment_void::PaymentVoid;
+use crate::replication::{
+    connect_standby, load_epoch, replica_stream, store_epoch, Heartbeat, ReplicaLine, Replicate,
+    ReplicateAck, Term, HEARTBEAT_INTERVAL, HEARTBEAT_TIMEOUT,
+};
+use crate::system_shutdown::SystemShutdown;
+use crate::tls::Transport;
+use crate::{GATEWAY_BACKUP_IP, GATEWAY_ID};
 use actix::fut::wrap_future;
-use actix::{Actor, ActorContext, Context, ContextFutureSpawner, StreamHandler};
+use actix::{
+    Actor, ActorContext, Addr, AsyncContext, Context, ContextFutureSpawner, Handler, Message,
+    StreamHandler, System,
+};
 use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::io::AsyncWriteExt;
-use tokio::io::WriteHalf;
-use tokio::net::TcpStream;
+use std::time::Instant;
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::net::TcpListener;
 use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio_stream::{Stream, StreamExt};
+
+type WriteArcMutex = Arc<Mutex<FrameWriter<WriteHalf<Transport>>>>;
+
+/// A message read from the connection of a Screen, tagged with the Screen's address. `line` is None
+/// once the connection ended.
+pub struct ScreenLine {
+    pub addr: String,
+    pub line: Option<Result<String, std::io::Error>>,
+}
+
+/// Tag every message read from a Screen with its address, and add an end marker when the connection
+/// ends, so the Gateway knows which Screen left.
+fn screen_stream(
+    addr: String,
+    read: FrameReader<ReadHalf<Transport>>,
+) -> impl Stream<Item = ScreenLine> {
+    let end = ScreenLine {
+        addr: addr.clone(),
+        line: None,
+    };
+    read.map(move |line| ScreenLine {
+        addr: addr.clone(),
+        line: Some(line),
+    })
+    .chain(tokio_stream::once(end))
+}
+
+/// Accept the connections of the Screens, and hand them to the Gateway once they introduced
+/// themselves with a protocol version it understands. The Welcome has the current `epoch` of the
+/// Gateway. If TLS is configured the Screens must complete the TLS handshake first, with a known
+/// certificate if they are checked.
+async fn accept_screens(listener: TcpListener, gateway: Addr<Gateway>, epoch: Arc<AtomicU64>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                let gateway = gateway.clone();
+                let epoch = epoch.load(Ordering::SeqCst);
+                // Una Pantalla que tarda en presentarse no demora a las demás
+                actix::spawn(async move {
+                    let mut stream = match Transport::accept(stream).await {
+                        Ok(stream) => FramedStream::new(stream),
+                        Err(e) => {
+                            println!(
+                                "[{}] Connection from {} refused, TLS handshake failed: {}",
+                                GATEWAY_ID, addr, e
+                            );
+                            return;
+                        }
+                    };
+                    match welcome_with_epoch(
+                        &mut stream,
+                        ROLE_GATEWAY,
+                        GATEWAY_ID.to_string(),
+                        &[ROLE_SCREEN],
+                        epoch,
+                    )
+                    .await
+                    {
+                        Ok(screen) => {
+                            println!(
+                                "[{}] Screen {} connected, protocol version {}",
+                                GATEWAY_ID, screen.id, screen.version
+                            );
+                            gateway.do_send(ScreenConnected((addr.to_string(), stream)));
+                        }
+                        Err(e) => {
+                            println!("[{}] Connection from {} refused: {}", GATEWAY_ID, addr, e)
+                        }
+                    }
+                });
+            }
+            Err(e) => println!(
+                "[ERROR] Couldn't establish a connection with the client: {}",
+                e
+            ),
+        }
+    }
+}
+
+/// Role of a Gateway instance. The primary serves the Screens and copies every Ledger entry to
+/// the standby, which takes over if the primary stops answering. Every term as primary has an
+/// epoch, higher than the ones before, so an outdated primary is told apart.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Primary,
+    Standby,
+}
 
 /// Gateway has a HashMap of connections, with the IP and the WriteHalf of the stream of each Screen.
 pub struct Gateway {
-    connections: HashMap<String, Arc<Mutex<WriteHalf<TcpStream>>>>,
+    connections: HashMap<String, WriteArcMutex>,
+    clock: LogicalClock,
+    // Se pidió cerrar todo el sistema
+    shutting_down: bool,
+    role: Role,
+    // Dirección donde atiende a las pantallas
+    ip: &'static str,
+    ledger: Ledger,
+    // Conexión con el otro Gateway: el standby si es el principal, o el principal si es el standby
+    replica: Option<WriteArcMutex>,
+    // Entradas enviadas al standby que todavía no confirmó, con la respuesta para la pantalla
+    awaiting_replication: HashMap<u64, Option<(String, String)>>,
+    next_seq: u64,
+    // Último mensaje recibido del principal, si es el standby
+    last_heartbeat: Option<Instant>,
+    // Época del período actual como principal, compartida con el servidor de las pantallas
+    epoch: Arc<AtomicU64>,
+    // Archivo donde se guarda la época
+    epoch_file: &'static str,
+    // Servidor de las pantallas, mientras atiende
+    server: Option<JoinHandle<()>>,
+    // Este Gateway espera al otro en la dirección de replicación: arrancó como standby
+    replication_server: bool,
+    // Este Gateway abrió la conexión con el otro
+    joined_replica: bool,
 }
 
 impl Gateway {
-    /// Create a new Gateway instance.
-    pub fn new(_connections: HashMap<String, Arc<Mutex<WriteHalf<TcpStream>>>>) -> Self {
+    /// Create a new Gateway instance, without Screens. They are added as they connect.
+    /// Confirmed and voided payments are logged to `journal`, and the epoch is kept in
+    /// `epoch_file`.
+    pub fn new(
+        role: Role,
+        ip: &'static str,
+        journal: &'static str,
+        epoch_file: &'static str,
+    ) -> Self {
         Gateway {
-            connections: _connections,
+            connections: HashMap::new(),
+            clock: LogicalClock::new(GATEWAY_ID.to_string()),
+            shutting_down: false,
+            role,
+            ip,
+            ledger: Ledger::new(journal),
+            replica: None,
+            awaiting_replication: HashMap::new(),
+            next_seq: 0,
+            last_heartbeat: None,
+            epoch: Arc::new(AtomicU64::new(load_epoch(epoch_file))),
+            epoch_file,
+            server: None,
+            replication_server: role == Role::Standby,
+            joined_replica: false,
+        }
+    }
+
+    fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::SeqCst)
+    }
+
+    /// Change to a new epoch, and store it.
+    fn set_epoch(&mut self, epoch: u64) {
+        self.epoch.store(epoch, Ordering::SeqCst);
+        if let Err(e) = store_epoch(self.epoch_file, epoch) {
+            println!("[ERROR] Couldn't store the epoch: {}", e);
+        }
+    }
+
+    /// Start serving the Screens at the address of this Gateway, unless it already does.
+    fn serve(&mut self, ctx: &mut Context<Gateway>) {
+        if self.server.is_some() || self.shutting_down {
+            return;
+        }
+        println!(
+            "[{}] Serving the screens at {}, epoch {}",
+            GATEWAY_ID,
+            self.ip,
+            self.epoch()
+        );
+        let gateway = ctx.address();
+        let ip = self.ip;
+        let epoch = self.epoch.clone();
+        self.server = Some(actix::spawn(async move {
+            match TcpListener::bind(ip).await {
+                Ok(listener) => accept_screens(listener, gateway, epoch).await,
+                Err(e) => println!("[ERROR] Couldn't start the Gateway server: {}", e),
+            }
+        }));
+    }
+
+    /// Look for a standby again. The one that waits at the replication address is found by the
+    /// other Gateway when it starts.
+    fn find_standby(&self, ctx: &mut Context<Gateway>) {
+        if !self.replication_server && !self.shutting_down {
+            connect_standby(ctx.address());
+        }
+    }
+
+    /// Use the connection with the other Gateway as the one with the standby, and copy the
+    /// whole Ledger to it before any new entry.
+    fn replicate_to(&mut self, ctx: &mut Context<Gateway>, write: WriteArcMutex) {
+        self.replica = Some(write);
+        for entry in self.ledger.entries() {
+            self.replicate(ctx, entry, None);
         }
     }
 
     /// Send a message to a certain Screen.
-    pub fn write_message(
-        ctx: &mut Context<Gateway>,
-        message: String,
-        destination: Arc<Mutex<WriteHalf<TcpStream>>>,
-    ) {
+    pub fn write_message(ctx: &mut Context<Gateway>, message: String, destination: WriteArcMutex) {
         wrap_future::<_, Self>(async move {
-            destination
-                .lock()
-                .await
-                .write_all((message + "\n").as_bytes())
-                .await
-                .expect("[ERROR] Couldn't send message to the client")
+            // Si la pantalla se cayó, su conexión se quita al terminar su stream
+            if let Err(e) = destination.lock().await.send(&message).await {
+                println!("[ERROR] Couldn't send message to the client: {}", e);
+            }
         })
         .spawn(ctx);
     }
+
+    /// Stamp a message with the logical clock and send it to the Screen with the given address,
+    /// if it is still connected.
+    fn reply(&mut self, ctx: &mut Context<Gateway>, client_ip: &str, message: String) {
+        let Some(connection) = self.connections.get(client_ip).cloned() else {
+            println!("[ERROR] {} is not connected anymore", client_ip);
+            return;
+        };
+        let message = self.clock.stamp(&message);
+        Gateway::write_message(ctx, message, connection);
+    }
+
+    /// Send a message to the other Gateway. The replication isn't part of the logical clock of
+    /// the system, so it isn't stamped.
+    fn write_replica(&mut self, ctx: &mut Context<Gateway>, message: String) {
+        if let Some(replica) = self.replica.clone() {
+            Gateway::write_message(ctx, message, replica);
+        }
+    }
+
+    /// Copy a Ledger entry to the standby. `reply` is sent to its Screen once the standby
+    /// acknowledges it.
+    fn replicate(
+        &mut self,
+        ctx: &mut Context<Gateway>,
+        entry: LedgerEntry,
+        reply: Option<(String, String)>,
+    ) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.awaiting_replication.insert(seq, reply);
+        let replicate = serde_json::to_string(&Replicate::new(seq, entry))
+            .expect("Couldn't serialize Replicate");
+        self.write_replica(ctx, replicate);
+    }
+
+    /// Store a Ledger entry and copy it to the standby. The reply for the Screen is sent once the
+    /// standby acknowledges the entry, or right away if there is no standby.
+    fn record(&mut self, ctx: &mut Context<Gateway>, entry: LedgerEntry, reply: (String, String)) {
+        if let Err(e) = self.ledger.apply(entry.clone()) {
+            println!("[ERROR] Couldn't write the journal: {}", e);
+        }
+        if self.replica.is_some() {
+            self.replicate(ctx, entry, Some(reply));
+        } else {
+            self.reply(ctx, &reply.0, reply.1);
+        }
+    }
+
+    /// Capture the payment of an order, unless it was already captured: then answer with the
+    /// stored result, so a Screen that retries after a failover isn't charged twice.
+    fn capture(&mut self, ctx: &mut Context<Gateway>, mut capture: PaymentCapture) {
+        let client_ip = capture.ip.clone();
+        let client_id = capture.id.clone();
+        let order_id = capture.order_id;
+        println!("[{}] wants to capture a payment", client_id);
+
+        let mut entry = LedgerEntry {
+            screen: client_id.clone(),
+            order_id,
+            payment_id: capture.payment_id.clone(),
+            record: LedgerRecord::Captured { valid: true },
+        };
+        if let Some(valid) = self.ledger.capture_result(&entry) {
+            println!(
+                "[{}] the payment of order {} from {} was already captured",
+                GATEWAY_ID, order_id, client_id
+            );
+            capture.valid = valid;
+            capture.id = GATEWAY_ID.to_string();
+            capture.ip = self.ip.to_string();
+            let capture_str =
+                serde_json::to_string(&capture).expect("Couldn't serialize PaymentCapture");
+            self.reply(ctx, &client_ip, capture_str);
+            return;
+        }
+
+        let capture = PaymentCapture::capture_payment(capture, GATEWAY_ID, self.ip);
+        if capture.valid {
+            println!(
+                "[{}] the payment from {} was captured succesfully ",
+                GATEWAY_ID, client_id
+            );
+        } else {
+            println!(
+                "[{}] the payment from {} couldn't be captured",
+                GATEWAY_ID, client_id
+            );
+        }
+        entry.record = LedgerRecord::Captured {
+            valid: capture.valid,
+        };
+        let capture_str =
+            serde_json::to_string(&capture).expect("Couldn't serialize PaymentCapture");
+        self.record(ctx, entry, (client_ip, capture_str));
+    }
+
+    /// Log a confirmed or voided payment, and acknowledge it to the Screen. A repeated one is
+    /// only acknowledged.
+    fn settle(
+        &mut self,
+        ctx: &mut Context<Gateway>,
+        client_ip: String,
+        entry: LedgerEntry,
+        payment: &str,
+    ) {
+        let ack = PaymentAck::new(
+            self.ip.to_string(),
+            GATEWAY_ID.to_string(),
+            entry.order_id,
+            payment,
+        );
+        let ack_str = serde_json::to_string(&ack).expect("Couldn't serialize PaymentAck");
+        self.record(ctx, entry, (client_ip, ack_str));
+    }
+
+    /// Start the shutdown of the whole system, by forwarding a SystemShutdown message to every
+    /// Screen and to the standby. The Gateway exits once all the Screens disconnected.
+    fn system_shutdown(&mut self, ctx: &mut Context<Gateway>) {
+        if self.shutting_down {
+            return;
+        }
+        self.shutting_down = true;
+        println!("[{}] Shutting down the system", GATEWAY_ID);
+        let shutdown = SystemShutdown::new(self.ip.to_string(), GATEWAY_ID.to_string());
+        let shutdown_str =
+            serde_json::to_string(&shutdown).expect("Couldn't serialize SystemShutdown");
+        for connection in self.connections.values().cloned().collect::<Vec<_>>() {
+            let message = self.clock.stamp(&shutdown_str);
+            Gateway::write_message(ctx, message, connection);
+        }
+        self.write_replica(ctx, shutdown_str);
+        if self.connections.is_empty() {
+            self.flush_journal();
+            System::current().stop();
+        }
+    }
+
+    /// Make sure every confirmed and voided payment is written to disk.
+    fn flush_journal(&self) {
+        match OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(self.ledger.journal())
+        {
+            Ok(file) if file.sync_all().is_ok() => {
+                println!("[{}] Journal flushed to disk", GATEWAY_ID)
+            }
+            _ => println!("[ERROR] Couldn't flush the journal"),
+        }
+    }
+
+    /// Forget a Screen whose connection ended without a Disconnect.
+    fn screen_lost(&mut self, addr: &str) {
+        if self.connections.remove(addr).is_some() {
+            println!("[EXIT] The connection with {} was lost", addr);
+        }
+    }
+
+    /// Send a heartbeat to the standby if this is the primary, or check the heartbeats of the
+    /// primary if this is the standby.
+    fn heartbeat(&mut self, ctx: &mut Context<Gateway>) {
+        match self.role {
+            Role::Primary => {
+                let heartbeat =
+                    serde_json::to_string(&Heartbeat::new()).expect("Couldn't serialize Heartbeat");
+                self.write_replica(ctx, heartbeat);
+            }
+            Role::Standby => {
+                if let Some(last_heartbeat) = self.last_heartbeat {
+                    if last_heartbeat.elapsed() > HEARTBEAT_TIMEOUT {
+                        println!("[{}] No heartbeat from the primary", GATEWAY_ID);
+                        self.take_over(ctx);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The primary is down: become the primary and start serving the Screens on the backup
+    /// address. The Ledger already has every payment the primary acknowledged.
+    fn take_over(&mut self, ctx: &mut Context<Gateway>) {
+        if self.role == Role::Primary || self.shutting_down {
+            return;
+        }
+        self.role = Role::Primary;
+        self.replica = None;
+        self.last_heartbeat = None;
+        self.ip = GATEWAY_BACKUP_IP;
+        self.set_epoch(self.epoch() + 1);
+        println!("[{}] Taking over as primary", GATEWAY_ID);
+        self.serve(ctx);
+        self.find_standby(ctx);
+    }
+
+    /// The other Gateway is the primary: stop serving the Screens, so they reconnect to it, and
+    /// stand by. The Ledger was already sent to the new primary when the connection started, and
+    /// every entry recorded since then too, so it is copied again from the new primary with the
+    /// payments of both.
+    fn step_down(&mut self, ctx: &mut Context<Gateway>) {
+        println!(
+            "[{}] The other Gateway is the primary, standing by",
+            GATEWAY_ID
+        );
+        self.role = Role::Standby;
+        self.last_heartbeat = Some(Instant::now());
+        if let Some(server) = self.server.take() {
+            server.abort();
+        }
+        for (_, connection) in self.connections.drain() {
+            wrap_future::<_, Self>(async move {
+                let _ = connection.lock().await.close().await;
+            })
+            .spawn(ctx);
+        }
+        // Las pantallas reenvían al nuevo principal los pagos que no tuvieron respuesta
+        self.awaiting_replication.clear();
+        if let Err(e) = self.ledger.clear() {
+            println!("[ERROR] Couldn't empty the journal: {}", e);
+        }
+    }
+
+    /// Handle the Term of the other Gateway. Two Gateways serving as primary leave the one
+    /// with the highest epoch, or the one that was accepting the other on a tie. The standby
+    /// agrees to a new term with a higher epoch than both knew.
+    fn handle_term(&mut self, ctx: &mut Context<Gateway>, term: Term) {
+        if term.primary && self.role == Role::Primary {
+            let epoch = self.epoch();
+            if term.epoch < epoch || (term.epoch == epoch && !self.joined_replica) {
+                return;
+            }
+            self.step_down(ctx);
+        }
+        match (self.role, term.primary) {
+            (Role::Standby, true) => {
+                self.set_epoch(self.epoch().max(term.epoch) + 1);
+                println!(
+                    "[{}] Standing by for the primary, epoch {}",
+                    GATEWAY_ID,
+                    self.epoch()
+                );
+                let term = serde_json::to_string(&Term::new(self.epoch(), false))
+                    .expect("Couldn't serialize Term");
+                self.write_replica(ctx, term);
+            }
+            (Role::Primary, false) => {
+                self.set_epoch(term.epoch);
+                println!(
+                    "[{}] The standby agreed to epoch {}",
+                    GATEWAY_ID, term.epoch
+                );
+                self.serve(ctx);
+            }
+            _ => {}
+        }
+    }
+
+    /// Handle a message from the primary, if this is the standby: store the replicated entries
+    /// and acknowledge them.
+    fn handle_primary_message(&mut self, ctx: &mut Context<Gateway>, line: String) {
+        self.last_heartbeat = Some(Instant::now());
+        if line.contains("\"Replicate\"") {
+            let replicate: Replicate =
+                serde_json::from_str(&line).expect("Couldn't deserialize Replicate");
+            match self.ledger.apply(replicate.entry) {
+                Ok(_) => {
+                    let ack = serde_json::to_string(&ReplicateAck::new(replicate.seq))
+                        .expect("Couldn't serialize ReplicateAck");
+                    self.write_replica(ctx, ack);
+                }
+                Err(e) => println!("[ERROR] Couldn't write the journal: {}", e),
+            }
+        } else if line.contains("\"SystemShutdown\"") {
+            println!("[{}] The primary is shutting down the system", GATEWAY_ID);
+            self.shutting_down = true;
+            self.flush_journal();
+            System::current().stop();
+        }
+    }
+
+    /// Handle a message from the standby, if this is the primary: answer the Screens whose
+    /// payments were copied, and store the entries of a Gateway that stands down for this one.
+    fn handle_standby_message(&mut self, ctx: &mut Context<Gateway>, line: String) {
+        if line.contains("\"ReplicateAck\"") {
+            let ack: ReplicateAck =
+                serde_json::from_str(&line).expect("Couldn't deserialize ReplicateAck");
+            if let Some(Some((client_ip, message))) = self.awaiting_replication.remove(&ack.seq) {
+                self.reply(ctx, &client_ip, message);
+            }
+        } else if line.contains("\"Replicate\"") {
+            // El otro Gateway atendía como principal: los pagos que éste no tenía se suman al
+            // ledger y se le vuelven a copiar, porque vació el suyo al pasar a standby
+            let replicate: Replicate =
+                serde_json::from_str(&line).expect("Couldn't deserialize Replicate");
+            match self.ledger.apply(replicate.entry.clone()) {
+                Ok(true) => self.replicate(ctx, replicate.entry, None),
+                Ok(false) => {}
+                Err(e) => println!("[ERROR] Couldn't write the journal: {}", e),
+            }
+        }
+    }
+
+    /// The connection with the standby ended: answer the Screens that were waiting for it, and
+    /// keep working alone until a standby connects again.
+    fn standby_lost(&mut self, ctx: &mut Context<Gateway>) {
+        println!(
+            "[{}] Lost the standby, working without replication",
+            GATEWAY_ID
+        );
+        self.replica = None;
+        let awaiting: Vec<_> = self.awaiting_replication.drain().collect();
+        for (client_ip, message) in awaiting.into_iter().filter_map(|(_, reply)| reply) {
+            self.reply(ctx, &client_ip, message);
+        }
+        self.find_standby(ctx);
+    }
 }
 
 impl Actor for Gateway {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |gateway, ctx| gateway.heartbeat(ctx));
+    }
 }
 
 /// Read a message from the Gateway's FIFO and act accordingly:
 /// If it's a PaymentCapture message, capture the payment and answer to the Screen.
-/// If it's a PaymentConfirmation message, log it to disk.
-/// If it's a Disconnect message, remove the sender from the connections HashMap.
-impl StreamHandler<Result<String, std::io::Error>> for Gateway {
-    fn handle(&mut self, read: Result<String, std::io::Error>, ctx: &mut Self::Context) {
+/// If it's a PaymentConfirmation message, log it to disk and acknowledge it.
+/// If it's a PaymentVoid message, release the captured payment, log it to disk and acknowledge it.
+/// If it's a Disconnect message, acknowledge it and remove the sender from the connections HashMap.
+/// If it's a SystemShutdown message, forward it to every Screen.
+/// If it's a Hello message, a Screen introduced itself.
+/// If the connection ended, the Screen is removed from the connections HashMap.
+impl StreamHandler<ScreenLine> for Gateway {
+    fn handle(&mut self, read: ScreenLine, ctx: &mut Self::Context) {
+        let client_ip = read.addr;
+        let Some(read) = read.line else {
+            self.screen_lost(&client_ip);
+            return;
+        };
         if let Ok(line) = read {
+            if let Some(sent_at) = self.clock.receive(&line) {
+                println!(
+                    "[{}] Received message sent at {}, clock {}",
+                    GATEWAY_ID,
+                    sent_at,
+                    self.clock.now()
+                );
+            }
             if line.contains("\"PaymentCapture\"") {
                 let mut capture: PaymentCapture =
                     serde_json::from_str(&line).expect("Couldn't deserialize PaymentCapture");
-                let client_ip = capture.ip.clone();
-                let client_id = capture.id.clone();
-                println!("[{}] wants to capture a payment", capture.id);
-
-                capture = PaymentCapture::capture_payment(capture, GATEWAY_ID, GATEWAY_IP);
-                let capture_str =
-                    serde_json::to_string(&capture).expect("Couldn't deserialize PaymentCapture");
-                Gateway::write_message(ctx, capture_str, self.connections[&client_ip].clone());
-
-                if capture.valid {
-                    println!(
-                        "[{}] the payment from {} was captured succesfully ",
-                        GATEWAY_ID, client_id
-                    );
-                } else {
-                    println!(
-                        "[{}] the payment from {} couldn't be captured",
-                        GATEWAY_ID, client_id
-                    );
-                }
+                // Contesto por la conexión por la que llegó
+                capture.ip = client_ip;
+                self.capture(ctx, capture);
             } else if line.contains("\"PaymentConfirmation\"") {
                 let confirmation: PaymentConfirmation =
                     serde_json::from_str(&line).expect("Couldn't deserialize PaymentConfirmation");
                 println!("[{}] wants to confirm a payment", confirmation.id);
-                if let Ok(()) = PaymentConfirmation::confirm_payment(&confirmation) {
-                    println!(
-                        "[{}] the payment from {} was confirmed",
-                        GATEWAY_ID, confirmation.id
-                    );
-                } else {
-                    println!(
-                        "[ERROR] There was an error while trying to confirm the payment from {}",
-                        confirmation.id
-                    );
-                }
+                println!(
+                    "[{}] the payment from {} was confirmed",
+                    GATEWAY_ID, confirmation.id
+                );
+                let entry = LedgerEntry {
+                    screen: confirmation.id.clone(),
+                    order_id: confirmation.order_data.id,
+                    payment_id: confirmation.payment_id.clone(),
+                    record: LedgerRecord::Confirmed(confirmation),
+                };
+                self.settle(ctx, client_ip, entry, "PaymentConfirmation");
+            } else if line.contains("\"PaymentVoid\"") {
+                let void: PaymentVoid =
+                    serde_json::from_str(&line).expect("Couldn't deserialize PaymentVoid");
+                println!(
+                    "[{}] wants to void the payment of order {}",
+                    void.id, void.order_id
+                );
+                println!("[{}] the payment from {} was voided", GATEWAY_ID, void.id);
+                let entry = LedgerEntry {
+                    screen: void.id.clone(),
+                    order_id: void.order_id,
+                    payment_id: void.payment_id.clone(),
+                    record: LedgerRecord::Voided(void),
+                };
+                self.settle(ctx, client_ip, entry, "PaymentVoid");
+            } else if line.contains("\"SystemShutdown\"") {
+                self.system_shutdown(ctx);
             } else if line.contains("\"Disconnect\"") {
                 let disconnect: Disconnect =
                     serde_json::from_str(&line).expect("Couldn't deserialize Disconnect");
-                self.connections.remove(&disconnect.ip);
+                // Respondo con otro Disconnect, así la pantalla sabe que puede salir
+                if let Some(connection) = self.connections.remove(&client_ip) {
+                    let ack = Disconnect::new(self.ip.to_string(), GATEWAY_ID.to_string());
+                    let ack_str = self.clock.stamp(
+                        &serde_json::to_string(&ack).expect("Couldn't serialize Disconnect"),
+                    );
+                    Gateway::write_message(ctx, ack_str, connection);
+                }
                 println!("[EXIT] {} has disconnected", disconnect.id);
             } else {
                 println!(
@@ -102,16 +644,168 @@ impl StreamHandler<Result<String, std::io::Error>> for Gateway {
                     &line[5..]
                 );
             }
-        } else {
-            println!("[ERROR] Failed to read line {:?}", read);
+        } else if let Err(e) = read {
+            // La Pantalla salió sin cerrar la sesión TLS: la conexión se cortó
+            if e.kind() == ErrorKind::UnexpectedEof {
+                println!("[ERROR] The connection with {} was cut: {}", client_ip, e);
+            } else {
+                println!("[ERROR] Failed to read message {:?}", e);
+            }
         }
     }
 
-    /// Stop the context if all the Screens have disconnected.
+    /// A connection ended. The Gateway keeps accepting Screens, unless the system is shutting
+    /// down and all of them left: then flush the journal and exit.
     fn finished(&mut self, ctx: &mut Self::Context) {
-        if self.connections.is_empty() {
+        if self.shutting_down && self.connections.is_empty() {
             println!("[EXIT] All screens are disconnected. Stopping context...");
             ctx.stop();
+            self.flush_journal();
+            System::current().stop();
+        }
+    }
+}
+
+/// Read a message from the other Gateway: replicated entries and heartbeats if this is the
+/// standby, acknowledgements if this is the primary.
+/// If the connection ended, the standby takes over, and the primary keeps working alone.
+impl StreamHandler<ReplicaLine> for Gateway {
+    fn handle(&mut self, read: ReplicaLine, ctx: &mut Self::Context) {
+        match (read.line, self.role) {
+            (Some(Ok(line)), _) if line.contains("\"Term\"") => {
+                let term: Term = serde_json::from_str(&line).expect("Couldn't deserialize Term");
+                self.handle_term(ctx, term);
+            }
+            (Some(Ok(line)), Role::Standby) => self.handle_primary_message(ctx, line),
+            (Some(Ok(line)), Role::Primary) => self.handle_standby_message(ctx, line),
+            (Some(Err(e)), _) => println!("[ERROR] Failed to read line from the replica: {}", e),
+            (None, Role::Standby) => {
+                println!("[{}] Lost the connection with the primary", GATEWAY_ID);
+                self.take_over(ctx);
+            }
+            (None, Role::Primary) => {
+                if self.replica.is_some() {
+                    self.standby_lost(ctx);
+                }
+            }
+        }
+    }
+
+    fn finished(&mut self, _ctx: &mut Self::Context) {}
+}
+
+/// Message to start the shutdown of the whole system, typed on the Gateway's terminal.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct StartSystemShutdown();
+
+impl Handler<StartSystemShutdown> for Gateway {
+    type Result = ();
+
+    fn handle(&mut self, _msg: StartSystemShutdown, ctx: &mut Context<Self>) -> Self::Result {
+        self.system_shutdown(ctx);
+    }
+}
+
+/// Message sent when the standby couldn't be reached. The primary serves the Screens without
+/// it, with the epoch it had.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct StandbyUnreachable();
+
+impl Handler<StandbyUnreachable> for Gateway {
+    type Result = ();
+
+    fn handle(&mut self, _msg: StandbyUnreachable, ctx: &mut Context<Self>) -> Self::Result {
+        if self.role == Role::Primary {
+            self.serve(ctx);
+        }
+    }
+}
+
+/// Message with the connection of a Screen, accepted by the Gateway server.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ScreenConnected(pub (String, FramedStream<Transport>));
+
+impl Handler<ScreenConnected> for Gateway {
+    type Result = ();
+
+    fn handle(&mut self, msg: ScreenConnected, ctx: &mut Context<Self>) -> Self::Result {
+        let (addr, stream) = msg.0;
+        if self.shutting_down {
+            println!(
+                "[{}] {} refused, the system is shutting down",
+                GATEWAY_ID, addr
+            );
+            return;
+        }
+        let (read, write) = stream.split();
+        <Gateway as StreamHandler<ScreenLine>>::add_stream(screen_stream(addr.clone(), read), ctx);
+        self.connections.insert(addr, Arc::new(Mutex::new(write)));
+    }
+}
+
+/// Message with the connection to the standby, made by the primary. The primary tells the
+/// standby its epoch, and sends it a copy of the whole Ledger before any new entry.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct StandbyConnected(pub FramedStream<Transport>);
+
+impl Handler<StandbyConnected> for Gateway {
+    type Result = ();
+
+    fn handle(&mut self, msg: StandbyConnected, ctx: &mut Context<Self>) -> Self::Result {
+        if self.shutting_down {
+            return;
+        }
+        println!("[{}] Standby connected, replicating the ledger", GATEWAY_ID);
+        let (read, write) = msg.0.split();
+        <Gateway as StreamHandler<ReplicaLine>>::add_stream(replica_stream(read), ctx);
+        self.joined_replica = true;
+        let write = Arc::new(Mutex::new(write));
+        let term =
+            serde_json::to_string(&Term::new(self.epoch(), true)).expect("Couldn't serialize Term");
+        Gateway::write_message(ctx, term, write.clone());
+        self.replicate_to(ctx, write);
+    }
+}
+
+/// Message with the connection of the primary, accepted by the standby. If this Gateway already
+/// took over, the other one is an outdated primary: it is told the epoch of this one, and stands
+/// by for it.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PrimaryConnected(pub FramedStream<Transport>);
+
+impl Handler<PrimaryConnected> for Gateway {
+    type Result = ();
+
+    fn handle(&mut self, msg: PrimaryConnected, ctx: &mut Context<Self>) -> Self::Result {
+        if self.replica.is_some() || self.shutting_down {
+            println!("[{}] Primary refused, there already is one", GATEWAY_ID);
+            return;
+        }
+        let (read, write) = msg.0.split();
+        <Gateway as StreamHandler<ReplicaLine>>::add_stream(replica_stream(read), ctx);
+        self.joined_replica = false;
+        let write = Arc::new(Mutex::new(write));
+        match self.role {
+            Role::Standby => {
+                println!("[{}] Primary connected, standing by", GATEWAY_ID);
+                self.replica = Some(write);
+                self.last_heartbeat = Some(Instant::now());
+            }
+            Role::Primary => {
+                println!(
+                    "[{}] An outdated primary connected, it stands by for this one",
+                    GATEWAY_ID
+                );
+                let term = serde_json::to_string(&Term::new(self.epoch(), true))
+                    .expect("Couldn't serialize Term");
+                Gateway::write_message(ctx, term, write.clone());
+                self.replicate_to(ctx, write);
+            }
         }
     }
 }
@@ -0,0 +1,250 @@
+use crate::payment_confirmation::PaymentConfirmation;
+use crate::payment_void::PaymentVoid;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Error;
+
+/// State of the payment of an order.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum LedgerRecord {
+    Captured { valid: bool },
+    Confirmed(PaymentConfirmation),
+    Voided(PaymentVoid),
+}
+
+/// A change in the payment of an order of a Screen. It is the unit copied to the standby.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LedgerEntry {
+    pub screen: String,
+    pub order_id: usize,
+    // Id que le dio la Pantalla al pago al capturarlo
+    #[serde(default)]
+    pub payment_id: String,
+    pub record: LedgerRecord,
+}
+
+impl LedgerEntry {
+    /// Key of the payment in the Ledger: its id, or the Screen and order ids if the Screen
+    /// doesn't give ids to its payments.
+    fn key(&self) -> String {
+        if self.payment_id.is_empty() {
+            format!("{}/{}", self.screen, self.order_id)
+        } else {
+            self.payment_id.clone()
+        }
+    }
+}
+
+/// Ledger has the last state of every payment, by the id its Screen gave it, so a repeated
+/// message doesn't capture or log a payment twice. A confirmed or voided payment doesn't change
+/// anymore.
+pub struct Ledger {
+    entries: HashMap<String, LedgerEntry>,
+    journal: &'static str,
+}
+
+impl Ledger {
+    /// Create an empty Ledger, that logs confirmed and voided payments to `journal`.
+    pub fn new(journal: &'static str) -> Self {
+        Ledger {
+            entries: HashMap::new(),
+            journal,
+        }
+    }
+
+    /// Forget every payment and empty the journal, before copying the Ledger of another
+    /// Gateway.
+    pub fn clear(&mut self) -> Result<(), Error> {
+        self.entries.clear();
+        File::create(self.journal).map(|_| ())
+    }
+
+    /// Result of the capture of a payment, if it was already captured.
+    pub fn capture_result(&self, entry: &LedgerEntry) -> Option<bool> {
+        self.entries
+            .get(&entry.key())
+            .map(|entry| match entry.record {
+                LedgerRecord::Captured { valid } => valid,
+                LedgerRecord::Confirmed(_) => true,
+                LedgerRecord::Voided(_) => false,
+            })
+    }
+
+    /// Store the entry, and log it to the journal if the payment was confirmed or voided.
+    /// Returns false if nothing changed: the payment was already captured, or it was already
+    /// confirmed or voided.
+    pub fn apply(&mut self, entry: LedgerEntry) -> Result<bool, Error> {
+        let key = entry.key();
+        let unchanged = matches!(
+            (self.entries.get(&key).map(|old| &old.record), &entry.record),
+            (Some(_), LedgerRecord::Captured { .. })
+                | (
+                    Some(LedgerRecord::Confirmed(_) | LedgerRecord::Voided(_)),
+                    _
+                )
+        );
+        if unchanged {
+            return Ok(false);
+        }
+        match &entry.record {
+            LedgerRecord::Captured { .. } => {}
+            LedgerRecord::Confirmed(confirmation) => {
+                PaymentConfirmation::confirm_payment(confirmation, self.journal)?
+            }
+            LedgerRecord::Voided(void) => PaymentVoid::void_payment(void, self.journal)?,
+        }
+        self.entries.insert(key, entry);
+        Ok(true)
+    }
+
+    /// File where confirmed and voided payments are logged.
+    pub fn journal(&self) -> &'static str {
+        self.journal
+    }
+
+    /// Every entry of the Ledger, to copy them to a standby that just connected.
+    pub fn entries(&self) -> Vec<LedgerEntry> {
+        self.entries.values().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::modules::order_json::OrderJSON;
+    use common::modules::order_prep::OrderPrep;
+    use common::modules::priority::Priority;
+    use std::fs;
+
+    fn journal(name: &str) -> &'static str {
+        let path = std::env::temp_dir().join(format!("ledger_{}_{}.txt", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        Box::leak(path.to_string_lossy().into_owned().into_boxed_str())
+    }
+
+    fn entry(payment_id: &str, order_id: usize, record: LedgerRecord) -> LedgerEntry {
+        LedgerEntry {
+            screen: "0".to_string(),
+            order_id,
+            payment_id: payment_id.to_string(),
+            record,
+        }
+    }
+
+    fn confirmed(payment_id: &str, order_id: usize) -> LedgerEntry {
+        let order = OrderPrep::from(OrderJSON {
+            id: order_id,
+            size: 1,
+            flavours: vec!["Vainilla".to_string()],
+            priority: Priority::default(),
+        });
+        let confirmation = PaymentConfirmation::new(
+            String::new(),
+            "0".to_string(),
+            order,
+            payment_id.to_string(),
+        );
+        entry(payment_id, order_id, LedgerRecord::Confirmed(confirmation))
+    }
+
+    fn voided(payment_id: &str, order_id: usize) -> LedgerEntry {
+        let void = PaymentVoid::new(
+            String::new(),
+            "0".to_string(),
+            order_id,
+            payment_id.to_string(),
+        );
+        entry(payment_id, order_id, LedgerRecord::Voided(void))
+    }
+
+    fn captured(payment_id: &str, order_id: usize, valid: bool) -> LedgerEntry {
+        entry(payment_id, order_id, LedgerRecord::Captured { valid })
+    }
+
+    fn journal_lines(journal: &str) -> usize {
+        fs::read_to_string(journal)
+            .unwrap_or_default()
+            .lines()
+            .count()
+    }
+
+    #[test]
+    fn replayed_capture_keeps_the_first_result() {
+        let mut ledger = Ledger::new(journal("replayed_capture"));
+        assert!(ledger.apply(captured("a-1", 1, false)).unwrap());
+        assert!(!ledger.apply(captured("a-1", 1, true)).unwrap());
+        assert_eq!(
+            ledger.capture_result(&captured("a-1", 1, true)),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn replayed_confirmation_is_logged_once() {
+        let journal = journal("replayed_confirmation");
+        let mut ledger = Ledger::new(journal);
+        ledger.apply(captured("a-1", 1, true)).unwrap();
+        assert!(ledger.apply(confirmed("a-1", 1)).unwrap());
+        assert!(!ledger.apply(confirmed("a-1", 1)).unwrap());
+        assert!(!ledger.apply(captured("a-1", 1, true)).unwrap());
+        assert_eq!(journal_lines(journal), 1);
+        assert_eq!(ledger.capture_result(&captured("a-1", 1, true)), Some(true));
+    }
+
+    #[test]
+    fn settled_payment_doesnt_change() {
+        let journal = journal("settled");
+        let mut ledger = Ledger::new(journal);
+        ledger.apply(captured("a-1", 1, true)).unwrap();
+        ledger.apply(captured("a-2", 2, true)).unwrap();
+        assert!(ledger.apply(voided("a-1", 1)).unwrap());
+        assert!(!ledger.apply(confirmed("a-1", 1)).unwrap());
+        assert!(ledger.apply(confirmed("a-2", 2)).unwrap());
+        assert!(!ledger.apply(voided("a-2", 2)).unwrap());
+        assert_eq!(journal_lines(journal), 2);
+        assert_eq!(
+            ledger.capture_result(&captured("a-1", 1, true)),
+            Some(false)
+        );
+        assert_eq!(ledger.capture_result(&captured("a-2", 2, true)), Some(true));
+    }
+
+    #[test]
+    fn reused_order_id_is_a_new_payment() {
+        let mut ledger = Ledger::new(journal("reused_order_id"));
+        ledger.apply(captured("a-1", 1, true)).unwrap();
+        ledger.apply(confirmed("a-1", 1)).unwrap();
+        // La Pantalla se reinició y volvió a usar el id de orden 1
+        assert_eq!(ledger.capture_result(&captured("b-1", 1, true)), None);
+        assert!(ledger.apply(captured("b-1", 1, false)).unwrap());
+        assert_eq!(
+            ledger.capture_result(&captured("b-1", 1, true)),
+            Some(false)
+        );
+        assert_eq!(ledger.entries().len(), 2);
+    }
+
+    #[test]
+    fn entries_without_payment_id_use_screen_and_order() {
+        let mut ledger = Ledger::new(journal("without_payment_id"));
+        assert!(ledger.apply(captured("", 1, true)).unwrap());
+        assert!(!ledger.apply(captured("", 1, false)).unwrap());
+        assert!(ledger.apply(captured("", 2, false)).unwrap());
+        assert_eq!(ledger.capture_result(&captured("", 2, true)), Some(false));
+    }
+
+    #[test]
+    fn cleared_ledger_copies_the_payments_again() {
+        let journal = journal("cleared");
+        let mut ledger = Ledger::new(journal);
+        ledger.apply(captured("a-1", 1, true)).unwrap();
+        ledger.apply(confirmed("a-1", 1)).unwrap();
+        ledger.clear().unwrap();
+        assert_eq!(journal_lines(journal), 0);
+        assert!(ledger.entries().is_empty());
+        // El nuevo principal le copia el mismo pago
+        assert!(ledger.apply(confirmed("a-1", 1)).unwrap());
+        assert_eq!(journal_lines(journal), 1);
+    }
+}
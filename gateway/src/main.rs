@@ -1,54 +1,113 @@
 mod gateway;
+mod ledger;
+mod replication;
+use actix::Actor;
 use actix::System;
-use actix::{Actor, StreamHandler};
-use common::modules::constants::SCREEN_COUNT;
+use common::modules::auth;
 use common::modules::disconnect;
+use common::modules::framing;
+use common::modules::handshake;
+use common::modules::hello;
+use common::modules::logical_clock;
+use common::modules::payment_ack;
 use common::modules::payment_capture;
 use common::modules::payment_confirmation;
-use gateway::Gateway;
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::io::{split, AsyncBufReadExt, BufReader};
+use common::modules::payment_void;
+use common::modules::system_shutdown;
+use common::modules::tls;
+use framing::FramedStream;
+use gateway::{Gateway, PrimaryConnected, Role, StartSystemShutdown};
+use replication::{connect_standby, welcome_primary};
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead};
+use std::thread;
+use tls::Transport;
 use tokio::net::TcpListener;
-use tokio::sync::Mutex;
-use tokio_stream::wrappers::LinesStream;
 
 const GATEWAY_ID: &str = "GATEWAY";
 const GATEWAY_IP: &str = "127.0.0.1:20000";
+const GATEWAY_BACKUP_IP: &str = "127.0.0.1:20001";
+const GATEWAY_REPLICATION_IP: &str = "127.0.0.1:20002";
+const JOURNAL: &str = "log.txt";
+const BACKUP_JOURNAL: &str = "log_backup.txt";
+const EPOCH_FILE: &str = "epoch.txt";
+const BACKUP_EPOCH_FILE: &str = "epoch_backup.txt";
 
-/// Start the Gateway server, and accept the connections from all the Screen instances.
-/// After that, create the Gateway actor with the Write half of the stream of each Screen.
+/// Start the Gateway. By default it is the primary: it starts the Gateway actor, looks for the
+/// standby, and then keeps accepting the connections from the Screen instances, which are handed
+/// to the actor, and copies every payment to the standby. If the other Gateway already took over,
+/// it stands by for it instead.
+/// With `backup` as argument it is the standby: it waits for the primary on the replication
+/// address, and takes over if the primary goes down.
+/// Typing `shutdown` on the terminal shuts down the whole system.
 fn main() {
+    let role = match env::args().nth(1).as_deref() {
+        Some("backup") => Role::Standby,
+        _ => Role::Primary,
+    };
+    if auth::shared_key().is_some() {
+        println!("[{}] Messages are signed with the shared key", GATEWAY_ID);
+    }
+    if tls::acceptor().is_some() {
+        if tls::checks_client_certificates() {
+            println!("[{}] Screen connections use TLS, only Screens with a known certificate are accepted", GATEWAY_ID);
+        } else {
+            println!("[{}] Screen connections use TLS", GATEWAY_ID);
+        }
+    }
     let system = System::new();
 
     system.block_on(async {
-        let gateway_server = TcpListener::bind(GATEWAY_IP)
-            .await
-            .expect("[ERROR] Couldn't start the Gateway server");
-        println!("[{}] Awaiting for incoming connections", GATEWAY_ID);
+        let gateway = match role {
+            Role::Primary => {
+                println!("[{}] Looking for the standby", GATEWAY_ID);
+                let gateway = Gateway::new(Role::Primary, GATEWAY_IP, JOURNAL, EPOCH_FILE).start();
+                // Atiende a las pantallas recién cuando sabe si el otro ya es el principal
+                connect_standby(gateway.clone());
+                gateway
+            }
+            Role::Standby => {
+                let replication_server = TcpListener::bind(GATEWAY_REPLICATION_IP)
+                    .await
+                    .expect("[ERROR] Couldn't start the replication server");
+                // El standby recibe todo el ledger del principal: arranca con el journal vacío
+                File::create(BACKUP_JOURNAL).expect("[ERROR] Couldn't create the journal");
+                println!("[{}] Standing by, awaiting for the primary", GATEWAY_ID);
 
-        let mut connections = HashMap::new();
-        for _ in 0..SCREEN_COUNT {
-            let (stream, addr) = gateway_server
-                .accept()
-                .await
-                .expect("[ERROR] Couldn't establish a connection with the client");
-            connections.insert(addr.to_string(), stream);
-        }
+                let gateway =
+                    Gateway::new(Role::Standby, GATEWAY_IP, BACKUP_JOURNAL, BACKUP_EPOCH_FILE)
+                        .start();
+                let primary_gateway = gateway.clone();
+                actix::spawn(async move {
+                    while let Ok((stream, _)) = replication_server.accept().await {
+                        let mut stream = FramedStream::new(Transport::from(stream));
+                        if welcome_primary(&mut stream).await {
+                            primary_gateway.do_send(PrimaryConnected(stream));
+                        }
+                    }
+                });
+                gateway
+            }
+        };
 
-        Gateway::create(|ctx| {
-            let mut screen_connections = HashMap::new();
-            for connection in connections {
-                let (read, write_half) = split(connection.1);
-                Gateway::add_stream(LinesStream::new(BufReader::new(read).lines()), ctx);
-                let write = Arc::new(Mutex::new(write_half));
-                screen_connections.insert(connection.0, write);
+        // Leo la terminal en un hilo aparte: una lectura bloqueante en el runtime no lo deja
+        // cerrarse cuando se detiene el sistema
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines().map_while(Result::ok) {
+                if line.trim() == "shutdown" {
+                    gateway.do_send(StartSystemShutdown());
+                } else {
+                    println!("[{}] Unknown command. Usage: shutdown", GATEWAY_ID);
+                }
             }
-            Gateway::new(screen_connections)
         });
     });
 
     system
         .run()
         .expect("[ERROR] Couldn't run Gateway with Actix System");
+    if auth::shared_key().is_some() {
+        println!("[AUTH] Rejected messages: {}", auth::rejections());
+    }
 }
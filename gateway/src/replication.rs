@@ -0,0 +1,164 @@
+use crate::framing::{FrameReader, FramedStream};
+use crate::gateway::{Gateway, StandbyConnected, StandbyUnreachable};
+use crate::handshake::{introduce, welcome};
+use crate::hello::ROLE_GATEWAY;
+use crate::ledger::LedgerEntry;
+use crate::tls::Transport;
+use crate::{GATEWAY_ID, GATEWAY_REPLICATION_IP};
+use actix::Addr;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Error;
+use std::time::Duration;
+use tokio::io::ReadHalf;
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+use tokio_stream::{Stream, StreamExt};
+
+/// Time between two heartbeats of the primary Gateway.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// Time without news from the primary after which the standby takes over.
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(3);
+/// Time between two attempts of the primary to connect to the standby.
+const STANDBY_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Replicate message, sent by the primary to copy a Ledger entry to the standby.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Replicate {
+    pub message: String, // "Replicate"
+    pub seq: u64,
+    pub entry: LedgerEntry,
+}
+
+impl Replicate {
+    pub fn new(seq: u64, entry: LedgerEntry) -> Self {
+        Replicate {
+            message: "Replicate".to_string(),
+            seq,
+            entry,
+        }
+    }
+}
+
+/// ReplicateAck message, sent by the standby once it stored the entry `seq`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReplicateAck {
+    pub message: String, // "ReplicateAck"
+    pub seq: u64,
+}
+
+impl ReplicateAck {
+    pub fn new(seq: u64) -> Self {
+        ReplicateAck {
+            message: "ReplicateAck".to_string(),
+            seq,
+        }
+    }
+}
+
+/// Heartbeat message, sent by the primary so the standby knows it is alive.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Heartbeat {
+    pub message: String, // "Heartbeat"
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Heartbeat {
+            message: "Heartbeat".to_string(),
+        }
+    }
+}
+
+/// Term message, the first one on the connection between the two Gateways. The one that connects
+/// sends it with `primary` set, to serve as primary. The standby answers without `primary` and
+/// the epoch of the new term, higher than the ones both knew. A Gateway already serving as
+/// primary answers with `primary` set and its own epoch, and the other one steps down.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Term {
+    pub message: String, // "Term"
+    pub epoch: u64,
+    pub primary: bool,
+}
+
+impl Term {
+    pub fn new(epoch: u64, primary: bool) -> Self {
+        Term {
+            message: "Term".to_string(),
+            epoch,
+            primary,
+        }
+    }
+}
+
+/// Last epoch this Gateway knew, stored in `path`. 0 if it never knew one.
+pub fn load_epoch(path: &str) -> u64 {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|epoch| epoch.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Store the epoch, so the Gateway still knows it after a restart.
+pub fn store_epoch(path: &str, epoch: u64) -> Result<(), Error> {
+    fs::write(path, epoch.to_string())
+}
+
+/// A message read from the connection between the primary and the standby. `line` is None once the
+/// connection ended.
+pub struct ReplicaLine {
+    pub line: Option<Result<String, std::io::Error>>,
+}
+
+/// Wrap every message read from the other Gateway, and add an end marker when the connection ends.
+pub fn replica_stream(read: FrameReader<ReadHalf<Transport>>) -> impl Stream<Item = ReplicaLine> {
+    read.map(|line| ReplicaLine { line: Some(line) })
+        .chain(tokio_stream::once(ReplicaLine { line: None }))
+}
+
+/// Keep trying to connect to the standby in the background, and hand the connection to the
+/// primary Gateway once the standby welcomed it. The Gateway is told every time the standby
+/// can't be reached, so it can serve the Screens without it.
+pub fn connect_standby(gateway: Addr<Gateway>) {
+    actix::spawn(async move {
+        loop {
+            if let Ok(stream) = TcpStream::connect(GATEWAY_REPLICATION_IP).await {
+                let mut stream = FramedStream::new(Transport::from(stream));
+                match introduce(&mut stream, ROLE_GATEWAY, GATEWAY_ID.to_string()).await {
+                    Ok(_) => {
+                        gateway.do_send(StandbyConnected(stream));
+                        return;
+                    }
+                    Err(e) => println!("[{}] Couldn't connect to the standby: {}", GATEWAY_ID, e),
+                }
+            }
+            gateway.do_send(StandbyUnreachable());
+            sleep(STANDBY_RETRY_DELAY).await;
+        }
+    });
+}
+
+/// Welcome the primary on a connection accepted by the standby. Returns false, after logging
+/// why, if the connection was refused.
+pub async fn welcome_primary(stream: &mut FramedStream<Transport>) -> bool {
+    match welcome(
+        stream,
+        ROLE_GATEWAY,
+        GATEWAY_ID.to_string(),
+        &[ROLE_GATEWAY],
+    )
+    .await
+    {
+        Ok(primary) => {
+            println!(
+                "[{}] Primary introduced itself, protocol version {}",
+                GATEWAY_ID, primary.version
+            );
+            true
+        }
+        Err(e) => {
+            println!("[{}] Connection refused: {}", GATEWAY_ID, e);
+            false
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use serde::Deserialize;
+
+use common::modules::flavour::FLAVOURS;
+
+/// Kind of a message read from a Screen or a Robot.
+#[derive(Debug, PartialEq)]
+pub enum MessageKind {
+    OrderRequest,
+    OrderRelease,
+    OrderPrep,
+    SystemShutdown,
+    Leave,
+    Disconnect,
+    SnapshotMarker,
+    InventoryQuery,
+    FlavourAck,
+    Flavour,
+    Unknown,
+}
+
+// Campos con los que se reconoce un mensaje, el resto se ignora
+#[derive(Deserialize)]
+struct Header {
+    message: Option<String>,
+    name: Option<String>,
+}
+
+/// Kind of the message, taken from its `message` field. A message may carry others inside it
+/// (a SnapshotMarker carries the queued OrderPreps), so its content can't be searched for the
+/// type. Flavour tokens have no `message` field, and are told apart by their `name`.
+pub fn message_kind(message: &str) -> MessageKind {
+    let Ok(header) = serde_json::from_str::<Header>(message) else {
+        return MessageKind::Unknown;
+    };
+    match (header.message.as_deref(), header.name) {
+        (Some("OrderRequest"), _) => MessageKind::OrderRequest,
+        (Some("OrderRelease"), _) => MessageKind::OrderRelease,
+        (Some("OrderPrep"), _) => MessageKind::OrderPrep,
+        (Some("SystemShutdown"), _) => MessageKind::SystemShutdown,
+        (Some("Leave"), _) => MessageKind::Leave,
+        (Some("Disconnect"), _) => MessageKind::Disconnect,
+        (Some("SnapshotMarker"), _) => MessageKind::SnapshotMarker,
+        (Some("InventoryQuery"), _) => MessageKind::InventoryQuery,
+        (Some("FlavourAck"), _) => MessageKind::FlavourAck,
+        (None, Some(name)) if FLAVOURS.contains(&name.as_str()) => MessageKind::Flavour,
+        _ => MessageKind::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use common::modules::flavour::Flavour;
+    use common::modules::order_prep::OrderPrep;
+    use common::modules::priority::Priority;
+
+    use super::*;
+    use crate::pending_order::PendingOrder;
+    use crate::snapshot::{RobotSnapshot, SnapshotMarker};
+
+    fn order() -> OrderPrep {
+        OrderPrep {
+            message: "OrderPrep".to_string(),
+            ip: "127.0.0.1:40000".to_string(),
+            id: 7,
+            size: 500,
+            flavours: vec!["Vainilla".to_string(), "Tramontana".to_string()],
+            fail_flag: 0,
+            priority: Priority::Normal,
+            stamp: 3,
+            clock: Default::default(),
+        }
+    }
+
+    #[test]
+    fn marker_with_queued_orders_is_a_snapshot_marker() {
+        let mut marker = SnapshotMarker::new(0);
+        marker.robots.push(RobotSnapshot {
+            id: 0,
+            orders: vec![PendingOrder::new(order(), "0".to_string())],
+            consumed: HashMap::new(),
+            refilled: HashMap::new(),
+        });
+        let marker_str = serde_json::to_string(&marker).unwrap();
+        assert!(marker_str.contains("\"OrderPrep\""));
+
+        assert_eq!(message_kind(&marker_str), MessageKind::SnapshotMarker);
+        let marker: SnapshotMarker = serde_json::from_str(&marker_str).unwrap();
+        assert_eq!(marker.robots[0].orders[0].order.id, 7);
+    }
+
+    #[test]
+    fn order_prep_is_told_apart_from_the_flavours_it_names() {
+        let order_str = serde_json::to_string(&order()).unwrap();
+        assert_eq!(message_kind(&order_str), MessageKind::OrderPrep);
+    }
+
+    #[test]
+    fn flavour_token_is_recognised_by_its_name() {
+        let token = Flavour::new("Dulce de leche".to_string(), 1000.0);
+        let token_str = serde_json::to_string(&token).unwrap();
+        assert_eq!(message_kind(&token_str), MessageKind::Flavour);
+    }
+
+    #[test]
+    fn unknown_and_invalid_messages_are_unknown() {
+        assert_eq!(message_kind(r#"{"message":"Nope"}"#), MessageKind::Unknown);
+        assert_eq!(message_kind("not json"), MessageKind::Unknown);
+        assert_eq!(message_kind("{}"), MessageKind::Unknown);
+        assert_eq!(
+            message_kind(r#"{"name":"Frutilla","amount":1.0}"#),
+            MessageKind::Unknown
+        );
+    }
+}
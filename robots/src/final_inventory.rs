@@ -0,0 +1,41 @@
+use std::fs::File;
+use std::io::{Error, Write};
+
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+
+use common::modules::flavour::Flavour;
+
+/// Stock of every Flavour when the token ring was stopped by a system shutdown.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FinalInventory {
+    // Robot que detuvo el anillo
+    pub stopped_by: usize,
+    pub stopped_at: String,
+    pub flavours: Vec<Flavour>,
+}
+
+impl FinalInventory {
+    /// FinalInventory constructor. The Flavours are sorted by name.
+    pub fn new(stopped_by: usize, mut flavours: Vec<Flavour>) -> Self {
+        flavours.sort_by(|a, b| a.name.cmp(&b.name));
+        FinalInventory {
+            stopped_by,
+            stopped_at: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            flavours,
+        }
+    }
+
+    /// Writes the inventory to `final_inventory_<date>.json`. Returns the name of the file.
+    pub fn write_to_file(&self) -> Result<String, Error> {
+        let file_name = format!(
+            "final_inventory_{}.json",
+            Local::now().format("%Y%m%d%H%M%S")
+        );
+        let mut file = File::create(&file_name)?;
+        let inventory_str = serde_json::to_string_pretty(self)?;
+        writeln!(file, "{}", inventory_str)?;
+
+        Ok(file_name)
+    }
+}
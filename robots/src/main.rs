@@ -1,16 +1,29 @@
 use std::collections::HashMap;
 use std::{env, sync::Arc};
 
-use tokio::io::{split, AsyncBufReadExt, BufReader};
-use tokio::net::TcpListener;
-use tokio::sync::Mutex;
-use tokio_stream::wrappers::LinesStream;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::{oneshot, Mutex};
 
 use actix::{Actor, StreamHandler};
 
-mod flavour;
+mod dispatch;
+mod final_inventory;
+mod peer;
+mod pending_order;
+mod refill;
+mod reservation;
 mod robot;
-use robot::{connect_robots, Robot, RobotReconnect, RobotStart, Shutdown, ROBOT_IP_PREFIX};
+mod snapshot;
+mod state;
+use common::modules::auth::{rejections, shared_key};
+use common::modules::framing::FramedStream;
+use common::modules::hello::ROLE_SCREEN;
+use peer::{greet, peer_stream, Peer};
+use refill::Refill;
+use robot::{
+    connect_robots, QueryInventory, RestoreState, Robot, RobotReconnect, RobotStart,
+    ScreenConnected, Shutdown, StartSnapshot, StartSystemShutdown, DEFAULT_ORDER_CAPACITY,
+};
 
 #[actix_rt::main]
 async fn main() {
@@ -19,64 +32,74 @@ async fn main() {
         .expect("[ERROR] Missing id parameter")
         .parse()
         .expect("[ERROR] id must be a number");
+    let capacity: usize = env::args()
+        .nth(2)
+        .map(|arg| arg.parse().expect("[ERROR] capacity must be a number"))
+        .unwrap_or(DEFAULT_ORDER_CAPACITY);
 
-    let (previous_robot, next_robot, screens) = connect_robots(id).await;
+    if shared_key().is_some() {
+        println!("[ROBOT {}] Messages are signed with the shared key", id);
+    }
+    let (previous_robot, next_robot, screens, listener) = connect_robots(id).await;
+    let (exit_tx, mut exit_rx) = oneshot::channel();
 
     let robot = Robot::create(|ctx| {
         let addr_previous = previous_robot.1;
 
         let local_ip_previous = previous_robot
             .0
+            .get_ref()
             .local_addr()
             .expect("[ERROR] Couldn't get local IP address");
-        let (read_previous, write_half_previous) = split(previous_robot.0);
-        Robot::add_stream(LinesStream::new(BufReader::new(read_previous).lines()), ctx);
-        let write_previous = Arc::new(Mutex::new(write_half_previous));
+        let (read_previous, write_previous) = previous_robot.0.split();
+        Robot::add_stream(peer_stream(Peer::Robot(addr_previous), read_previous), ctx);
+        let write_previous = Arc::new(Mutex::new(write_previous));
 
         let local_ip_next = next_robot
+            .get_ref()
             .local_addr()
             .expect("[ERROR] Couldn't get local IP address");
-        let (read_next, write_half_next) = split(next_robot);
-        Robot::add_stream(LinesStream::new(BufReader::new(read_next).lines()), ctx);
-        let write_next = Arc::new(Mutex::new(write_half_next));
+        let addr_next = next_robot
+            .get_ref()
+            .peer_addr()
+            .expect("[ERROR] Couldn't get the address of the next Robot");
+        let (read_next, write_next) = next_robot.split();
+        Robot::add_stream(peer_stream(Peer::Robot(addr_next), read_next), ctx);
+        let write_next = Arc::new(Mutex::new(write_next));
 
         let mut screen_connections = HashMap::new();
-        for connection in screens {
-            let local_ip = connection
-                .1
+        for (addr, (stream, screen_id)) in screens {
+            let local_ip = stream
+                .get_ref()
                 .local_addr()
                 .expect("[ERROR] Couldn't get local IP address");
-            let (read, write_half) = split(connection.1);
-            Robot::add_stream(LinesStream::new(BufReader::new(read).lines()), ctx);
-            let write = Arc::new(Mutex::new(write_half));
-            screen_connections.insert(connection.0, (write, local_ip));
+            let (read, write) = stream.split();
+            Robot::add_stream(peer_stream(Peer::Screen(addr.to_string()), read), ctx);
+            let write = Arc::new(Mutex::new(write));
+            screen_connections.insert(addr, ((write, local_ip), screen_id));
         }
 
-        let need_flavours = HashMap::from([
-            ("Vainilla".to_string(), 0.0),
-            ("Dulce de leche".to_string(), 0.0),
-            ("Tramontana".to_string(), 0.0),
-        ]);
-
         let ack_flavours = HashMap::from([
             ("Vainilla".to_string(), 0),
             ("Dulce de leche".to_string(), 0),
             ("Tramontana".to_string(), 0),
         ]);
 
-        let current_order = None;
-
         Robot::new(
             id,
             (addr_previous, (write_previous, local_ip_previous)),
             (write_next, local_ip_next),
             screen_connections,
-            need_flavours,
             ack_flavours,
-            current_order,
+            capacity,
+            exit_tx,
         )
     });
 
+    if robot.send(RestoreState()).await.is_err() {
+        println!("[ROBOT {}] Error while restoring the persisted state", id);
+    }
+
     if id == 0 {
         match robot.send(RobotStart()).await {
             Ok(_) => println!("[ROBOT {}] Starting ring, sending initial tokens", id),
@@ -85,32 +108,66 @@ async fn main() {
     }
 
     let mut async_stdin = BufReader::new(tokio::io::stdin()).lines();
-    let listener = TcpListener::bind(ROBOT_IP_PREFIX.to_string() + &*id.to_string())
-        .await
-        .unwrap();
 
+    let mut exited = false;
     loop {
         tokio::select! {
+            _ = &mut exit_rx => {
+                // Se apagó todo el sistema
+                exited = true;
+                break;
+            }
             Ok(Some(input)) = async_stdin.next_line() => {
                 if input.trim() == "q" {
                     break;
+                } else if input.trim() == "shutdown" {
+                    robot.do_send(StartSystemShutdown());
+                } else if input.trim() == "snapshot" {
+                    robot.do_send(StartSnapshot());
+                } else if input.trim() == "inventory" {
+                    robot.do_send(QueryInventory());
+                } else if let Some(refill) = Refill::parse(&input) {
+                    robot.do_send(refill);
+                } else {
+                    println!("[ROBOT {}] Unknown command. Usage: refill <flavour> <amount> | inventory | snapshot | shutdown | q", id);
                 }
             }
             Ok((stream, addr)) = listener.accept() => {
-                println!("[ROBOT {}] New Robot connected with address {}", id, addr);
-                let local_ip = stream.local_addr().expect("[ERROR] Couldn't get local IP address");
-                let (read, write_half) = split(stream);
-                let reader = BufReader::new(read).lines();
-                match robot.send(RobotReconnect((addr, write_half, local_ip, reader))).await {
-                    Ok(_) => println!("[ROBOT {}] Ring reconnected and closed", id),
-                    Err(_) => println!("[ROBOT {}] Error while reconnecting ring", id),
-                }
+                let robot = robot.clone();
+                let mut stream = FramedStream::new(stream);
+                // La conexión nueva se presenta con un Hello: puede ser una Pantalla o un Robot.
+                // Una conexión que tarda en presentarse no demora a las demás
+                actix::spawn(async move {
+                    match greet(id, &mut stream, addr).await {
+                        Some(peer) if peer.role == ROLE_SCREEN => {
+                            println!("[ROBOT {}] Screen {} connected with address {}", id, peer.id, addr);
+                            robot.do_send(ScreenConnected((addr, stream, peer.id)));
+                        }
+                        // greet sólo acepta Pantallas y Robots
+                        Some(_) => {
+                            println!("[ROBOT {}] New Robot connected with address {}", id, addr);
+                            let local_ip = stream.get_ref().local_addr().expect("[ERROR] Couldn't get local IP address");
+                            let (read, write) = stream.split();
+                            match robot.send(RobotReconnect((addr, write, local_ip, read))).await {
+                                Ok(_) => println!("[ROBOT {}] Ring reconnected and closed", id),
+                                Err(_) => println!("[ROBOT {}] Error while reconnecting ring", id),
+                            }
+                        }
+                        None => {}
+                    }
+                });
             }
         }
     }
 
-    // Cierre ordenado
-    let _ = robot.send(Shutdown()).await;
-    // Para dar tiempo a que se envien los Disconnect
-    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    // Cierre ordenado: espero a que el anillo se cierre sin este Robot
+    if !exited {
+        let _ = robot.send(Shutdown()).await;
+        let _ = exit_rx.await;
+    }
+    if shared_key().is_some() {
+        println!("[AUTH] Rejected messages: {}", rejections());
+    }
+    // La lectura de stdin ocupa un hilo bloqueante que el runtime esperaría al cerrarse
+    std::process::exit(0);
 }
@@ -0,0 +1,65 @@
+use std::net::SocketAddr;
+
+use tokio::io::ReadHalf;
+use tokio::net::TcpStream;
+use tokio_stream::{Stream, StreamExt};
+
+use common::modules::framing::{FrameReader, FramedStream};
+use common::modules::handshake::{welcome, PeerInfo};
+use common::modules::hello::{ROLE_ROBOT, ROLE_SCREEN};
+
+/// The other end of a connection of the Robot.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Peer {
+    Robot(SocketAddr),
+    // Dirección de la Pantalla, la misma clave que en Robot::screens
+    Screen(String),
+}
+
+/// A message read from a connection, tagged with the peer that sent it. `line` is None once the
+/// connection ended.
+pub struct PeerLine {
+    pub peer: Peer,
+    pub line: Option<Result<String, std::io::Error>>,
+}
+
+/// Tags every message read from a connection with its peer, and adds an end marker when the
+/// connection ends, so the Robot knows which peer left.
+pub fn peer_stream(
+    peer: Peer,
+    read: FrameReader<ReadHalf<TcpStream>>,
+) -> impl Stream<Item = PeerLine> {
+    let end = PeerLine {
+        peer: peer.clone(),
+        line: None,
+    };
+    read.map(move |line| PeerLine {
+        peer: peer.clone(),
+        line: Some(line),
+    })
+    .chain(tokio_stream::once(end))
+}
+
+/// Answers the Hello a new connection starts with. Screens and Robots are welcome if they speak
+/// a protocol version this Robot understands. Returns None, after logging why, if the connection
+/// was refused.
+pub async fn greet(
+    id: usize,
+    stream: &mut FramedStream<TcpStream>,
+    addr: SocketAddr,
+) -> Option<PeerInfo> {
+    match welcome(
+        stream,
+        ROLE_ROBOT,
+        id.to_string(),
+        &[ROLE_SCREEN, ROLE_ROBOT],
+    )
+    .await
+    {
+        Ok(peer) => Some(peer),
+        Err(e) => {
+            println!("[ROBOT {}] Connection from {} refused: {}", id, addr, e);
+            None
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use common::modules::order_prep::OrderPrep;
+
+/// An Order accepted by a Robot, with the amount of each Flavour it still needs.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingOrder {
+    pub order: OrderPrep,
+    pub need_flavours: HashMap<String, f64>,
+    // Milisegundos desde UNIX_EPOCH en que se aceptó la Order, para el envejecimiento
+    #[serde(default)]
+    pub accepted_at: u64,
+    // Id con el que se presentó la Pantalla, el mismo aunque se reconecte desde otra dirección
+    #[serde(default)]
+    pub screen: String,
+}
+
+impl PendingOrder {
+    /// PendingOrder constructor, for an Order of the Screen with the given id. The size of the
+    /// Order is split evenly between its Flavours.
+    pub fn new(order: OrderPrep, screen: String) -> Self {
+        let size_per_flavour = PendingOrder::size_per_flavour(&order);
+        let need_flavours = order
+            .flavours
+            .iter()
+            .map(|flavour| (flavour.to_string(), size_per_flavour))
+            .collect();
+        PendingOrder {
+            order,
+            need_flavours,
+            accepted_at: now_millis(),
+            screen,
+        }
+    }
+
+    fn size_per_flavour(order: &OrderPrep) -> f64 {
+        order.size as f64 / order.flavours.len() as f64
+    }
+
+    /// Amount of each Flavour the Order already consumed.
+    pub fn consumed(&self) -> Vec<(String, f64)> {
+        let size_per_flavour = PendingOrder::size_per_flavour(&self.order);
+        self.need_flavours
+            .iter()
+            .map(|(flavour, need)| (flavour.to_string(), size_per_flavour - need))
+            .filter(|(_, consumed)| *consumed > 0.0)
+            .collect()
+    }
+
+    /// Priority of the Order, including the time it has been waiting in the queue.
+    pub fn aged_priority(&self) -> u64 {
+        let waited = now_millis().saturating_sub(self.accepted_at);
+        self.order.priority.aged(Duration::from_millis(waited))
+    }
+
+    /// Amount of the Flavour that the Order still needs.
+    pub fn needs(&self, flavour: &str) -> f64 {
+        *self.need_flavours.get(flavour).unwrap_or(&0.0)
+    }
+
+    /// True if every Flavour of the Order has been served.
+    pub fn is_complete(&self) -> bool {
+        self.need_flavours.values().all(|&x| x == 0.0)
+    }
+
+    /// True if the Order was sent by the given Screen with the given id.
+    pub fn is(&self, screen: &str, id: usize) -> bool {
+        self.order.ip == screen && self.order.id == id
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use common::modules::flavour::Ticket;
+    use common::modules::priority::{Priority, AGING_INTERVAL};
+
+    use super::*;
+
+    fn order(id: usize, priority: Priority, stamp: u64) -> OrderPrep {
+        OrderPrep {
+            message: "OrderPrep".to_string(),
+            ip: "127.0.0.1:40000".to_string(),
+            id,
+            size: 300,
+            flavours: vec!["Vainilla".to_string(), "Frutilla".to_string()],
+            fail_flag: 0,
+            priority,
+            stamp,
+            clock: Default::default(),
+        }
+    }
+
+    fn ticket(pending: &PendingOrder) -> Ticket {
+        Ticket {
+            robot: 0,
+            screen: pending.order.ip.to_string(),
+            order_id: pending.order.id,
+            stamp: pending.order.stamp,
+            priority: pending.aged_priority(),
+            amount: pending.needs("Vainilla"),
+            epoch: 0,
+        }
+    }
+
+    #[test]
+    fn size_is_split_between_the_flavours() {
+        let mut pending = PendingOrder::new(order(1, Priority::Normal, 1), "0".to_string());
+        assert_eq!(pending.needs("Vainilla"), 150.0);
+        assert_eq!(pending.needs("Frutilla"), 150.0);
+        assert_eq!(pending.needs("Menta"), 0.0);
+        assert!(pending.consumed().is_empty());
+
+        pending.need_flavours.insert("Vainilla".to_string(), 50.0);
+        assert_eq!(pending.consumed(), vec![("Vainilla".to_string(), 100.0)]);
+        assert!(!pending.is_complete());
+
+        pending.need_flavours.insert("Vainilla".to_string(), 0.0);
+        pending.need_flavours.insert("Frutilla".to_string(), 0.0);
+        assert!(pending.is_complete());
+        assert!(pending.is("127.0.0.1:40000", 1));
+        assert!(!pending.is("127.0.0.1:40000", 2));
+    }
+
+    #[test]
+    fn older_orders_go_first_within_a_priority() {
+        let newer = PendingOrder::new(order(1, Priority::Normal, 8), "0".to_string());
+        let older = PendingOrder::new(order(2, Priority::Normal, 3), "0".to_string());
+        let mut tickets = [ticket(&newer), ticket(&older)];
+        tickets.sort_by_key(|ticket| ticket.sort_key());
+        assert_eq!(tickets[0].order_id, 2);
+    }
+
+    #[test]
+    fn higher_priority_goes_before_older_orders() {
+        let express = PendingOrder::new(order(1, Priority::Express, 8), "0".to_string());
+        let staff = PendingOrder::new(order(2, Priority::Staff, 1), "0".to_string());
+        let normal = PendingOrder::new(order(3, Priority::Normal, 3), "0".to_string());
+        let mut tickets = [ticket(&staff), ticket(&normal), ticket(&express)];
+        tickets.sort_by_key(|ticket| ticket.sort_key());
+        let ids: Vec<usize> = tickets.iter().map(|ticket| ticket.order_id).collect();
+        assert_eq!(ids, vec![1, 3, 2]);
+    }
+
+    #[test]
+    fn waiting_orders_age_into_a_higher_priority() {
+        let fresh = PendingOrder::new(order(1, Priority::Normal, 8), "0".to_string());
+        let mut waiting = PendingOrder::new(order(2, Priority::Staff, 9), "0".to_string());
+        waiting.accepted_at -= 2 * AGING_INTERVAL.as_millis() as u64;
+        assert_eq!(fresh.aged_priority(), 1);
+        assert_eq!(waiting.aged_priority(), 2);
+
+        let mut tickets = [ticket(&fresh), ticket(&waiting)];
+        tickets.sort_by_key(|ticket| ticket.sort_key());
+        assert_eq!(tickets[0].order_id, 2);
+    }
+}
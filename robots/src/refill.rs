@@ -0,0 +1,110 @@
+use std::{fs::OpenOptions, io::Error, io::Write};
+
+use actix::Message;
+use chrono::Local;
+
+const REFILL_COMMAND: &str = "refill";
+const REFILL_LOG: &str = "refills.txt";
+
+/// Refill message for Robots.
+/// Adds `amount` to the Flavour `flavour` the next time its token passes through the Robot.
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct Refill {
+    pub flavour: String,
+    pub amount: f64,
+}
+
+impl Refill {
+    /// Parses an operator command with the format `refill <flavour> <amount>`.
+    /// The flavour name may contain spaces (e.g. `refill Dulce de leche 5`).
+    pub fn parse(input: &str) -> Option<Self> {
+        let (command, arguments) = input.trim().split_once(char::is_whitespace)?;
+        if command != REFILL_COMMAND {
+            return None;
+        }
+        let (flavour, amount) = arguments.trim().rsplit_once(char::is_whitespace)?;
+        let amount: f64 = amount.parse().ok()?;
+        if flavour.trim().is_empty() || !amount.is_finite() || amount <= 0.0 {
+            return None;
+        }
+        Some(Refill {
+            flavour: flavour.trim().to_string(),
+            amount,
+        })
+    }
+
+    /// Records the receipt of a refill applied to a Flavour token, by logging it to disk.
+    pub fn record_receipt(
+        robot_id: usize,
+        flavour: &str,
+        amount: f64,
+        new_amount: f64,
+    ) -> Result<(), Error> {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(REFILL_LOG)?;
+        let log_str = Local::now().format("%Y-%m-%d %H:%M:%S").to_string()
+            + &format!(
+                " ROBOT {} refilled {} of {}, new amount {}",
+                robot_id, amount, flavour, new_amount
+            );
+        writeln!(file, "{}", log_str)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(input: &str) -> Option<(String, f64)> {
+        Refill::parse(input).map(|refill| (refill.flavour, refill.amount))
+    }
+
+    #[test]
+    fn refill_command_is_parsed() {
+        assert_eq!(
+            parsed("refill Vainilla 5"),
+            Some(("Vainilla".to_string(), 5.0))
+        );
+        assert_eq!(
+            parsed("  refill\tTramontana   2.5 \n"),
+            Some(("Tramontana".to_string(), 2.5))
+        );
+    }
+
+    #[test]
+    fn flavour_name_may_contain_spaces() {
+        assert_eq!(
+            parsed("refill Dulce de leche 5"),
+            Some(("Dulce de leche".to_string(), 5.0))
+        );
+    }
+
+    #[test]
+    fn command_must_be_followed_by_whitespace() {
+        assert_eq!(parsed("refillVainilla 5"), None);
+        assert_eq!(parsed("refills Vainilla 5"), None);
+    }
+
+    #[test]
+    fn invalid_commands_are_rejected() {
+        for input in [
+            "",
+            "refill",
+            "refill Vainilla",
+            "refill 5",
+            "refill Vainilla cinco",
+            "refill Vainilla 0",
+            "refill Vainilla -3",
+            "refill Vainilla NaN",
+            "refill Vainilla inf",
+            "snapshot",
+        ] {
+            assert_eq!(parsed(input), None, "{:?}", input);
+        }
+    }
+}
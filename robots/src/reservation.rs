@@ -0,0 +1,54 @@
+use std::time::{Duration, Instant};
+
+/// Tiempo que un Robot mantiene el lugar para una Order después de responder un OrderRequest
+pub const RESERVATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Place in the Order queue kept for a Screen that received an answer to its OrderRequest,
+/// until it sends the OrderPrep, releases it or the reservation expires.
+pub struct Reservation {
+    pub screen: String,
+    pub order_id: usize,
+    pub expires_at: Instant,
+}
+
+impl Reservation {
+    /// Reservation constructor.
+    pub fn new(screen: String, order_id: usize) -> Self {
+        Reservation {
+            screen,
+            order_id,
+            expires_at: Instant::now() + RESERVATION_TIMEOUT,
+        }
+    }
+
+    /// True if the reservation was made for the given Screen and Order.
+    pub fn is_for(&self, screen: &str, order_id: usize) -> bool {
+        self.screen == screen && self.order_id == order_id
+    }
+
+    /// True if the reservation is no longer valid.
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.expires_at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reservation_is_for_its_screen_and_order() {
+        let reservation = Reservation::new("127.0.0.1:40000".to_string(), 4);
+        assert!(reservation.is_for("127.0.0.1:40000", 4));
+        assert!(!reservation.is_for("127.0.0.1:40000", 5));
+        assert!(!reservation.is_for("127.0.0.1:40001", 4));
+    }
+
+    #[test]
+    fn reservation_expires_after_its_deadline() {
+        let mut reservation = Reservation::new("127.0.0.1:40000".to_string(), 4);
+        assert!(!reservation.is_expired());
+        reservation.expires_at = Instant::now();
+        assert!(reservation.is_expired());
+    }
+}
@@ -1,29 +1,57 @@
 use std::collections::HashMap;
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::{net::SocketAddr, sync::Arc, thread, time::Duration};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::{io::Error, net::SocketAddr, sync::Arc, thread, time::Duration};
 
 use actix::fut::wrap_future;
 use actix::{
-    Actor, AsyncContext, Context, ContextFutureSpawner, Handler, Message, StreamHandler, WrapFuture,
+    Actor, ActorFutureExt, AsyncContext, Context, ContextFutureSpawner, Handler, Message,
+    StreamHandler, WrapFuture,
 };
 use common::modules::disconnect::Disconnect;
-use tokio::io::{split, AsyncBufReadExt, AsyncWriteExt, BufReader, Lines, ReadHalf, WriteHalf};
+use common::modules::leave::Leave;
+use tokio::io::{ReadHalf, WriteHalf};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use tokio::sync::{oneshot, Mutex};
 use tokio::time::sleep;
-use tokio_stream::wrappers::LinesStream;
 
-use crate::flavour::Flavour;
+use crate::dispatch::{message_kind, MessageKind};
+use crate::final_inventory::FinalInventory;
+use crate::peer::{greet, peer_stream, Peer, PeerLine};
+use crate::pending_order::PendingOrder;
+use crate::refill::Refill;
+use crate::reservation::Reservation;
+use crate::snapshot::{RingSnapshot, RobotSnapshot, SnapshotMarker, SnapshotRecording};
+use crate::state::RobotState;
 
 use common::modules::constants::ROBOT_COUNT;
-use common::modules::constants::SCREEN_COUNT;
-use common::modules::order_prep::OrderPrep;
+use common::modules::flavour::{Flavour, FlavourAck, Ticket, FLAVOURS, INITIAL_FLAVOUR_AMOUNT};
+use common::modules::framing::{FrameReader, FrameWriter, FramedStream};
+use common::modules::handshake::introduce;
+use common::modules::hello::ROLE_ROBOT;
+use common::modules::inventory::{InventoryQuery, InventoryReport};
+use common::modules::logical_clock::LogicalClock;
+use common::modules::order_prep::{
+    OrderPrep, ORDER_ACCEPTED, ORDER_FAILED, ORDER_SUCCESS, ROBOT_OCCUPIED,
+};
+use common::modules::order_release::OrderRelease;
 use common::modules::order_request::OrderRequest;
+use common::modules::stock_update::StockUpdate;
+use common::modules::system_shutdown::SystemShutdown;
 
 const FLAVOUR_TIMEOUT: u128 = 60000;
 pub const ROBOT_IP_PREFIX: &str = "127.0.0.1:3000";
+pub const DEFAULT_ORDER_CAPACITY: usize = 2;
+/// Tiempo máximo que espera un Robot que deja el anillo a que el anterior se conecte al siguiente
+const LEAVE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Tiempo máximo que espera el coordinador del cierre a que le lleguen todos los tokens
+const RING_STOP_TIMEOUT: Duration = Duration::from_secs(60);
+/// Tiempo que se espera antes de salir, para que se envíen los últimos mensajes
+const EXIT_DELAY: Duration = Duration::from_millis(500);
+// Tiempo que espera un Robot reiniciado a que se reconecte la Pantalla de una Order recuperada
+const SCREEN_RECONNECT_TIMEOUT: Duration = Duration::from_secs(60);
 
-type Connection = (Arc<Mutex<WriteHalf<TcpStream>>>, SocketAddr);
+type WriteArcMutex = Arc<Mutex<FrameWriter<WriteHalf<TcpStream>>>>;
+type Connection = (WriteArcMutex, SocketAddr);
 
 /// Robot Actor. Receives and sends Flavours to other Robots. Processes Orders from Screens.
 pub struct Robot {
@@ -31,202 +59,905 @@ pub struct Robot {
     addr_previous: SocketAddr,
     previous_robot: Connection,
     next_robot: Connection,
+    // Id del Robot siguiente en el anillo
+    next_id: usize,
     screens: HashMap<String, Connection>,
-    need_flavours: HashMap<String, f64>,
+    // Id con el que se presentó cada Pantalla, por dirección
+    screen_ids: HashMap<String, String>,
     ack_flavours: HashMap<String, u128>,
-    current_order: Option<OrderPrep>,
+    orders: Vec<PendingOrder>,
+    // Orders recuperadas al reiniciar, esperando que se reconecte su Pantalla
+    orphaned: Vec<PendingOrder>,
+    // Gustos de Orders abandonadas que se devuelven al token la próxima vez que pase
+    returns: HashMap<String, f64>,
+    capacity: usize,
+    reservations: Vec<Reservation>,
+    pending_refills: HashMap<String, f64>,
+    last_seen: HashMap<String, Flavour>,
+    consumed: HashMap<String, f64>,
+    refilled: HashMap<String, f64>,
+    snapshot: Option<SnapshotRecording>,
+    // Momento en que se envió cada token al siguiente Robot, para medir la vuelta al anillo
+    token_sent_at: HashMap<String, Instant>,
+    ring_rtt: Option<Duration>,
+    // Reloj lógico, también ordena las Orders de todo el anillo
+    clock: LogicalClock,
+    // El Robot está dejando el anillo y no toma Orders nuevas
+    leaving: bool,
+    // Avisa a main que ya se puede salir
+    exit: Option<oneshot::Sender<()>>,
+    // Se pidió cerrar todo el sistema
+    stopping: bool,
+    // Menor id de Robot que hizo circular el cierre, el que detiene el anillo
+    coordinator: Option<usize>,
+    // Este Robot es el coordinador y está juntando los tokens para detener el anillo
+    collecting: Option<HashMap<String, Flavour>>,
 }
 
 impl Robot {
-    /// Robot constructor.
+    /// Robot constructor. Every Screen comes with the id it introduced itself with.
     pub fn new(
         id: usize,
         previous: (SocketAddr, Connection),
         next_robot: Connection,
-        screens: HashMap<String, Connection>,
-        need_flavours: HashMap<String, f64>,
+        screens: HashMap<String, (Connection, String)>,
         ack_flavours: HashMap<String, u128>,
-        current_order: Option<OrderPrep>,
+        capacity: usize,
+        exit: oneshot::Sender<()>,
     ) -> Self {
         let addr_previous = previous.0;
         let previous_robot = previous.1;
+        let screen_ids = screens
+            .iter()
+            .map(|(addr, (_, screen_id))| (addr.to_string(), screen_id.to_string()))
+            .collect();
+        let screens = screens
+            .into_iter()
+            .map(|(addr, (connection, _))| (addr, connection))
+            .collect();
         Robot {
             id,
             addr_previous,
             previous_robot,
             next_robot,
+            next_id: (id + 1) % ROBOT_COUNT,
             screens,
-            need_flavours,
+            screen_ids,
             ack_flavours,
-            current_order,
+            orders: Vec::new(),
+            orphaned: Vec::new(),
+            returns: HashMap::new(),
+            capacity,
+            reservations: Vec::new(),
+            pending_refills: HashMap::new(),
+            last_seen: HashMap::new(),
+            consumed: HashMap::new(),
+            refilled: HashMap::new(),
+            snapshot: None,
+            token_sent_at: HashMap::new(),
+            ring_rtt: None,
+            clock: LogicalClock::new(format!("robot_{}", id)),
+            leaving: false,
+            exit: Some(exit),
+            stopping: false,
+            coordinator: None,
+            collecting: None,
         }
     }
 
-    /// Handles an incoming Flavour. If it has an Order than needs it, it consumes the required amount, if possible.
-    /// If the Order is complete or cancelled, it sends a message to the Screen.
+    /// Handles an incoming Flavour. The stock is given to the Orders in the order of the waiting
+    /// list carried by the token, so Orders from every Robot are served in arrival order instead
+    /// of ring position. An Order of this Robot consumes the required amount if there is enough
+    /// stock left after the earlier tickets, and it is cancelled if there isn't enough stock even
+    /// though it's first in line.
+    /// If an Order is complete or cancelled, it sends a message to the Screen.
     fn process_flavour(&mut self, ctx: &mut Context<Self>, message_str: String) {
         let mut flavour: Flavour =
             serde_json::from_str(&message_str).expect("[ERROR] Couldn't deserialize Flavour");
+        if let Some(sent_at) = self.token_sent_at.get(&flavour.name) {
+            self.ring_rtt = Some(sent_at.elapsed());
+        }
         println!(
             "[ROBOT {}] Flavour {} received, amount: {}",
             self.id, flavour.name, flavour.amount
         );
+        let level_received = StockUpdate::stock_level(flavour.amount);
+        if let Some(recording) = self.snapshot.as_mut() {
+            recording.channel_tokens.push(flavour.clone());
+        }
+
+        let restocked = self.apply_refill(&mut flavour) | self.apply_returns(&mut flavour);
+
+        // Si estoy deteniendo el anillo y se fueron todas mis Pantallas, me quedo con el token
+        let screens_left = self.screens.is_empty();
+        // El token que me quedo para detener el anillo no llega al final de la pasada
+        if restocked && screens_left && self.collecting.is_some() {
+            self.persist_state();
+        }
+        if let Some(collected) = self.collecting.as_mut().filter(|_| screens_left) {
+            println!(
+                "[ROBOT {}] Keeping Flavour {} to stop the ring",
+                self.id, flavour.name
+            );
+            collected.insert(flavour.name.to_string(), flavour);
+            if collected.len() == FLAVOURS.len() {
+                self.stop_ring(ctx);
+            }
+            return;
+        }
+
+        self.update_tickets(&mut flavour);
 
-        if self.need_flavours[&flavour.name] > 0.0 && self.current_order.is_some() {
-            if flavour.amount >= self.need_flavours[&flavour.name] {
+        let mut finished = Vec::new();
+        let mut consumed = false;
+        // Cantidad del gusto reservada por los tickets anteriores que siguen esperando
+        let mut reserved = 0.0;
+        for ticket in std::mem::take(&mut flavour.tickets) {
+            let own_order = (ticket.robot == self.id)
+                .then(|| {
+                    self.orders
+                        .iter()
+                        .position(|pending| pending.is(&ticket.screen, ticket.order_id))
+                })
+                .flatten();
+            let Some(index) = own_order else {
+                reserved += ticket.amount;
+                flavour.tickets.push(ticket);
+                continue;
+            };
+            let need = self.orders[index].needs(&flavour.name);
+            let order_id = self.orders[index].order.id;
+            if flavour.amount - reserved >= need {
                 println!(
-                    "[ROBOT {}] Consuming {} of Flavour {}",
-                    self.id, self.need_flavours[&flavour.name], flavour.name
+                    "[ROBOT {}] Consuming {} of Flavour {} for Order {}",
+                    self.id, need, flavour.name, order_id
                 );
                 // Sleep según la cantidad de helado utilizada
                 let _sleep = Box::pin(
                     sleep(Duration::from_secs(2 * flavour.amount as u64)).into_actor(self),
                 );
-                flavour.amount -= self.need_flavours[&flavour.name];
-                self.need_flavours.insert(flavour.name.to_string(), 0.0);
-                if self.need_flavours.values().all(|&x| x == 0.0) {
+                flavour.amount -= need;
+                consumed = true;
+                *self.consumed.entry(flavour.name.to_string()).or_insert(0.0) += need;
+                let pending = &mut self.orders[index];
+                pending.need_flavours.insert(flavour.name.to_string(), 0.0);
+                if pending.is_complete() {
                     // Terminé Order con éxito
-                    println!("[ROBOT {:?}] Order Prep completed", self.id);
-                    let fail_flag = 0; // Significa que la orden se preparó correctamente
-                    self.send_order_prep(ctx, fail_flag);
+                    println!("[ROBOT {:?}] Order Prep {} completed", self.id, order_id);
+                    finished.push((index, ORDER_SUCCESS));
                 }
+            } else if reserved > 0.0 {
+                // Hay tickets anteriores esperando, la Order espera su turno
+                println!(
+                    "[ROBOT {}] Order Prep {} waits for earlier tickets of Flavour {}",
+                    self.id, order_id, flavour.name
+                );
+                reserved += need;
+                flavour.tickets.push(ticket);
             } else {
                 // Si no hay cantidad suficiente de helado, cancelar Order
                 println!(
-                    "[ROBOT {:?}] Order Prep cancelled, not enough ice cream",
-                    self.id
+                    "[ROBOT {:?}] Order Prep {} cancelled, not enough ice cream",
+                    self.id, order_id
                 );
-                let fail_flag = 1; // Significa que no hay cantidad suficiente de helado
-                self.send_order_prep(ctx, fail_flag);
+                finished.push((index, ORDER_FAILED));
             }
         }
+        let changed = restocked || consumed || !finished.is_empty();
+        // Saco las Orders terminadas de atrás para adelante para no invalidar los índices
+        finished.sort_by_key(|(index, _)| *index);
+        for (index, fail_flag) in finished.into_iter().rev() {
+            let pending = self.orders.remove(index);
+            self.send_order_prep(ctx, pending.order, fail_flag as usize);
+        }
+        if StockUpdate::stock_level(flavour.amount) != level_received {
+            self.broadcast_stock_update(ctx, &flavour);
+        }
+        flavour.epoch += 1;
+        self.last_seen
+            .insert(flavour.name.to_string(), flavour.clone());
+        // Persisto sólo si se consumió el gusto, terminó una Order o se le sumó stock que estaba
+        // en el estado, con el token tal como sale, así al reiniciar no queda una vuelta atrás
+        if changed {
+            self.persist_state();
+        }
         let flavour_new_str =
-            serde_json::to_string(&flavour).expect("[ERROR] Couldn't serialize Flavour") + "\n";
+            serde_json::to_string(&flavour).expect("[ERROR] Couldn't serialize Flavour");
         println!("[ROBOT {}] Sending {}", self.id, &flavour.name);
+        self.token_sent_at
+            .insert(flavour.name.to_string(), Instant::now());
         self.send_message(ctx, flavour_new_str, self.next_robot.0.clone());
 
         // self.ack_flavours.insert(flavour.name.to_string(), SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis());
 
-        // let ack = String::from("ACKToken".to_string() + &flavour.name + "\n");
+        // let ack = serde_json::to_string(&FlavourAck::new(flavour.name.to_string())).unwrap();
         // self.send_message(ctx, ack, self.write_previous.clone());
     }
 
+    /// Updates the waiting list of a Flavour: drops the tickets of Orders of this Robot that are
+    /// no longer waiting and the tickets of Robots that haven't seen the token in two rounds
+    /// (they are down), refreshes the tickets of this Robot and adds the missing ones.
+    fn update_tickets(&mut self, flavour: &mut Flavour) {
+        let max_age = 2 * ROBOT_COUNT as u64;
+        for ticket in &flavour.tickets {
+            self.clock.witness(ticket.stamp);
+        }
+        let (id, orders, epoch) = (self.id, &self.orders, flavour.epoch);
+        flavour.tickets.retain(|ticket| {
+            if ticket.robot == id {
+                orders.iter().any(|pending| {
+                    pending.is(&ticket.screen, ticket.order_id)
+                        && pending.needs(&flavour.name) > 0.0
+                })
+            } else {
+                epoch.saturating_sub(ticket.epoch) <= max_age
+            }
+        });
+
+        for pending in &self.orders {
+            let need = pending.needs(&flavour.name);
+            if need <= 0.0 {
+                continue;
+            }
+            let ticket = Ticket {
+                robot: self.id,
+                screen: pending.order.ip.to_string(),
+                order_id: pending.order.id,
+                stamp: pending.order.stamp,
+                priority: pending.aged_priority(),
+                amount: need,
+                epoch: flavour.epoch,
+            };
+            match flavour.tickets.iter_mut().find(|existing| {
+                existing.robot == self.id && pending.is(&existing.screen, existing.order_id)
+            }) {
+                Some(existing) => *existing = ticket,
+                None => flavour.tickets.push(ticket),
+            }
+        }
+        flavour.tickets.sort_by_key(|ticket| ticket.sort_key());
+    }
+
+    /// Notifies every Screen of the new stock level of a Flavour.
+    fn broadcast_stock_update(&mut self, ctx: &mut Context<Self>, flavour: &Flavour) {
+        let stock_update = StockUpdate::new(
+            ROBOT_IP_PREFIX.to_string() + &self.id.to_string(),
+            self.id,
+            flavour.name.to_string(),
+            flavour.amount,
+        );
+        println!(
+            "[ROBOT {}] Stock level of Flavour {} changed, notifying Screens",
+            self.id, flavour.name
+        );
+        let stock_update_str =
+            serde_json::to_string(&stock_update).expect("[ERROR] Couldn't serialize StockUpdate");
+        let screens: Vec<_> = self
+            .screens
+            .values()
+            .map(|screen| screen.0.clone())
+            .collect();
+        for screen in screens {
+            self.send_message(ctx, stock_update_str.clone(), screen);
+        }
+    }
+
+    /// Adds the pending refill of the Flavour, if any, to the token and records the receipt.
+    /// Returns true if there was one.
+    fn apply_refill(&mut self, flavour: &mut Flavour) -> bool {
+        let Some(amount) = self.pending_refills.remove(&flavour.name) else {
+            return false;
+        };
+        flavour.amount += amount;
+        *self.refilled.entry(flavour.name.to_string()).or_insert(0.0) += amount;
+        println!(
+            "[ROBOT {}] Refilled {} of Flavour {}, amount: {}",
+            self.id, amount, flavour.name, flavour.amount
+        );
+        if Refill::record_receipt(self.id, &flavour.name, amount, flavour.amount).is_err() {
+            println!(
+                "[ERROR] Couldn't record the refill of Flavour {}",
+                flavour.name
+            );
+        }
+        true
+    }
+
+    /// Adds to the token what abandoned Orders had consumed of the Flavour, if anything.
+    /// Returns true if there was something to return.
+    fn apply_returns(&mut self, flavour: &mut Flavour) -> bool {
+        let Some(amount) = self.returns.remove(&flavour.name) else {
+            return false;
+        };
+        flavour.amount += amount;
+        *self.consumed.entry(flavour.name.to_string()).or_insert(0.0) -= amount;
+        println!(
+            "[ROBOT {}] Returned {} of Flavour {} from abandoned Orders, amount: {}",
+            self.id, amount, flavour.name, flavour.amount
+        );
+        true
+    }
+
+    /// Gives up the recovered Orders whose Screen didn't reconnect in time. What they consumed
+    /// goes back to the tokens, and their Screens void the payments when their timeouts expire.
+    fn abandon_orphaned(&mut self) {
+        for pending in std::mem::take(&mut self.orphaned) {
+            println!(
+                "[ROBOT {}] Order {} cancelled, Screen {} didn't reconnect",
+                self.id, pending.order.id, pending.screen
+            );
+            for (flavour, amount) in pending.consumed() {
+                *self.returns.entry(flavour).or_insert(0.0) += amount;
+            }
+        }
+        self.persist_state();
+    }
+
+    /// Resumes the recovered Orders of a Screen that reconnected, answering on its new address.
+    fn resume_orders(&mut self, screen_id: &str, screen_addr: &str) {
+        let (resumed, orphaned) = std::mem::take(&mut self.orphaned)
+            .into_iter()
+            .partition(|pending| pending.screen == screen_id);
+        self.orphaned = orphaned;
+        let resumed: Vec<PendingOrder> = resumed;
+        if resumed.is_empty() {
+            return;
+        }
+        for mut pending in resumed {
+            println!(
+                "[ROBOT {}] Resuming Order {} of Screen {}, waiting for the missing Flavours",
+                self.id, pending.order.id, screen_id
+            );
+            pending.order.ip = screen_addr.to_string();
+            self.orders.push(pending);
+        }
+    }
+
+    /// Handles an Inventory Query from a Screen or from the previous Robot.
+    /// Adds the latest copy of each Flavour seen by this Robot and passes the query to the next one.
+    /// When the query completes the round, the report is sent to whoever asked for it.
+    fn process_inventory_query(&mut self, ctx: &mut Context<Self>, message_str: String) {
+        let mut query: InventoryQuery = serde_json::from_str(&message_str)
+            .expect("[ERROR] Couldn't deserialize InventoryQuery");
+        match query.origin {
+            Some(origin) if origin == self.id => {
+                println!("[ROBOT {}] Inventory Query completed the ring", self.id);
+                query.merge(self.last_seen.values());
+                self.send_inventory_report(ctx, query);
+            }
+            Some(_) => {
+                println!("[ROBOT {}] Inventory Query from previous Robot", self.id);
+                self.forward_inventory_query(ctx, query);
+            }
+            None => {
+                println!("[ROBOT {}] Inventory Query from Screen", self.id);
+                query.origin = Some(self.id);
+                self.forward_inventory_query(ctx, query);
+            }
+        }
+    }
+
+    /// Merges the Flavours seen by this Robot into the query and sends it to the next Robot.
+    fn forward_inventory_query(&mut self, ctx: &mut Context<Self>, mut query: InventoryQuery) {
+        query.merge(self.last_seen.values());
+        let query_str =
+            serde_json::to_string(&query).expect("[ERROR] Couldn't serialize InventoryQuery");
+        self.send_message(ctx, query_str, self.next_robot.0.clone());
+    }
+
+    /// Sends the result of a completed Inventory Query to the Screen that asked for it.
+    /// If it was asked through the terminal, the inventory is printed.
+    fn send_inventory_report(&mut self, ctx: &mut Context<Self>, query: InventoryQuery) {
+        let report = InventoryReport::new(
+            ROBOT_IP_PREFIX.to_string() + &self.id.to_string(),
+            self.id,
+            query.flavours,
+        );
+        if query.ip.is_empty() {
+            println!("[ROBOT {}] Inventory:", self.id);
+            for flavour in &report.flavours {
+                println!(
+                    "[ROBOT {}]   {}: {} (epoch {})",
+                    self.id, flavour.name, flavour.amount, flavour.epoch
+                );
+            }
+        } else if let Some(screen) = self.screens.get(&query.ip) {
+            let report_str =
+                serde_json::to_string(&report).expect("[ERROR] Couldn't serialize InventoryReport");
+            self.send_message(ctx, report_str, screen.0.clone());
+        }
+    }
+
+    /// Records the local state of the Robot for a snapshot.
+    fn record_state(&self) -> RobotSnapshot {
+        RobotSnapshot {
+            id: self.id,
+            orders: self.orders.clone(),
+            consumed: self.consumed.clone(),
+            refilled: self.refilled.clone(),
+        }
+    }
+
+    /// Handles a Snapshot Marker from the previous Robot.
+    /// If this Robot started the snapshot, the marker completed the ring and the combined
+    /// snapshot is written to disk. Otherwise, it records its state and passes the marker on.
+    fn process_snapshot_marker(&mut self, ctx: &mut Context<Self>, message_str: String) {
+        let mut marker: SnapshotMarker = serde_json::from_str(&message_str)
+            .expect("[ERROR] Couldn't deserialize SnapshotMarker");
+        if marker.origin != self.id {
+            println!(
+                "[ROBOT {}] Snapshot Marker from Robot {}, recording state",
+                self.id, marker.origin
+            );
+            marker.robots.push(self.record_state());
+            self.send_snapshot_marker(ctx, &marker);
+            return;
+        }
+
+        let Some(recording) = self.snapshot.take() else {
+            println!("[ROBOT {}] Unexpected Snapshot Marker ignored", self.id);
+            return;
+        };
+        println!("[ROBOT {}] Snapshot Marker completed the ring", self.id);
+        let snapshot = RingSnapshot::new(marker, recording.channel_tokens);
+        match snapshot.write_to_file() {
+            Ok(file_name) => println!(
+                "[ROBOT {}] Snapshot written to {} (consistent: {})",
+                self.id, file_name, snapshot.consistent
+            ),
+            Err(_) => println!("[ERROR] Couldn't write the snapshot to disk"),
+        }
+    }
+
+    /// Sends a Snapshot Marker to the next Robot.
+    fn send_snapshot_marker(&mut self, ctx: &mut Context<Self>, marker: &SnapshotMarker) {
+        let marker_str =
+            serde_json::to_string(marker).expect("[ERROR] Couldn't serialize SnapshotMarker");
+        self.send_message(ctx, marker_str, self.next_robot.0.clone());
+    }
+
     /// Handles an incoming Flavour ACK.
     fn process_ack(&mut self, message_str: String) {
-        let flavour = message_str[8..].to_string();
-        println!("[ROBOT {}] ACK of flavour {}", self.id, flavour);
-        self.ack_flavours.insert(flavour.clone(), 0);
+        let ack: FlavourAck =
+            serde_json::from_str(&message_str).expect("[ERROR] Couldn't deserialize FlavourAck");
+        println!("[ROBOT {}] ACK of flavour {}", self.id, ack.flavour);
+        self.ack_flavours.insert(ack.flavour, 0);
+    }
+
+    /// Free places in the Order queue, not counting the reserved ones.
+    fn free_capacity(&self) -> usize {
+        self.capacity
+            .saturating_sub(self.orders.len() + self.reservations.len())
+    }
+
+    /// Drops the reservations that expired.
+    fn purge_reservations(&mut self) {
+        let id = self.id;
+        self.reservations.retain(|reservation| {
+            if reservation.is_expired() {
+                println!(
+                    "[ROBOT {}] Reservation for Order {} expired",
+                    id, reservation.order_id
+                );
+            }
+            !reservation.is_expired()
+        });
+    }
+
+    /// Removes the reservation for the given Screen and Order. Returns true if there was one.
+    fn take_reservation(&mut self, screen: &str, order_id: usize) -> bool {
+        let reserved = self.reservations.len();
+        self.reservations
+            .retain(|reservation| !reservation.is_for(screen, order_id));
+        self.reservations.len() < reserved
+    }
+
+    /// Flavours that the pending Orders are still waiting for.
+    fn waiting_flavours(&self) -> Vec<String> {
+        FLAVOURS
+            .iter()
+            .filter(|flavour| {
+                self.orders
+                    .iter()
+                    .any(|pending| pending.needs(flavour) > 0.0)
+            })
+            .map(|flavour| flavour.to_string())
+            .collect()
     }
 
     /// Handles an Order Request from a Screen.
-    /// If it has no current Order, it accepts it and notifies the Screen.
+    /// If it has room for another Order, it reserves a place for it for a limited time and
+    /// notifies the Screen with its free capacity and load.
     fn process_order_request(&mut self, ctx: &mut Context<Self>, message_str: String) {
         println!("[ROBOT {:?}] Order Request from Screen", self.id);
-        if self.current_order.is_none() {
-            // Aceptar pedido y responder a la pantalla
-            println!("[ROBOT {:?}] Order Request accepted", self.id);
-            let mut order_request: OrderRequest = serde_json::from_str(&message_str)
-                .expect("[ERROR] Couldn't deserialize OrderRequest");
+        if self.leaving {
+            println!(
+                "[ROBOT {}] Order Request ignored, leaving the ring",
+                self.id
+            );
+            return;
+        }
+        self.purge_reservations();
+        let mut order_request: OrderRequest =
+            serde_json::from_str(&message_str).expect("[ERROR] Couldn't deserialize OrderRequest");
+        // Si ya tenía una reserva para la Order (re-broadcast), la renuevo
+        let reserved = self.take_reservation(&order_request.ip, order_request.order_id);
+        if reserved || self.free_capacity() > 0 {
+            // Reservar lugar y responder a la pantalla
+            println!(
+                "[ROBOT {:?}] Order Request accepted, place reserved for Order {}",
+                self.id, order_request.order_id
+            );
             let screen_addr = order_request.ip;
-            let screen_stream = self.screens[&screen_addr].clone();
+            let Some(screen_stream) = self.screens.get(&screen_addr).cloned() else {
+                println!(
+                    "[ROBOT {}] Order Request ignored, Screen {} is no longer connected",
+                    self.id, screen_addr
+                );
+                return;
+            };
+            self.reservations.push(Reservation::new(
+                screen_addr.to_string(),
+                order_request.order_id,
+            ));
             order_request.id = self.id;
             order_request.ip = ROBOT_IP_PREFIX.to_string() + &self.id.to_string();
+            order_request.capacity = self.free_capacity();
+            order_request.queue_len = self.orders.len();
+            order_request.waiting_flavours = self.waiting_flavours();
+            order_request.token_epochs = self
+                .last_seen
+                .iter()
+                .map(|(name, flavour)| (name.to_string(), flavour.epoch))
+                .collect();
+            order_request.ring_rtt_ms = self.ring_rtt.map(|rtt| rtt.as_millis() as u64);
             let order_request_response_str = serde_json::to_string(&order_request)
-                .expect("[ERROR] Couldn't serialize OrderRequest")
-                + "\n";
+                .expect("[ERROR] Couldn't serialize OrderRequest");
             self.send_message(ctx, order_request_response_str, screen_stream.0);
         } else {
-            // Rechazar pedido, ya tengo uno, no respondo a la pantalla
-            // Podria reenviar el mensaje OrderRequest con un flag que indique que el Robot esta ocupado
+            // Rechazar pedido, no tengo lugar libre ni reservado, no respondo a la pantalla
             println!(
-                "[ROBOT {:?}] Order Request ignored, already have an Order",
+                "[ROBOT {:?}] Order Request ignored, the Order queue is full or reserved",
                 self.id
             );
         }
     }
 
     /// Handles an Order Prep from a Screen.
-    /// If doesn't have a current Order, it accepts it and waits for the needed Flavours.
-    /// If it has a current Order, it is rejected and notifies the Screen.
+    /// If it has a reservation for it or room for another Order, it is queued and waits for the needed Flavours.
+    /// If the queue is full, it is rejected and notifies the Screen.
     fn process_order_prep(&mut self, ctx: &mut Context<Self>, message_str: String) {
         println!("[ROBOT {}] Order Prep from Screen", self.id);
         let mut order: OrderPrep =
             serde_json::from_str(&message_str).expect("[ERROR] Couldn't deserialize OrderPrep");
-        if self.current_order.is_some() {
-            // Si ya tome una order (tengo Some(Order)), tengo que rechazarla
+        self.purge_reservations();
+        let reserved = self.take_reservation(&order.ip, order.id);
+        if self
+            .orders
+            .iter()
+            .any(|pending| pending.is(&order.ip, order.id))
+        {
+            println!(
+                "[ROBOT {}] Order Prep {} ignored, it is already queued",
+                self.id, order.id
+            );
+        } else if self.leaving {
             println!(
-                "[ROBOT {:?}] Order Prep rejected, already have an Order",
+                "[ROBOT {}] Order Prep {} rejected, leaving the ring",
+                self.id, order.id
+            );
+            self.send_order_prep(ctx, order, ROBOT_OCCUPIED as usize);
+        } else if !reserved && self.free_capacity() == 0 {
+            // Si la cola está llena, tengo que rechazarla
+            println!(
+                "[ROBOT {:?}] Order Prep rejected, the Order queue is full",
                 self.id
             );
-            order.fail_flag = 2; // Significa que ya tengo una Order y no puedo tomarla, que la Pantalla intente con otro Robot.
-            let screen_addr = order.ip.to_string();
-            let screen_stream = self.screens[&screen_addr].clone();
-            order.ip = ROBOT_IP_PREFIX.to_string() + &self.id.to_string();
-            let order_prep_str =
-                serde_json::to_string(&order).expect("[ERROR] Couldn't serialize OrderPrep") + "\n";
-            self.send_message(ctx, order_prep_str, screen_stream.0);
+            // Significa que no puedo tomar la Order, que la Pantalla intente con otro Robot.
+            self.send_order_prep(ctx, order, ROBOT_OCCUPIED as usize);
         } else {
-            println!("[ROBOT {}] Order Prep accepted, preparing Order", self.id);
-            let size_per_flavour = order.size as f64 / order.flavours.len() as f64;
-            for flavour in &order.flavours {
-                self.need_flavours
-                    .insert(flavour.to_string(), size_per_flavour);
-            }
-            self.current_order = Some(order);
+            println!(
+                "[ROBOT {}] Order Prep {} ({:?}) accepted, {} Orders in queue",
+                self.id,
+                order.id,
+                order.priority,
+                self.orders.len() + 1
+            );
+            // El timestamp define el lugar de la Order en la lista de espera de cada gusto
+            order.stamp = self.clock.tick().lamport;
+            // Aviso a la Pantalla que la Order está en preparación
+            self.send_order_prep(ctx, order.clone(), ORDER_ACCEPTED as usize);
+            let screen = self.screen_ids.get(&order.ip).cloned().unwrap_or_default();
+            self.orders.push(PendingOrder::new(order, screen));
+            self.persist_state();
         }
     }
 
-    /// Sends a message with the successful or cancelled Order Prep to the Screen.
-    fn send_order_prep(&mut self, ctx: &mut Context<Self>, fail_flag: usize) {
-        let mut order_prep = self
-            .current_order
-            .take()
-            .expect("Will always have an Order at this point");
+    /// Handles an Order Release from a Screen that chose another Robot for the Order, or that
+    /// gave up on an Order this Robot accepted. A cancelled Order is dropped, and the Flavours it
+    /// already consumed go back to their tokens.
+    fn process_order_release(&mut self, message_str: String) {
+        let release: OrderRelease =
+            serde_json::from_str(&message_str).expect("[ERROR] Couldn't deserialize OrderRelease");
+        if self.take_reservation(&release.ip, release.order_id) {
+            println!(
+                "[ROBOT {}] Reservation for Order {} released",
+                self.id, release.order_id
+            );
+        }
+        let Some(index) = self
+            .orders
+            .iter()
+            .position(|pending| pending.is(&release.ip, release.order_id))
+        else {
+            return;
+        };
+        let pending = self.orders.remove(index);
+        println!(
+            "[ROBOT {}] Order Prep {} cancelled by its Screen",
+            self.id, release.order_id
+        );
+        for (flavour, amount) in pending.consumed() {
+            *self.returns.entry(flavour).or_insert(0.0) += amount;
+        }
+        self.persist_state();
+    }
+
+    /// Sends a message with the successful, cancelled or rejected Order Prep to the Screen.
+    fn send_order_prep(
+        &mut self,
+        ctx: &mut Context<Self>,
+        mut order_prep: OrderPrep,
+        fail_flag: usize,
+    ) {
         let screen_addr = order_prep.ip.to_string();
-        let screen_stream = self.screens[&screen_addr].clone();
+        let Some(screen_stream) = self.screens.get(&screen_addr).cloned() else {
+            println!(
+                "[ROBOT {}] Order Prep {} not sent, Screen {} is no longer connected",
+                self.id, order_prep.id, screen_addr
+            );
+            return;
+        };
         order_prep.ip = ROBOT_IP_PREFIX.to_string() + &self.id.to_string();
         order_prep.fail_flag = fail_flag;
-        let order_prep_str = serde_json::to_string(&order_prep)
-            .expect("[ERROR] Couldn't serialize OrderPrep")
-            + "\n";
+        let order_prep_str =
+            serde_json::to_string(&order_prep).expect("[ERROR] Couldn't serialize OrderPrep");
         self.send_message(ctx, order_prep_str, screen_stream.0);
     }
 
-    /// Sends a message to the desired destination.
-    fn send_message(
-        &mut self,
-        ctx: &mut Context<Self>,
-        msg: String,
-        dest: Arc<Mutex<WriteHalf<TcpStream>>>,
-    ) {
+    /// Writes the pending Orders, the consumed amounts, the last copy of each token and the stock
+    /// still to be added to the tokens to disk. It is written when an Order is accepted, consumes
+    /// a Flavour, finishes or is cancelled, and when a refill is entered or added to its token.
+    fn persist_state(&self) {
+        let state = RobotState {
+            orders: self.orders.iter().chain(&self.orphaned).cloned().collect(),
+            consumed: self.consumed.clone(),
+            refilled: self.refilled.clone(),
+            last_seen: self.last_seen.clone(),
+            returns: self.returns.clone(),
+            pending_refills: self.pending_refills.clone(),
+        };
+        if state.save(self.id).is_err() {
+            println!("[ERROR] Couldn't persist the state of the Robot");
+        }
+    }
+
+    /// Stamps a message with the logical clock and sends it to the desired destination.
+    fn send_message(&mut self, ctx: &mut Context<Self>, msg: String, dest: WriteArcMutex) {
+        let msg = self.clock.stamp(&msg);
         wrap_future::<_, Self>(async move {
-            dest.lock()
-                .await
-                .write_all(msg.as_bytes())
-                .await
-                .unwrap_or_else(|_| {
-                    println!("[ERROR] Couldn't send the following message:");
-                    println!("{}", msg);
-                });
+            dest.lock().await.send(&msg).await.unwrap_or_else(|_| {
+                println!("[ERROR] Couldn't send the following message:");
+                println!("{}", msg);
+            });
         })
         .spawn(ctx);
     }
 
-    /// Handles a Disconnect message.
+    /// Handles a Disconnect message, telling the sender apart by the connection it arrived on.
+    /// If a Screen disconnected, it answers with another Disconnect so the Screen can exit, and
+    /// forgets the Screen.
     /// If the next Robot disconnected, it tries to connect to the next one available.
-    fn process_disconnect(&mut self, ctx: &mut Context<Self>, message_str: String) {
+    fn process_disconnect(&mut self, ctx: &mut Context<Self>, peer: Peer, message_str: String) {
         let disconnect: Disconnect =
             serde_json::from_str(&message_str).expect("[ERROR] Couldn't deserialize Disconnect");
-        if disconnect.ip == self.addr_previous.to_string() {
-            println!("[ROBOT {}] The previous Robot is down", self.id);
-            // self.skip_dead_robot(ctx, "previous".to_string());
-            println!("[ROBOT {}] Wating for reconnection", self.id);
-        } else {
-            println!("[ROBOT {}] The next Robot is down", self.id);
-            self.skip_dead_robot(ctx);
+        match peer {
+            Peer::Screen(screen_addr) => {
+                println!(
+                    "[ROBOT {}] Screen {} is disconnecting",
+                    self.id, disconnect.id
+                );
+                if let Some((screen_stream, _)) = self.screens.get(&screen_addr).cloned() {
+                    let ack = Disconnect::new(
+                        ROBOT_IP_PREFIX.to_string() + &self.id.to_string(),
+                        self.id.to_string(),
+                    );
+                    let msg =
+                        serde_json::to_string(&ack).expect("[ERROR] Couldn't serialize Disconnect");
+                    self.send_message(ctx, msg, screen_stream);
+                }
+                self.remove_screen(ctx, &screen_addr);
+            }
+            Peer::Robot(robot_addr) if robot_addr == self.addr_previous && self.leaving => {
+                // El anterior ya se conectó al siguiente y no me va a mandar más tokens
+                println!(
+                    "[ROBOT {}] The previous Robot connected to Robot {}, leaving",
+                    self.id, self.next_id
+                );
+                // Los tokens que acabo de reenviar pueden estar todavía escribiéndose
+                ctx.run_later(EXIT_DELAY, |robot, _| robot.exit());
+            }
+            Peer::Robot(robot_addr) if robot_addr == self.addr_previous => {
+                println!("[ROBOT {}] The previous Robot is down", self.id);
+                // self.skip_dead_robot(ctx, "previous".to_string());
+                println!("[ROBOT {}] Wating for reconnection", self.id);
+            }
+            Peer::Robot(_) => {
+                println!("[ROBOT {}] The next Robot is down", self.id);
+                self.skip_dead_robot(ctx);
+            }
+        }
+    }
+
+    /// Forgets a Screen that left: cancels its pending Orders and reservations, and stops
+    /// writing to it. The consumed Flavours of its Orders are discarded.
+    fn remove_screen(&mut self, ctx: &mut Context<Self>, screen_addr: &str) {
+        if self.screens.remove(screen_addr).is_none() {
+            return;
+        }
+        self.screen_ids.remove(screen_addr);
+        let id = self.id;
+        self.orders.retain(|pending| {
+            if pending.order.ip != screen_addr {
+                return true;
+            }
+            println!(
+                "[ROBOT {}] Order Prep {} cancelled, its Screen disconnected",
+                id, pending.order.id
+            );
+            false
+        });
+        self.reservations
+            .retain(|reservation| reservation.screen != screen_addr);
+        self.persist_state();
+        println!("[ROBOT {}] Screen {} removed", self.id, screen_addr);
+        self.collect_tokens(ctx);
+    }
+
+    /// Handles a SystemShutdown message. The first time, it is forwarded to the Screens, that
+    /// drain and exit. The Robots that receive it from a Screen or the terminal circulate it
+    /// through the ring with their id as origin: only the one with the lowest id completes the
+    /// round, and it stops the ring once its Screens are gone. Then it circulates the message
+    /// again with `ring_stopped` so every Robot exits.
+    fn process_system_shutdown(
+        &mut self,
+        ctx: &mut Context<Self>,
+        peer: Peer,
+        message_str: String,
+    ) {
+        let shutdown: SystemShutdown = serde_json::from_str(&message_str)
+            .expect("[ERROR] Couldn't deserialize SystemShutdown");
+        if !self.stopping {
+            println!(
+                "[ROBOT {}] {} asked to shut down the system",
+                self.id, shutdown.id
+            );
+            self.stopping = true;
+            self.notify_screens_shutdown(ctx);
+        }
+
+        let Some(origin) = shutdown.origin.filter(|_| matches!(peer, Peer::Robot(_))) else {
+            // Viene de una Pantalla: hago circular el cierre por el anillo
+            self.circulate_shutdown(ctx);
+            return;
+        };
+        if shutdown.ring_stopped {
+            if origin != self.id {
+                self.send_message(ctx, message_str, self.next_robot.0.clone());
+            }
+            println!("[ROBOT {}] The ring is stopped, exiting", self.id);
+            ctx.run_later(EXIT_DELAY, |robot, _| robot.exit());
+        } else if origin == self.id {
+            println!(
+                "[ROBOT {}] Coordinating the shutdown, waiting for the Screens to leave",
+                self.id
+            );
+            self.collecting = Some(HashMap::new());
+            self.collect_tokens(ctx);
+        } else if self
+            .coordinator
+            .is_none_or(|coordinator| origin < coordinator)
+        {
+            self.coordinator = Some(origin);
+            self.send_message(ctx, message_str, self.next_robot.0.clone());
+        }
+    }
+
+    /// Sends a SystemShutdown message with this Robot as origin to the next Robot, unless it
+    /// already knows a Robot with a lower id did.
+    fn circulate_shutdown(&mut self, ctx: &mut Context<Self>) {
+        if self
+            .coordinator
+            .is_some_and(|coordinator| coordinator <= self.id)
+        {
+            return;
+        }
+        self.coordinator = Some(self.id);
+        let mut shutdown = SystemShutdown::new(
+            ROBOT_IP_PREFIX.to_string() + &self.id.to_string(),
+            self.id.to_string(),
+        );
+        shutdown.origin = Some(self.id);
+        let shutdown_str =
+            serde_json::to_string(&shutdown).expect("[ERROR] Couldn't serialize SystemShutdown");
+        self.send_message(ctx, shutdown_str, self.next_robot.0.clone());
+    }
+
+    /// Forwards the system shutdown to every Screen, so they drain and exit.
+    fn notify_screens_shutdown(&mut self, ctx: &mut Context<Self>) {
+        let shutdown = SystemShutdown::new(
+            ROBOT_IP_PREFIX.to_string() + &self.id.to_string(),
+            self.id.to_string(),
+        );
+        let shutdown_str =
+            serde_json::to_string(&shutdown).expect("[ERROR] Couldn't serialize SystemShutdown");
+        let screens: Vec<_> = self
+            .screens
+            .values()
+            .map(|screen| screen.0.clone())
+            .collect();
+        for screen in screens {
+            self.send_message(ctx, shutdown_str.clone(), screen);
         }
-        //self.screens.remove(&disconnect.ip);
-        //println!("[EXIT] {} has disconnected", disconnect.id);
+    }
+
+    /// If this Robot coordinates the shutdown and every Screen left, it starts keeping the tokens
+    /// that arrive instead of passing them on. If some token doesn't arrive in
+    /// `RING_STOP_TIMEOUT`, the ring is stopped with the last copy seen of it.
+    fn collect_tokens(&mut self, ctx: &mut Context<Self>) {
+        if self.collecting.is_none() || !self.screens.is_empty() {
+            return;
+        }
+        println!("[ROBOT {}] Every Screen left, stopping the ring", self.id);
+        ctx.run_later(RING_STOP_TIMEOUT, |robot, ctx| {
+            if robot.collecting.is_some() {
+                println!(
+                    "[ROBOT {}] Some tokens didn't arrive, stopping anyway",
+                    robot.id
+                );
+                robot.stop_ring(ctx);
+            }
+        });
+    }
+
+    /// Writes the final inventory with the collected tokens and tells every Robot to exit.
+    fn stop_ring(&mut self, ctx: &mut Context<Self>) {
+        let Some(mut collected) = self.collecting.take() else {
+            return;
+        };
+        for (name, flavour) in &self.last_seen {
+            collected
+                .entry(name.to_string())
+                .or_insert_with(|| flavour.clone());
+        }
+        let inventory = FinalInventory::new(self.id, collected.into_values().collect());
+        match inventory.write_to_file() {
+            Ok(file_name) => println!(
+                "[ROBOT {}] Final inventory written to {}",
+                self.id, file_name
+            ),
+            Err(_) => println!("[ERROR] Couldn't write the final inventory to disk"),
+        }
+
+        let mut shutdown = SystemShutdown::new(
+            ROBOT_IP_PREFIX.to_string() + &self.id.to_string(),
+            self.id.to_string(),
+        );
+        shutdown.origin = Some(self.id);
+        shutdown.ring_stopped = true;
+        let shutdown_str =
+            serde_json::to_string(&shutdown).expect("[ERROR] Couldn't serialize SystemShutdown");
+        self.send_message(ctx, shutdown_str, self.next_robot.0.clone());
     }
 
     /// Connects to the next available Robot and updates its connections.
@@ -238,19 +969,93 @@ impl Robot {
         } else {
             self.id + 2
         };
+        self.connect_next(ctx, id_robot, None);
+    }
+
+    /// Connects to the given Robot and makes it the next one in the ring. If it is down or
+    /// refuses the connection, the Robot after it is tried. Once connected, a Disconnect is sent
+    /// through `replaced`, the connection with the old next Robot, if any.
+    /// No other message is handled until then, so none is sent through the old connection.
+    fn connect_next(
+        &mut self,
+        ctx: &mut Context<Self>,
+        id_robot: usize,
+        replaced: Option<Connection>,
+    ) {
+        connect_next_robot(self.id, id_robot)
+            .into_actor(self)
+            .map(move |result, robot, ctx| match result {
+                Ok(new_robot) => robot.set_next(ctx, id_robot, new_robot, replaced),
+                Err(e) => {
+                    println!(
+                        "[ROBOT {}] Couldn't connect to Robot {}: {}",
+                        robot.id, id_robot, e
+                    );
+                    let next = (id_robot + 1) % ROBOT_COUNT;
+                    if next == robot.id {
+                        println!("[ROBOT {}] No other Robot is reachable", robot.id);
+                        return;
+                    }
+                    robot.connect_next(ctx, next, replaced);
+                }
+            })
+            .wait(ctx);
+    }
 
-        let new_robot_std =
-            std::net::TcpStream::connect(ROBOT_IP_PREFIX.to_string() + &*(id_robot).to_string())
-                .unwrap();
-        let new_robot = TcpStream::from_std(new_robot_std).unwrap();
+    /// Makes the connection the next one in the ring.
+    fn set_next(
+        &mut self,
+        ctx: &mut Context<Self>,
+        id_robot: usize,
+        new_robot: FramedStream<TcpStream>,
+        replaced: Option<Connection>,
+    ) {
         let local_ip_new = new_robot
+            .get_ref()
             .local_addr()
             .expect("[ERROR] Couldn't get local IP address");
-        let (read_new, write_half_new) = split(new_robot);
-        ctx.add_stream(LinesStream::new(BufReader::new(read_new).lines()));
-        let write_new = Arc::new(Mutex::new(write_half_new));
+        let addr_new = new_robot
+            .get_ref()
+            .peer_addr()
+            .expect("[ERROR] Couldn't get the address of the next Robot");
+        let (read_new, write_new) = new_robot.split();
+        ctx.add_stream(peer_stream(Peer::Robot(addr_new), read_new));
+        let write_new = Arc::new(Mutex::new(write_new));
         self.next_robot = (write_new, local_ip_new);
+        self.next_id = id_robot;
         println!("[ROBOT {}] Connected to Robot {}", self.id, id_robot);
+
+        if let Some((old_next, old_local_ip)) = replaced {
+            let disconnect = Disconnect::new(old_local_ip.to_string(), self.id.to_string());
+            let msg =
+                serde_json::to_string(&disconnect).expect("[ERROR] Couldn't serialize Disconnect");
+            self.send_message(ctx, msg, old_next);
+        }
+    }
+
+    /// Handles a Leave message from the next Robot.
+    /// Connects to the Robot after it, and sends a Disconnect through the old connection so the
+    /// leaving Robot knows no more tokens will arrive from this Robot. The stock the leaving Robot
+    /// had to give back goes to the tokens when they pass through this Robot.
+    fn process_leave(&mut self, ctx: &mut Context<Self>, message_str: String) {
+        let leave: Leave =
+            serde_json::from_str(&message_str).expect("[ERROR] Couldn't deserialize Leave");
+        println!(
+            "[ROBOT {}] Robot {} is leaving the ring, connecting to Robot {}",
+            self.id, leave.id, leave.successor
+        );
+        for (flavour, amount) in leave.returns {
+            *self.returns.entry(flavour).or_insert(0.0) += amount;
+        }
+        let old_next = self.next_robot.clone();
+        self.connect_next(ctx, leave.successor, Some(old_next));
+    }
+
+    /// Lets main know the Robot can exit.
+    fn exit(&mut self) {
+        if let Some(exit) = self.exit.take() {
+            let _ = exit.send(());
+        }
     }
 }
 
@@ -258,7 +1063,7 @@ impl Actor for Robot {
     type Context = Context<Self>;
 }
 
-/// Shutdown message for Robots.
+/// Shutdown message for Robots. Main is notified once the Robot left the ring.
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Shutdown();
@@ -267,29 +1072,220 @@ impl Handler<Shutdown> for Robot {
     type Result = ();
 
     /// Handles the Shutdown message.
-    /// Notifies the previous and next Robots that it is disconnecting with Disconnect messages.
+    /// Returns its pending Orders to their Screens as ROBOT_OCCUPIED so they are retried with
+    /// other Robots, notifies the Screens with a Disconnect and asks the previous Robot to connect
+    /// to the next one with a Leave message, which hands over the stock those Orders had consumed. Tokens keep being forwarded until the previous Robot
+    /// answers, or until `LEAVE_TIMEOUT`.
     fn handle(&mut self, _msg: Shutdown, ctx: &mut Context<Self>) -> Self::Result {
-        println!("[ROBOT {}] Shutting down, disconnecting", self.id);
+        if self.leaving {
+            return;
+        }
+        println!("[ROBOT {}] Shutting down, leaving the ring", self.id);
+        self.leaving = true;
+
+        for pending in std::mem::take(&mut self.orders) {
+            println!(
+                "[ROBOT {}] Returning Order Prep {} to its Screen",
+                self.id, pending.order.id
+            );
+            for (flavour, amount) in pending.consumed() {
+                *self.returns.entry(flavour).or_insert(0.0) += amount;
+            }
+            self.send_order_prep(ctx, pending.order, ROBOT_OCCUPIED as usize);
+        }
+        self.reservations.clear();
 
-        println!("[ROBOT {}] Sending Disconnect to previous Robot", self.id);
-        let disconnect_previous =
-            Disconnect::new(self.previous_robot.1.to_string(), self.id.to_string());
-        let msg_previous = serde_json::to_string(&disconnect_previous)
-            .expect("[ERROR] Couldn't serialize Disconnect");
+        let robot_ip = ROBOT_IP_PREFIX.to_string() + &self.id.to_string();
+        let disconnect = Disconnect::new(robot_ip.to_string(), self.id.to_string());
+        let msg_screen =
+            serde_json::to_string(&disconnect).expect("[ERROR] Couldn't serialize Disconnect");
+        let screens: Vec<_> = self
+            .screens
+            .values()
+            .map(|screen| screen.0.clone())
+            .collect();
+        for screen in screens {
+            self.send_message(ctx, msg_screen.clone(), screen);
+        }
+
+        println!(
+            "[ROBOT {}] Sending Leave to previous Robot, next is Robot {}",
+            self.id, self.next_id
+        );
+        // El anterior devuelve a los tokens lo que consumieron las Orders que no se terminaron
+        let leave = Leave {
+            returns: std::mem::take(&mut self.returns),
+            ..Leave::new(robot_ip, self.id, self.next_id)
+        };
+        self.persist_state();
+        let msg_previous = serde_json::to_string(&leave).expect("[ERROR] Couldn't serialize Leave");
         self.send_message(ctx, msg_previous, self.previous_robot.0.clone());
 
-        println!("[ROBOT {}] Sending Disconnect to next Robot", self.id);
-        let disconnect_next = Disconnect::new(self.next_robot.1.to_string(), self.id.to_string());
-        let msg_next =
-            serde_json::to_string(&disconnect_next).expect("[ERROR] Couldn't serialize Disconnect");
-        self.send_message(ctx, msg_next, self.next_robot.0.clone());
+        let robot_address = ctx.address();
+        wrap_future::<_, Self>(async move {
+            sleep(LEAVE_TIMEOUT).await;
+            let _ = robot_address.send(LeaveDeadline()).await;
+        })
+        .spawn(ctx);
+    }
+}
+
+/// LeaveDeadline message for Robots.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct LeaveDeadline();
+
+impl Handler<LeaveDeadline> for Robot {
+    type Result = ();
+
+    /// Handles the LeaveDeadline message.
+    /// Exits even if the previous Robot didn't answer the Leave message.
+    fn handle(&mut self, _msg: LeaveDeadline, ctx: &mut Context<Self>) {
+        if self.exit.is_some() {
+            println!(
+                "[ROBOT {}] The previous Robot didn't answer the Leave, exiting anyway",
+                self.id
+            );
+        }
+        ctx.run_later(EXIT_DELAY, |robot, _| robot.exit());
+    }
+}
+
+impl Handler<Refill> for Robot {
+    type Result = ();
+
+    /// Handles the Refill message.
+    /// The refill is kept, and persisted, until the token of the Flavour passes through this
+    /// Robot.
+    fn handle(&mut self, msg: Refill, _ctx: &mut Context<Self>) {
+        if !FLAVOURS.contains(&msg.flavour.as_str()) {
+            println!(
+                "[ROBOT {}] Unknown Flavour {}, refill ignored",
+                self.id, msg.flavour
+            );
+            return;
+        }
+        println!(
+            "[ROBOT {}] Refill of {} for Flavour {} waiting for its token",
+            self.id, msg.amount, msg.flavour
+        );
+        *self.pending_refills.entry(msg.flavour).or_insert(0.0) += msg.amount;
+        self.persist_state();
+    }
+}
+
+/// StartSystemShutdown message for Robots.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct StartSystemShutdown();
+
+impl Handler<StartSystemShutdown> for Robot {
+    type Result = ();
+
+    /// Handles the StartSystemShutdown message.
+    /// Starts the shutdown of the whole system, asked through the Robot's terminal.
+    fn handle(&mut self, _msg: StartSystemShutdown, ctx: &mut Context<Self>) {
+        if self.stopping {
+            println!("[ROBOT {}] The system is already shutting down", self.id);
+            return;
+        }
+        println!("[ROBOT {}] Shutting down the system", self.id);
+        self.stopping = true;
+        self.notify_screens_shutdown(ctx);
+        self.circulate_shutdown(ctx);
+    }
+}
+
+/// QueryInventory message for Robots.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct QueryInventory();
+
+impl Handler<QueryInventory> for Robot {
+    type Result = ();
+
+    /// Handles the QueryInventory message.
+    /// Starts an Inventory Query round asked through the Robot's terminal.
+    fn handle(&mut self, _msg: QueryInventory, ctx: &mut Context<Self>) {
+        println!("[ROBOT {}] Starting Inventory Query", self.id);
+        let mut query = InventoryQuery::new(String::new(), self.id);
+        query.origin = Some(self.id);
+        self.forward_inventory_query(ctx, query);
+    }
+}
+
+/// StartSnapshot message for Robots.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct StartSnapshot();
+
+impl Handler<StartSnapshot> for Robot {
+    type Result = ();
+
+    /// Handles the StartSnapshot message.
+    /// Records the local state, starts recording the incoming channel and sends the marker
+    /// to the next Robot.
+    fn handle(&mut self, _msg: StartSnapshot, ctx: &mut Context<Self>) {
+        if self.snapshot.is_some() {
+            println!("[ROBOT {}] A snapshot is already in progress", self.id);
+            return;
+        }
+        println!("[ROBOT {}] Starting snapshot of the ring", self.id);
+        let mut marker = SnapshotMarker::new(self.id);
+        marker.robots.push(self.record_state());
+        self.snapshot = Some(SnapshotRecording {
+            channel_tokens: Vec::new(),
+        });
+        self.send_snapshot_marker(ctx, &marker);
+    }
+}
+
+/// RestoreState message for Robots.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RestoreState();
+
+impl Handler<RestoreState> for Robot {
+    type Result = ();
+
+    /// Handles the RestoreState message.
+    /// Reloads the state persisted by a previous run of this Robot. The pending Orders are
+    /// resumed once their Screens, recognized by the id they introduce themselves with, are
+    /// connected again. The ones whose Screen doesn't reconnect in time are cancelled, and what
+    /// they consumed goes back to the tokens.
+    fn handle(&mut self, _msg: RestoreState, ctx: &mut Context<Self>) {
+        let Some(state) = RobotState::load(self.id) else {
+            return;
+        };
+        println!("[ROBOT {}] Restoring persisted state", self.id);
+        self.consumed = state.consumed;
+        self.refilled = state.refilled;
+        self.last_seen = state.last_seen;
+        self.returns = state.returns;
+        self.pending_refills = state.pending_refills;
 
-        // TODO: envío disconnect a las Screens
-        /*for screen in self.screens.values() {
-            let disconnect_screen = Disconnect::new(screen.1.to_string(), self.id.to_string());
-            let msg_screen = serde_json::to_string(&disconnect_screen).expect("[ERROR] Couldn't serialize Disconnect");
-            self.send_message(ctx, msg_screen, screen.0.clone());
-        }*/
+        for pending in state.orders {
+            self.clock.witness(pending.order.stamp);
+            println!(
+                "[ROBOT {}] Order {} waits for Screen {} to reconnect",
+                self.id, pending.order.id, pending.screen
+            );
+            self.orphaned.push(pending);
+        }
+        // Las Pantallas que se conectaron mientras se armaba el anillo ya están
+        let connected: Vec<_> = self
+            .screen_ids
+            .iter()
+            .map(|(addr, screen_id)| (screen_id.to_string(), addr.to_string()))
+            .collect();
+        for (screen_id, addr) in connected {
+            self.resume_orders(&screen_id, &addr);
+        }
+        if !self.orphaned.is_empty() {
+            ctx.run_later(SCREEN_RECONNECT_TIMEOUT, |robot, _ctx| {
+                robot.abandon_orphaned()
+            });
+        }
     }
 }
 
@@ -299,9 +1295,9 @@ impl Handler<Shutdown> for Robot {
 pub struct RobotReconnect(
     pub  (
         SocketAddr,
-        WriteHalf<TcpStream>,
+        FrameWriter<WriteHalf<TcpStream>>,
         SocketAddr,
-        Lines<BufReader<ReadHalf<TcpStream>>>,
+        FrameReader<ReadHalf<TcpStream>>,
     ),
 );
 
@@ -316,13 +1312,51 @@ impl Handler<RobotReconnect> for Robot {
             self.id
         );
 
-        ctx.add_stream(LinesStream::new(msg.0 .3));
+        ctx.add_stream(peer_stream(Peer::Robot(msg.0 .0), msg.0 .3));
         let write = Arc::new(Mutex::new(msg.0 .1));
         self.addr_previous = msg.0 .0;
         self.previous_robot = (write, msg.0 .2);
     }
 }
 
+/// ScreenConnected message for Robots.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ScreenConnected(pub (SocketAddr, FramedStream<TcpStream>, String));
+
+impl Handler<ScreenConnected> for Robot {
+    type Result = ();
+
+    /// Handles the ScreenConnected message.
+    /// When a Screen connects after the ring started, or reconnects, it adds the new connection
+    /// to the Robot. A Robot that is leaving or stopping doesn't take new Screens.
+    fn handle(&mut self, msg: ScreenConnected, ctx: &mut Context<Self>) {
+        let (addr, stream, screen_id) = msg.0;
+        if self.leaving || self.stopping {
+            println!(
+                "[ROBOT {}] Screen {} refused, the Robot is shutting down",
+                self.id, addr
+            );
+            return;
+        }
+        let local_ip = stream
+            .get_ref()
+            .local_addr()
+            .expect("[ERROR] Couldn't get local IP address");
+        let (read, write) = stream.split();
+        ctx.add_stream(peer_stream(Peer::Screen(addr.to_string()), read));
+        let write = Arc::new(Mutex::new(write));
+        self.screens.insert(addr.to_string(), (write, local_ip));
+        self.screen_ids
+            .insert(addr.to_string(), screen_id.to_string());
+        println!(
+            "[ROBOT {}] Screen {} added to my connections",
+            self.id, addr
+        );
+        self.resume_orders(&screen_id, &addr.to_string());
+    }
+}
+
 /// RobotStart message for Robots.
 #[derive(Message)]
 #[rtype(result = "()")]
@@ -336,31 +1370,53 @@ impl Handler<RobotStart> for Robot {
     fn handle(&mut self, _msg: RobotStart, ctx: &mut Context<Self>) {
         println!("[ROBOT {}] Building flavours...", self.id);
 
-        let vainilla = Flavour::new("Vainilla".to_string(), 10.0);
+        let vainilla = Flavour::new("Vainilla".to_string(), INITIAL_FLAVOUR_AMOUNT);
         let vainilla_str =
             serde_json::to_string(&vainilla).expect("[ERROR] Couldn't serialize Vainilla");
         println!("{:?}", vainilla_str);
-        self.send_message(ctx, vainilla_str + "\n", self.next_robot.0.clone());
+        self.send_message(ctx, vainilla_str, self.next_robot.0.clone());
 
-        let ddl = Flavour::new("Dulce de leche".to_string(), 10.0);
+        let ddl = Flavour::new("Dulce de leche".to_string(), INITIAL_FLAVOUR_AMOUNT);
         let ddl_str = serde_json::to_string(&ddl).expect("[ERROR] Couldn't serialize Vainilla");
         println!("{:?}", ddl_str);
-        self.send_message(ctx, ddl_str + "\n", self.next_robot.0.clone());
+        self.send_message(ctx, ddl_str, self.next_robot.0.clone());
 
-        let tramontana = Flavour::new("Tramontana".to_string(), 10.0);
+        let tramontana = Flavour::new("Tramontana".to_string(), INITIAL_FLAVOUR_AMOUNT);
         let tramontana_str =
             serde_json::to_string(&tramontana).expect("[ERROR] Couldn't serialize Vainilla");
         println!("{:?}", tramontana_str);
-        self.send_message(ctx, tramontana_str + "\n", self.next_robot.0.clone());
+        self.send_message(ctx, tramontana_str, self.next_robot.0.clone());
     }
 }
 
-impl StreamHandler<Result<String, std::io::Error>> for Robot {
+impl StreamHandler<PeerLine> for Robot {
     /// Handles socket messages from other Robots and Screens.
     /// Matches the message type to its corresponding processing function.
-    fn handle(&mut self, read: Result<String, std::io::Error>, ctx: &mut Self::Context) {
+    /// If the connection of a Screen ended, the Screen is removed.
+    fn handle(&mut self, msg: PeerLine, ctx: &mut Self::Context) {
+        let PeerLine { peer, line } = msg;
+        let Some(read) = line else {
+            match peer {
+                Peer::Screen(screen_addr) => {
+                    println!("[ROBOT {}] Screen {} disconnected", self.id, screen_addr);
+                    self.remove_screen(ctx, &screen_addr);
+                }
+                Peer::Robot(robot_addr) => {
+                    println!("[ROBOT {}] Robot {} disconnected", self.id, robot_addr)
+                }
+            }
+            return;
+        };
         if let Ok(message_str) = read {
-            println!("\n[ROBOT {}] Received message", self.id);
+            match self.clock.receive(&message_str) {
+                Some(sent_at) => println!(
+                    "\n[ROBOT {}] Received message sent at {}, clock {}",
+                    self.id,
+                    sent_at,
+                    self.clock.now()
+                ),
+                None => println!("\n[ROBOT {}] Received message", self.id),
+            }
 
             // Sleep para que la ejecución sea legible
             thread::sleep(Duration::from_secs(2));
@@ -385,29 +1441,18 @@ impl StreamHandler<Result<String, std::io::Error>> for Robot {
                 }
             }
 
-            match message_str.to_string() {
-                message_str if message_str.contains("\"OrderRequest\"") => {
-                    self.process_order_request(ctx, message_str);
-                }
-                message_str if message_str.contains("\"OrderPrep\"") => {
-                    self.process_order_prep(ctx, message_str);
-                }
-                message_str if message_str.contains("\"Disconnect\"") => {
-                    self.process_disconnect(ctx, message_str);
-                }
-                message_str if message_str.contains("ACKToken") => {
-                    self.process_ack(message_str);
-                }
-                message_str if message_str.contains("\"Vainilla\"") => {
-                    self.process_flavour(ctx, message_str);
-                }
-                message_str if message_str.contains("\"Dulce de leche\"") => {
-                    self.process_flavour(ctx, message_str);
-                }
-                message_str if message_str.contains("\"Tramontana\"") => {
-                    self.process_flavour(ctx, message_str);
-                }
-                message_str => {
+            match message_kind(&message_str) {
+                MessageKind::OrderRequest => self.process_order_request(ctx, message_str),
+                MessageKind::OrderRelease => self.process_order_release(message_str),
+                MessageKind::OrderPrep => self.process_order_prep(ctx, message_str),
+                MessageKind::SystemShutdown => self.process_system_shutdown(ctx, peer, message_str),
+                MessageKind::Leave => self.process_leave(ctx, message_str),
+                MessageKind::Disconnect => self.process_disconnect(ctx, peer, message_str),
+                MessageKind::SnapshotMarker => self.process_snapshot_marker(ctx, message_str),
+                MessageKind::InventoryQuery => self.process_inventory_query(ctx, message_str),
+                MessageKind::FlavourAck => self.process_ack(message_str),
+                MessageKind::Flavour => self.process_flavour(ctx, message_str),
+                MessageKind::Unknown => {
                     println!(
                         "[ROBOT {}] The following unknown message was received:",
                         self.id
@@ -418,78 +1463,92 @@ impl StreamHandler<Result<String, std::io::Error>> for Robot {
         }
     }
 
-    /// A stream connected to this Robot has finished.
+    /// A stream connected to this Robot has finished. Its end marker was already handled, and
+    /// the Robot keeps running with the other connections.
     fn finished(&mut self, _ctx: &mut Self::Context) {
-        println!("[ROBOT {}] An actor disconnected", self.id);
-        // ctx.stop();
+        println!(
+            "[ROBOT {}] A connection was closed, {} Screen(s) still connected",
+            self.id,
+            self.screens.len()
+        );
     }
 }
 
 /// Conects the Robots in the ring.
 /// Robots with an even id connect first to the next Robot and then wait for an incoming connection from the previous Robot.
 /// Robots with an uneven id wait for an incoming connection from the previous Robot first and then connect to the next Robot.
+/// Screens that connect in the meantime are kept, and the listener is returned to accept the rest.
 pub async fn connect_robots(
     id: usize,
 ) -> (
-    (TcpStream, SocketAddr),
-    TcpStream,
-    HashMap<String, TcpStream>,
+    (FramedStream<TcpStream>, SocketAddr),
+    FramedStream<TcpStream>,
+    HashMap<String, (FramedStream<TcpStream>, String)>,
+    TcpListener,
 ) {
+    let next_id = (id + 1) % ROBOT_COUNT;
     let listener;
     let previous_robot;
     let next_robot;
-    if id % 2 == 0 {
-        if id == ROBOT_COUNT - 1 {
-            next_robot = TcpStream::connect(ROBOT_IP_PREFIX.to_string() + &*(0).to_string())
-                .await
-                .expect("[ERROR] Couldn't connect to the next Robot");
-            println!("[ROBOT {}] Next Robot connected, id {}", id, 0);
-        } else {
-            next_robot = TcpStream::connect(ROBOT_IP_PREFIX.to_string() + &*(id + 1).to_string())
-                .await
-                .expect("[ERROR] Couldn't connect to the next Robot");
-            println!("[ROBOT {}] Next Robot connected, id {}", id, id + 1);
-        }
-
+    let mut screens = HashMap::new();
+    if id.is_multiple_of(2) {
+        next_robot = connect_next_robot(id, next_id)
+            .await
+            .unwrap_or_else(|e| panic!("[ERROR] Couldn't connect to the next Robot: {}", e));
         listener = TcpListener::bind(ROBOT_IP_PREFIX.to_string() + &*id.to_string())
             .await
             .unwrap();
-        previous_robot = listener
-            .accept()
-            .await
-            .expect("[ERROR] Couldn't connect to the previous Robot");
-        println!("[ROBOT {}] Previous Robot connected", id);
+        previous_robot = accept_previous_robot(id, &listener, &mut screens).await;
     } else {
         listener = TcpListener::bind(ROBOT_IP_PREFIX.to_string() + &*id.to_string())
             .await
             .unwrap();
-        previous_robot = listener
-            .accept()
+        previous_robot = accept_previous_robot(id, &listener, &mut screens).await;
+        next_robot = connect_next_robot(id, next_id)
             .await
-            .expect("[ERROR] Couldn't connect to the previous Robot");
-        println!("[ROBOT {}] Previous Robot connected", id);
-
-        if id == ROBOT_COUNT - 1 {
-            next_robot = TcpStream::connect(ROBOT_IP_PREFIX.to_string() + &*(0).to_string())
-                .await
-                .expect("[ERROR] Couldn't connect to the next Robot");
-            println!("[ROBOT {}] Next Robot connected, id {}", id, 0);
-        } else {
-            next_robot = TcpStream::connect(ROBOT_IP_PREFIX.to_string() + &*(id + 1).to_string())
-                .await
-                .expect("[ERROR] Couldn't connect to the next Robot");
-            println!("[ROBOT {}] Next Robot connected, id {}", id, id + 1);
-        }
+            .unwrap_or_else(|e| panic!("[ERROR] Couldn't connect to the next Robot: {}", e));
     }
 
-    let mut screens = HashMap::new();
-    for _ in 0..SCREEN_COUNT {
+    (previous_robot, next_robot, screens, listener)
+}
+
+/// Connects to the next Robot and introduces itself with a Hello message. If the Robot refuses
+/// the Hello, the connection is dropped.
+async fn connect_next_robot(id: usize, next_id: usize) -> Result<FramedStream<TcpStream>, Error> {
+    let next_robot =
+        TcpStream::connect(ROBOT_IP_PREFIX.to_string() + &*next_id.to_string()).await?;
+    let mut next_robot = FramedStream::new(next_robot);
+    let next = introduce(&mut next_robot, ROLE_ROBOT, id.to_string()).await?;
+    println!(
+        "[ROBOT {}] Next Robot connected, id {}, protocol version {}",
+        id, next_id, next.version
+    );
+    Ok(next_robot)
+}
+
+/// Accepts connections until the previous Robot introduces itself. The Screens that connect
+/// before it are stored in `screens`, with the id they introduced themselves with.
+async fn accept_previous_robot(
+    id: usize,
+    listener: &TcpListener,
+    screens: &mut HashMap<String, (FramedStream<TcpStream>, String)>,
+) -> (FramedStream<TcpStream>, SocketAddr) {
+    loop {
         let (stream, addr) = listener
             .accept()
             .await
-            .expect("[ERROR] Error while connecting to Screen");
-        screens.insert(addr.to_string(), stream);
+            .expect("[ERROR] Couldn't connect to the previous Robot");
+        let mut stream = FramedStream::new(stream);
+        match greet(id, &mut stream, addr).await {
+            Some(peer) if peer.role == ROLE_ROBOT => {
+                println!("[ROBOT {}] Previous Robot connected", id);
+                return (stream, addr);
+            }
+            Some(peer) => {
+                println!("[ROBOT {}] Screen {} connected", id, peer.id);
+                screens.insert(addr.to_string(), (stream, peer.id));
+            }
+            None => {}
+        }
     }
-
-    (previous_robot, next_robot, screens)
 }
@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Error, Write};
+
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+
+use common::modules::flavour::{Flavour, INITIAL_FLAVOUR_AMOUNT};
+use common::modules::logical_clock::ClockStamp;
+
+use crate::pending_order::PendingOrder;
+
+// Margen para comparar cantidades de helado, que se dividen entre los gustos del pedido
+const STOCK_EPSILON: f64 = 1e-6;
+
+/// Local state of a Robot recorded for a snapshot.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RobotSnapshot {
+    pub id: usize,
+    pub orders: Vec<PendingOrder>,
+    pub consumed: HashMap<String, f64>,
+    pub refilled: HashMap<String, f64>,
+}
+
+/// Chandy-Lamport marker. It is sent through the ring behind the Flavour tokens, and every
+/// Robot adds its local state the first time it receives it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotMarker {
+    pub message: String, // "SnapshotMarker"
+    pub origin: usize,
+    pub robots: Vec<RobotSnapshot>,
+    #[serde(default)]
+    pub clock: ClockStamp,
+}
+
+impl SnapshotMarker {
+    /// SnapshotMarker constructor.
+    pub fn new(origin: usize) -> Self {
+        SnapshotMarker {
+            message: "SnapshotMarker".to_string(),
+            origin,
+            robots: Vec::new(),
+            clock: ClockStamp::default(),
+        }
+    }
+}
+
+/// Snapshot being recorded by the Robot that started it.
+/// Every token received before the marker comes back was in flight on the channel
+/// from the previous Robot.
+pub struct SnapshotRecording {
+    pub channel_tokens: Vec<Flavour>,
+}
+
+/// State of a channel of the ring: the tokens that were in flight from one Robot to the next.
+#[derive(Serialize, Debug)]
+pub struct ChannelSnapshot {
+    pub from: usize,
+    pub to: usize,
+    pub tokens: Vec<Flavour>,
+}
+
+/// Global snapshot of the ring, with the stock totals to audit it.
+#[derive(Serialize, Debug)]
+pub struct RingSnapshot {
+    pub origin: usize,
+    pub taken_at: String,
+    pub robots: Vec<RobotSnapshot>,
+    pub channels: Vec<ChannelSnapshot>,
+    pub initial_stock: HashMap<String, f64>,
+    pub stock_in_flight: HashMap<String, f64>,
+    pub consumed: HashMap<String, f64>,
+    pub refilled: HashMap<String, f64>,
+    /// True if, for every flavour, stock in flight plus consumed stock equals initial plus refilled stock.
+    pub consistent: bool,
+}
+
+impl RingSnapshot {
+    /// Combines the states collected by the marker with the tokens recorded on the channel
+    /// that goes into the origin Robot. The other channels are empty, because every Robot
+    /// records its state when the marker arrives through its only incoming channel.
+    pub fn new(marker: SnapshotMarker, channel_tokens: Vec<Flavour>) -> Self {
+        let mut channels = Vec::new();
+        for pair in marker.robots.windows(2) {
+            channels.push(ChannelSnapshot {
+                from: pair[0].id,
+                to: pair[1].id,
+                tokens: Vec::new(),
+            });
+        }
+        if let Some(last) = marker.robots.last() {
+            channels.push(ChannelSnapshot {
+                from: last.id,
+                to: marker.origin,
+                tokens: channel_tokens,
+            });
+        }
+
+        let mut initial_stock = HashMap::new();
+        let mut stock_in_flight = HashMap::new();
+        for token in channels.iter().flat_map(|channel| &channel.tokens) {
+            initial_stock.insert(token.name.to_string(), INITIAL_FLAVOUR_AMOUNT);
+            *stock_in_flight.entry(token.name.to_string()).or_insert(0.0) += token.amount;
+        }
+        let mut consumed = HashMap::new();
+        let mut refilled = HashMap::new();
+        for robot in &marker.robots {
+            for (name, amount) in &robot.consumed {
+                *consumed.entry(name.to_string()).or_insert(0.0) += amount;
+            }
+            for (name, amount) in &robot.refilled {
+                *refilled.entry(name.to_string()).or_insert(0.0) += amount;
+            }
+        }
+
+        let consistent = !stock_in_flight.is_empty()
+            && consumed
+                .keys()
+                .chain(refilled.keys())
+                .all(|name| stock_in_flight.contains_key(name))
+            && stock_in_flight.iter().all(|(name, in_flight)| {
+                let used = consumed.get(name).unwrap_or(&0.0);
+                let added = refilled.get(name).unwrap_or(&0.0);
+                (in_flight + used - added - INITIAL_FLAVOUR_AMOUNT).abs() < STOCK_EPSILON
+            });
+
+        RingSnapshot {
+            origin: marker.origin,
+            taken_at: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            robots: marker.robots,
+            channels,
+            initial_stock,
+            stock_in_flight,
+            consumed,
+            refilled,
+            consistent,
+        }
+    }
+
+    /// Writes the snapshot to disk and returns the name of the file.
+    pub fn write_to_file(&self) -> Result<String, Error> {
+        let file_name = format!(
+            "snapshot_{}_{}.json",
+            self.origin,
+            Local::now().format("%Y%m%d%H%M%S")
+        );
+        let mut file = File::create(&file_name)?;
+        let snapshot_str = serde_json::to_string_pretty(self)?;
+        writeln!(file, "{}", snapshot_str)?;
+
+        Ok(file_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn robot(id: usize, consumed: f64, refilled: f64) -> RobotSnapshot {
+        RobotSnapshot {
+            id,
+            orders: Vec::new(),
+            consumed: HashMap::from([("Vainilla".to_string(), consumed)]),
+            refilled: HashMap::from([("Vainilla".to_string(), refilled)]),
+        }
+    }
+
+    fn marker(robots: Vec<RobotSnapshot>) -> SnapshotMarker {
+        let mut marker = SnapshotMarker::new(0);
+        marker.robots = robots;
+        marker
+    }
+
+    #[test]
+    fn stock_that_adds_up_is_consistent() {
+        let marker = marker(vec![
+            robot(0, 3.0, 1.0),
+            robot(1, 1.5, 0.0),
+            robot(2, 0.0, 0.5),
+        ]);
+        let tokens = vec![Flavour::new("Vainilla".to_string(), 7.0)];
+        let snapshot = RingSnapshot::new(marker, tokens);
+
+        assert!(snapshot.consistent);
+        let channels: Vec<(usize, usize, usize)> = snapshot
+            .channels
+            .iter()
+            .map(|channel| (channel.from, channel.to, channel.tokens.len()))
+            .collect();
+        assert_eq!(channels, vec![(0, 1, 0), (1, 2, 0), (2, 0, 1)]);
+        assert_eq!(snapshot.stock_in_flight["Vainilla"], 7.0);
+        assert_eq!(snapshot.consumed["Vainilla"], 4.5);
+        assert_eq!(snapshot.refilled["Vainilla"], 1.5);
+        assert_eq!(snapshot.initial_stock["Vainilla"], INITIAL_FLAVOUR_AMOUNT);
+    }
+
+    #[test]
+    fn stock_that_does_not_add_up_is_inconsistent() {
+        let marker = marker(vec![robot(0, 3.0, 1.0)]);
+        let tokens = vec![Flavour::new("Vainilla".to_string(), 9.0)];
+        assert!(!RingSnapshot::new(marker, tokens).consistent);
+    }
+
+    #[test]
+    fn consumed_flavour_without_a_token_is_inconsistent() {
+        let marker = marker(vec![robot(0, 0.0, 0.0)]);
+        let tokens = vec![Flavour::new("Menta".to_string(), INITIAL_FLAVOUR_AMOUNT)];
+        assert!(!RingSnapshot::new(marker, tokens).consistent);
+    }
+
+    #[test]
+    fn snapshot_without_tokens_is_inconsistent() {
+        let marker = marker(vec![robot(0, 0.0, 0.0)]);
+        assert!(!RingSnapshot::new(marker, Vec::new()).consistent);
+    }
+}
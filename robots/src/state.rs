@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Error, Write};
+
+use serde::{Deserialize, Serialize};
+
+use common::modules::flavour::Flavour;
+
+use crate::pending_order::PendingOrder;
+
+/// State of a Robot that is persisted to disk, to be recovered if the Robot restarts.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RobotState {
+    pub orders: Vec<PendingOrder>,
+    pub consumed: HashMap<String, f64>,
+    pub refilled: HashMap<String, f64>,
+    pub last_seen: HashMap<String, Flavour>,
+    // Gustos de Orders abandonadas que todavía no se devolvieron a su token
+    #[serde(default)]
+    pub returns: HashMap<String, f64>,
+    // Reposiciones ingresadas que todavía no se sumaron a su token
+    #[serde(default)]
+    pub pending_refills: HashMap<String, f64>,
+}
+
+impl RobotState {
+    fn file_name(id: usize) -> String {
+        format!("robot_{}_state.json", id)
+    }
+
+    /// Writes the state to disk. It is written to a temporary file first and then renamed,
+    /// so a crash while writing doesn't leave a corrupted state behind.
+    pub fn save(&self, id: usize) -> Result<(), Error> {
+        let file_name = RobotState::file_name(id);
+        let tmp_name = file_name.clone() + ".tmp";
+        let mut file = File::create(&tmp_name)?;
+        let state_str = serde_json::to_string(self)?;
+        writeln!(file, "{}", state_str)?;
+        file.sync_all()?;
+        fs::rename(tmp_name, file_name)
+    }
+
+    /// Reads the state saved by the Robot with the given id, if there is one.
+    pub fn load(id: usize) -> Option<Self> {
+        let state_str = fs::read_to_string(RobotState::file_name(id)).ok()?;
+        serde_json::from_str(&state_str).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::modules::order_prep::OrderPrep;
+    use common::modules::priority::Priority;
+
+    use super::*;
+
+    // Id fuera del rango de los Robots, para no pisar el estado de uno que esté corriendo
+    const TEST_ID: usize = 9032;
+
+    #[test]
+    fn saved_state_is_loaded_back() {
+        let order = OrderPrep {
+            message: "OrderPrep".to_string(),
+            ip: "127.0.0.1:40000".to_string(),
+            id: 3,
+            size: 200,
+            flavours: vec!["Menta".to_string()],
+            fail_flag: 0,
+            priority: Priority::Express,
+            stamp: 5,
+            clock: Default::default(),
+        };
+        let state = RobotState {
+            orders: vec![PendingOrder::new(order, "1".to_string())],
+            consumed: HashMap::from([("Menta".to_string(), 2.5)]),
+            refilled: HashMap::from([("Menta".to_string(), 1.0)]),
+            last_seen: HashMap::from([(
+                "Menta".to_string(),
+                Flavour::new("Menta".to_string(), 7.5),
+            )]),
+            returns: HashMap::from([("Chocolate".to_string(), 0.5)]),
+            pending_refills: HashMap::from([("Frutilla".to_string(), 4.0)]),
+        };
+        state.save(TEST_ID).unwrap();
+        let loaded = RobotState::load(TEST_ID);
+        fs::remove_file(RobotState::file_name(TEST_ID)).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.orders.len(), 1);
+        assert!(loaded.orders[0].is("127.0.0.1:40000", 3));
+        assert_eq!(loaded.orders[0].screen, "1");
+        assert_eq!(loaded.orders[0].order.priority, Priority::Express);
+        assert_eq!(loaded.consumed, state.consumed);
+        assert_eq!(loaded.refilled, state.refilled);
+        assert_eq!(loaded.last_seen["Menta"].amount, 7.5);
+        assert_eq!(loaded.returns, state.returns);
+        assert_eq!(loaded.pending_refills, state.pending_refills);
+    }
+
+    #[test]
+    fn missing_state_is_not_loaded() {
+        assert!(RobotState::load(TEST_ID + 1).is_none());
+    }
+
+    #[test]
+    fn state_saved_before_returns_and_refills_is_loaded() {
+        let state_str = r#"{"orders":[],"consumed":{"Menta":1.0},"refilled":{},"last_seen":{}}"#;
+        let state: RobotState = serde_json::from_str(state_str).unwrap();
+        assert_eq!(state.consumed["Menta"], 1.0);
+        assert!(state.returns.is_empty());
+        assert!(state.pending_refills.is_empty());
+    }
+}
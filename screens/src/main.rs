@@ -1,49 +1,73 @@
 use actix::prelude::*;
-use tokio::io::AsyncWriteExt; // For shutdown()
+use common::modules::auth::{rejections, shared_key};
+use common::modules::tls::connector;
+use tokio::sync::{mpsc, oneshot};
 
 mod modules;
 use modules::{
     connections::{connect_with_gateway, connect_with_robots},
     init::create_screen,
+    order_queue::LOOKAHEAD,
+    order_source::{start_source, OrderSink},
     parser::parse_args,
     screen::{ReceiveOrder, Screen, Shutdown},
-    utils::{listen_user_input, perror},
+    utils::listen_user_input,
 };
 
 #[actix_rt::main]
 async fn main() {
-    let (screen_id, reader) = parse_args();
+    let (config, source) = parse_args();
+    let screen_id = config.id;
+    if shared_key().is_some() {
+        println!("Messages are signed with the shared key.");
+    }
+    if connector().is_some() {
+        println!("The connection to the gateway uses TLS.");
+    }
 
-    let mut gateway_stream = connect_with_gateway().await;
-
-    let robot_streams = match connect_with_robots().await {
-        Ok(robot_streams) => robot_streams,
-        Err(e) => {
-            gateway_stream
-                .shutdown()
-                .await
-                .expect("Unable to shutdown gateway_stream");
-            perror("", Some(e));
-            std::process::exit(1)
-        }
-    };
+    // Si el gateway o algún robot todavía no arrancó, se reintenta hasta que aparezca
+    let gateway_stream = connect_with_gateway(screen_id).await;
+    let (robot_streams, missing_robots) = connect_with_robots(screen_id).await;
 
     // Creo el actor Screen
-    let screen: Addr<Screen> = create_screen(screen_id, reader, gateway_stream, robot_streams);
+    let (orders_tx, orders_rx) = mpsc::channel(LOOKAHEAD);
+    let (exit_tx, mut exit_rx) = oneshot::channel();
+    let screen: Addr<Screen> = create_screen(
+        config,
+        orders_rx,
+        exit_tx,
+        gateway_stream,
+        robot_streams,
+        missing_robots,
+    );
+    let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+    start_source(
+        source,
+        OrderSink::new(screen.clone(), orders_tx),
+        commands_tx,
+    );
 
     println!(
         "\x1b[32m\nScreen {} is ready to receive orders.\x1b[0m",
         screen_id
     );
-    println!("Presiona 'q' para salir: \n");
+    println!("Presiona 'q' para salir, 'inventory' para consultar el stock o 'status [id]' para ver el estado de los pedidos: \n");
 
     // Arranco con la primer orden
     let _ = screen.send(ReceiveOrder()).await;
 
-    listen_user_input().await;
-
-    // Cierre ordenado
-    let _ = screen.send(Shutdown()).await;
-    // Para dar tiempo a que se envien los Disconnect
-    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    // La pantalla también se cierra si se apaga todo el sistema
+    tokio::select! {
+        _ = listen_user_input(&screen, commands_rx) => {
+            // Cierre ordenado: espero a que terminen los pedidos y a que robots y gateway respondan
+            let _ = screen.send(Shutdown()).await;
+            let _ = exit_rx.await;
+        }
+        _ = &mut exit_rx => {}
+    }
+    if shared_key().is_some() {
+        println!("[AUTH] Rejected messages: {}", rejections());
+    }
+    // La lectura de stdin ocupa un hilo bloqueante que el runtime esperaría al cerrarse
+    std::process::exit(0);
 }
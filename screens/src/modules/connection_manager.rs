@@ -0,0 +1,162 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use actix::Addr;
+use tokio::{io::ReadHalf, net::TcpStream};
+use tokio_stream::{Stream, StreamExt};
+
+use common::modules::{
+    constants::{GATEWAY_BACKUP_PORT, GATEWAY_PORT, ROBOT_0_PORT, ROBOT_COUNT},
+    framing::{FrameReader, FramedStream},
+    handshake::introduce,
+    hello::ROLE_SCREEN,
+    tls::Transport,
+};
+
+use crate::modules::screen::{PeerConnected, Screen};
+use crate::modules::timeouts::backoff;
+
+/// Highest epoch of a gateway term seen by the Screen. A gateway with a lower one is not the
+/// primary anymore, even if it still answers.
+static GATEWAY_EPOCH: AtomicU64 = AtomicU64::new(0);
+
+/// The other end of a connection of the Screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Peer {
+    Gateway,
+    // Dirección donde escucha el robot, la misma clave que en Screen::robots_write
+    Robot(SocketAddr),
+}
+
+impl Peer {
+    /// Addresses the Screen tries, in order, to reach the peer. The gateway has a backup that
+    /// takes over if the primary goes down.
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        match self {
+            Peer::Gateway => vec![
+                SocketAddr::from(([127, 0, 0, 1], GATEWAY_PORT)),
+                SocketAddr::from(([127, 0, 0, 1], GATEWAY_BACKUP_PORT)),
+            ],
+            Peer::Robot(addr) => vec![*addr],
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Gateway => write!(f, "gateway"),
+            Peer::Robot(addr) => write!(f, "robot at {}", addr),
+        }
+    }
+}
+
+/// A message read from a connection, tagged with the peer that sent it. `line` is None once the
+/// connection ended.
+pub struct PeerLine {
+    pub peer: Peer,
+    pub line: Option<Result<String, io::Error>>,
+}
+
+/// Every robot the Screen may connect to.
+pub fn robot_peers() -> Vec<Peer> {
+    (ROBOT_0_PORT as usize..(ROBOT_0_PORT as usize + ROBOT_COUNT))
+        .map(|port| Peer::Robot(SocketAddr::from(([127, 0, 0, 1], port as u16))))
+        .collect()
+}
+
+/// Tags every message read from a connection with its peer, and adds an end marker when the
+/// connection ends, so the Screen knows which peer it lost.
+pub fn peer_stream(
+    peer: Peer,
+    read_half: FrameReader<ReadHalf<Transport>>,
+) -> impl Stream<Item = PeerLine> {
+    let end = PeerLine { peer, line: None };
+    read_half
+        .map(move |line| PeerLine {
+            peer,
+            line: Some(line),
+        })
+        .chain(tokio_stream::once(end))
+}
+
+/// Connects to the first address of the peer that answers, and introduces the Screen with a
+/// Hello message, so the other end knows it is not a robot. Fails if the peer doesn't speak a
+/// protocol version the Screen understands. The connection to the gateway uses TLS if it is
+/// configured, and a gateway with an older epoch than one already seen is skipped.
+pub async fn connect(peer: Peer, screen_id: u8) -> io::Result<FramedStream<Transport>> {
+    let mut error = io::Error::new(io::ErrorKind::NotFound, "no address to connect to");
+    for addr in peer.addrs() {
+        match connect_to(peer, addr, screen_id).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
+
+async fn connect_to(
+    peer: Peer,
+    addr: SocketAddr,
+    screen_id: u8,
+) -> io::Result<FramedStream<Transport>> {
+    let stream = TcpStream::connect(addr).await?;
+    let mut stream = FramedStream::new(match peer {
+        Peer::Gateway => Transport::connect(stream).await?,
+        Peer::Robot(_) => Transport::from(stream),
+    });
+    let info = introduce(&mut stream, ROLE_SCREEN, screen_id.to_string()).await?;
+    if peer == Peer::Gateway {
+        let newest = GATEWAY_EPOCH.fetch_max(info.epoch, Ordering::SeqCst);
+        if info.epoch < newest {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!(
+                    "the gateway at {} has epoch {}, older than {}",
+                    addr, info.epoch, newest
+                ),
+            ));
+        }
+    }
+    println!(
+        "{} introduced itself as {} {}, protocol version {} (features: {}).",
+        peer,
+        info.role,
+        info.id,
+        info.version,
+        info.features.join(", ")
+    );
+    Ok(stream)
+}
+
+/// Keeps trying to connect to the peer until it answers, waiting longer after every failed
+/// attempt.
+pub async fn connect_with_retry(peer: Peer, screen_id: u8) -> FramedStream<Transport> {
+    let mut attempt = 0;
+    loop {
+        match connect(peer, screen_id).await {
+            Ok(stream) => return stream,
+            Err(e) => {
+                attempt += 1;
+                let delay = backoff(attempt);
+                println!(
+                    "Couldn't connect to {} ({}), retrying in {:.1} seconds.",
+                    peer,
+                    e,
+                    delay.as_secs_f64()
+                );
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+/// Reconnects to the peer in the background, and hands the new connection to the Screen.
+pub fn spawn_reconnect(screen: Addr<Screen>, peer: Peer, screen_id: u8) {
+    actix::spawn(async move {
+        let stream = connect_with_retry(peer, screen_id).await;
+        screen.do_send(PeerConnected { peer, stream });
+    });
+}
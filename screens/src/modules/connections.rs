@@ -1,52 +1,77 @@
-use std::error::Error;
-use std::net::SocketAddr;
+use common::modules::{framing::FramedStream, tls::Transport};
+use tokio::sync::mpsc;
 
-use tokio::net::TcpStream;
+use crate::modules::connection_manager::{connect, connect_with_retry, robot_peers, Peer};
 
-use common::modules::constants::{GATEWAY_PORT, ROBOT_0_PORT, ROBOT_COUNT};
-
-use crate::modules::utils::perror;
-
-/// Exits process if an error occurs when establishing a connection
-pub async fn connect_with_gateway() -> tokio::net::TcpStream {
+/// Waits until the gateway accepts the connection, retrying with backoff
+pub async fn connect_with_gateway(screen_id: u8) -> FramedStream<Transport> {
     println!("\x1b[32m\nConnecting with gateway:\x1b[0m");
-    let gateway_addr = SocketAddr::from(([127, 0, 0, 1], GATEWAY_PORT));
-
-    match TcpStream::connect(gateway_addr).await {
-        Ok(gateway_stream) => {
-            println!("Connected to gateway at {}", gateway_addr);
-            gateway_stream
-        }
-        Err(e) => {
-            perror("Couldn't connect to gateway", Some(Box::new(e)));
-            std::process::exit(1);
+    let gateway_stream = connect_with_retry(Peer::Gateway, screen_id).await;
+    if let Ok(addr) = gateway_stream.get_ref().peer_addr() {
+        if gateway_stream.get_ref().is_tls() {
+            println!("Connected to gateway at {} over TLS", addr);
+        } else {
+            println!("Connected to gateway at {}", addr);
         }
     }
+    gateway_stream
 }
 
-/// Attempts to connect with all robots. If all connections failed, returns an Error
-pub async fn connect_with_robots() -> Result<Vec<tokio::net::TcpStream>, Box<dyn Error>> {
-    let mut robots_addrs = Vec::new();
-    for port in ROBOT_0_PORT as usize..(ROBOT_0_PORT as usize + ROBOT_COUNT) {
-        robots_addrs.push(SocketAddr::from(([127, 0, 0, 1], port as u16)));
-    }
-
+/// Attempts to connect with all robots, and waits until at least one of them is up, retrying
+/// all of them at once.
+/// Returns the connections made and the robots that were down, to be retried in the background
+pub async fn connect_with_robots(
+    screen_id: u8,
+) -> (Vec<(Peer, FramedStream<Transport>)>, Vec<Peer>) {
     println!("\x1b[32m\nStarting connection with robots:\x1b[0m");
 
-    let mut robots_streams = Vec::<TcpStream>::new();
-    for addr in &robots_addrs {
-        match TcpStream::connect(addr).await {
+    let mut robots_streams = Vec::new();
+    let mut missing = Vec::new();
+    for peer in robot_peers() {
+        match connect(peer, screen_id).await {
             Ok(stream) => {
-                println!("Connected to robot at {}", addr);
-                robots_streams.push(stream);
+                println!("Connected to {}", peer);
+                robots_streams.push((peer, stream));
+            }
+            Err(e) => {
+                println!("Couldn't connect to {} ({})", peer, e);
+                missing.push(peer);
             }
-            Err(_) => println!("Couldn't connect to robot at {}", addr),
         }
     }
 
-    if robots_streams.is_empty() {
-        return Err("No robots connected to screen.".into());
+    // Sin robots no se puede preparar ningún pedido: reintento todos a la vez y sigo con el
+    // primero que aparezca, el resto queda para el reintento en segundo plano
+    if robots_streams.is_empty() && !missing.is_empty() {
+        let (connected_tx, mut connected_rx) = mpsc::unbounded_channel();
+        let retries: Vec<_> = missing
+            .iter()
+            .map(|&peer| {
+                let connected_tx = connected_tx.clone();
+                actix::spawn(async move {
+                    let stream = connect_with_retry(peer, screen_id).await;
+                    let _ = connected_tx.send((peer, stream));
+                })
+            })
+            .collect();
+        drop(connected_tx);
+
+        if let Some(first) = connected_rx.recv().await {
+            for retry in retries {
+                retry.abort();
+            }
+            // Los que conectaron junto con el primero también se usan
+            let mut connected = vec![first];
+            while let Ok(other) = connected_rx.try_recv() {
+                connected.push(other);
+            }
+            for (peer, stream) in connected {
+                println!("Connected to {}", peer);
+                robots_streams.push((peer, stream));
+                missing.retain(|missing_peer| *missing_peer != peer);
+            }
+        }
     }
 
-    Ok(robots_streams)
+    (robots_streams, missing)
 }
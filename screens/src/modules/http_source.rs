@@ -0,0 +1,104 @@
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use common::modules::order_json::OrderJSON;
+
+use crate::modules::order_source::{OrderSink, OrderSource, SOURCE_IP};
+use crate::modules::utils::perror;
+
+/// Tamaño máximo del cuerpo de un pedido
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+/// Local HTTP endpoint. Each `POST /orders` request carries an order as its JSON body, and is
+/// answered with the final status of the order once it is finished.
+pub struct HttpSource {
+    pub port: u16,
+}
+
+impl OrderSource for HttpSource {
+    fn start(self: Box<Self>, sink: OrderSink) {
+        actix::spawn(async move {
+            let listener = match TcpListener::bind(format!("{}:{}", SOURCE_IP, self.port)).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    perror("Couldn't listen for orders", Some(Box::new(e)));
+                    return;
+                }
+            };
+            while let Ok((stream, _)) = listener.accept().await {
+                actix::spawn(handle_request(stream, sink.clone()));
+            }
+        });
+    }
+
+    fn describe(&self) -> String {
+        format!("http://{}:{}/orders", SOURCE_IP, self.port)
+    }
+}
+
+/// Reads a single request from the connection and answers it.
+async fn handle_request(stream: TcpStream, sink: OrderSink) {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).await.is_err() {
+        return;
+    }
+
+    // Headers: sólo me importa el largo del cuerpo
+    let mut content_len = 0;
+    loop {
+        let mut header = String::new();
+        match reader.read_line(&mut header).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) if header.trim().is_empty() => break,
+            Ok(_) => {
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.trim().eq_ignore_ascii_case("content-length") {
+                        content_len = value.trim().parse().unwrap_or(0);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let (status, body) = if path != "/orders" {
+        ("404 Not Found", "{\"error\":\"Unknown path\"}".to_string())
+    } else if method != "POST" {
+        (
+            "405 Method Not Allowed",
+            "{\"error\":\"Orders must be sent with POST\"}".to_string(),
+        )
+    } else if content_len == 0 || content_len > MAX_BODY_LEN {
+        (
+            "400 Bad Request",
+            "{\"error\":\"Missing or too large body\"}".to_string(),
+        )
+    } else {
+        let mut body = vec![0; content_len];
+        if reader.read_exact(&mut body).await.is_err() {
+            return;
+        }
+        match serde_json::from_slice::<OrderJSON>(&body) {
+            Ok(_) => {
+                let outcome = sink.process_line(&String::from_utf8_lossy(&body)).await;
+                ("200 OK", serde_json::to_string(&outcome).unwrap())
+            }
+            Err(e) => (
+                "400 Bad Request",
+                serde_json::json!({ "error": format!("Invalid order: {}", e) }).to_string(),
+            ),
+        }
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let _ = reader.get_mut().write_all(response.as_bytes()).await;
+}
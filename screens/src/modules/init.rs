@@ -1,56 +1,71 @@
-use std::{collections::HashMap, fs::File, net::SocketAddr, sync::Arc};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 
 use actix::prelude::*;
-use tokio::{
-    io::{split, AsyncBufReadExt, BufReader},
-    net::TcpStream,
-    sync::Mutex,
-};
-use tokio_stream::wrappers::LinesStream;
+use tokio::sync::{mpsc, oneshot, Mutex};
 
+use common::modules::{framing::FramedStream, tls::Transport};
+
+use crate::modules::connection_manager::{peer_stream, spawn_reconnect, Peer};
+use crate::modules::order_source::IncomingOrder;
+use crate::modules::parser::ScreenConfig;
 use crate::modules::screen::{Screen, WriteArcMutex};
 
-/// Creates the Screen Actix Actor
+/// Creates the Screen Actix Actor, that takes its orders from `orders` and notifies `exit` once
+/// it can exit. The robots in `missing_robots` are retried in the background.
 pub fn create_screen(
-    id: u8,
-    reader: std::io::BufReader<File>,
-    gateway_stream: TcpStream,
-    robot_streams: Vec<TcpStream>,
+    config: ScreenConfig,
+    orders: mpsc::Receiver<IncomingOrder>,
+    exit: oneshot::Sender<()>,
+    gateway_stream: FramedStream<Transport>,
+    robot_streams: Vec<(Peer, FramedStream<Transport>)>,
+    missing_robots: Vec<Peer>,
 ) -> Addr<Screen> {
     Screen::create(|ctx| {
-        let gateway_write: (SocketAddr, WriteArcMutex) = set_gateway_stream(gateway_stream, ctx);
+        let gateway_write: (SocketAddr, WriteArcMutex) =
+            set_peer_stream(Peer::Gateway, gateway_stream, ctx);
 
         let robots_write: HashMap<SocketAddr, (SocketAddr, WriteArcMutex)> =
             set_robots_streams(robot_streams, ctx);
 
-        Screen::new(id, reader, gateway_write, robots_write)
+        for peer in missing_robots {
+            spawn_reconnect(ctx.address(), peer, config.id);
+        }
+
+        Screen::new(
+            config.id,
+            orders,
+            gateway_write,
+            robots_write,
+            config.max_orders,
+            config.selector,
+            exit,
+        )
     })
 }
 
 /// Retorna (puerto donde escucho, donde escribo)
-fn set_gateway_stream(stream: TcpStream, ctx: &mut Context<Screen>) -> (SocketAddr, WriteArcMutex) {
-    let ip = stream.local_addr().unwrap();
-    let (read_half, write_half) = split(stream);
-    let stream = LinesStream::new(BufReader::new(read_half).lines());
-    Screen::add_stream(stream, ctx);
+pub fn set_peer_stream(
+    peer: Peer,
+    stream: FramedStream<Transport>,
+    ctx: &mut Context<Screen>,
+) -> (SocketAddr, WriteArcMutex) {
+    let ip = stream.get_ref().local_addr().unwrap();
+    let (read_half, write_half) = stream.split();
+    Screen::add_stream(peer_stream(peer, read_half), ctx);
 
     (ip, Arc::new(Mutex::new(write_half)))
 }
 
 /// Retorna (puerto destino robot), (puerto donde escucho, donde escribo)
 fn set_robots_streams(
-    streams: Vec<TcpStream>,
+    streams: Vec<(Peer, FramedStream<Transport>)>,
     ctx: &mut Context<Screen>,
 ) -> HashMap<SocketAddr, (SocketAddr, WriteArcMutex)> {
     let mut robots_write: HashMap<SocketAddr, (SocketAddr, WriteArcMutex)> = HashMap::new();
-    for stream in streams {
-        let local_ip = stream.local_addr().unwrap();
-        let robot_ip = stream.peer_addr().unwrap();
-
-        let (read_half, write_half) = split(stream);
-        let stream = LinesStream::new(BufReader::new(read_half).lines());
-        Screen::add_stream(stream, ctx);
-        robots_write.insert(robot_ip, (local_ip, Arc::new(Mutex::new(write_half))));
+    for (peer, stream) in streams {
+        if let Peer::Robot(robot_ip) = peer {
+            robots_write.insert(robot_ip, set_peer_stream(peer, stream, ctx));
+        }
     }
 
     robots_write
@@ -4,6 +4,16 @@ pub mod utils;
 // sugerencias en screen.rs, aunque el programa funciona bien
 // #[allow(non_snake_case)]
 // pub mod Screen;
+pub mod connection_manager;
 pub mod connections;
+pub mod http_source;
 pub mod init;
+pub mod order_in_flight;
+pub mod order_queue;
+pub mod order_source;
+pub mod order_status;
+pub mod order_tracker;
+pub mod payment_outbox;
 pub mod screen;
+pub mod selection;
+pub mod timeouts;
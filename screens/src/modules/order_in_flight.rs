@@ -0,0 +1,53 @@
+use std::{collections::HashSet, net::SocketAddr};
+
+use actix::SpawnHandle;
+use tokio::sync::oneshot;
+
+use common::modules::{order_prep::OrderPrep, order_request::OrderRequest};
+
+use crate::modules::order_status::OrderOutcome;
+
+#[derive(Debug, PartialEq)]
+pub enum PaymentState {
+    Pending,
+    Captured,
+}
+
+/// An order that the Screen is processing, with its own payment state, robot assignment and
+/// timer.
+pub struct OrderInFlight {
+    pub order: OrderPrep,
+    pub payment: PaymentState,
+    // Robot al que se le envió el OrderPrep, si hay alguno
+    pub robot: Option<SocketAddr>,
+    // Orders en la cola de ese robot cuando respondió el OrderRequest
+    pub queued: usize,
+    // Si el robot ya aceptó el pedido: el timer es entonces el plazo de preparación
+    pub preparing: bool,
+    // Respuestas de robots recibidas durante la ventana de selección
+    pub candidates: Vec<OrderRequest>,
+    // Robots que respondieron ROBOT_OCCUPIED desde el último timeout
+    pub refused_by: HashSet<SocketAddr>,
+    pub timer: Option<SpawnHandle>,
+    // Cantidad de veces que se venció el timer del pedido
+    pub attempts: u32,
+    // Por donde se devuelve el estado final del pedido a quien lo envió
+    pub reply: Option<oneshot::Sender<OrderOutcome>>,
+}
+
+impl OrderInFlight {
+    pub fn new(order: OrderPrep) -> Self {
+        OrderInFlight {
+            order,
+            payment: PaymentState::Pending,
+            robot: None,
+            queued: 0,
+            preparing: false,
+            candidates: Vec::new(),
+            refused_by: HashSet::new(),
+            timer: None,
+            attempts: 0,
+            reply: None,
+        }
+    }
+}
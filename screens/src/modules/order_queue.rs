@@ -0,0 +1,168 @@
+use std::{cmp::Reverse, time::Instant};
+
+use tokio::sync::mpsc::{self, error::TryRecvError};
+
+use crate::modules::order_source::IncomingOrder;
+
+/// Cantidad de pedidos que se toman por adelantado de la fuente para elegir por prioridad
+pub const LOOKAHEAD: usize = 4;
+
+/// Orders taken ahead from the order source. The next order is the one with the highest
+/// priority, including the time it has been waiting, so express orders are broadcast first but
+/// normal ones are not starved.
+pub struct OrderQueue {
+    orders: mpsc::Receiver<IncomingOrder>,
+    queued: Vec<(IncomingOrder, Instant)>,
+    // La fuente no va a enviar más pedidos
+    closed: bool,
+}
+
+impl OrderQueue {
+    pub fn new(orders: mpsc::Receiver<IncomingOrder>) -> Self {
+        OrderQueue {
+            orders,
+            queued: Vec::new(),
+            closed: false,
+        }
+    }
+
+    /// Takes the orders already delivered by the source, until the lookahead is full.
+    fn fill(&mut self) {
+        while self.queued.len() < LOOKAHEAD {
+            match self.orders.try_recv() {
+                Ok(incoming) => self.queued.push((incoming, Instant::now())),
+                Err(TryRecvError::Disconnected) => {
+                    self.closed = true;
+                    return;
+                }
+                Err(TryRecvError::Empty) => return,
+            }
+        }
+    }
+
+    /// Returns the next order to process, or None if there are no orders available right now.
+    pub fn next_order(&mut self) -> Option<IncomingOrder> {
+        self.fill();
+        // Ante igual prioridad, gana el que llegó primero
+        let (index, _) =
+            self.queued
+                .iter()
+                .enumerate()
+                .max_by_key(|(index, (incoming, received_at))| {
+                    (
+                        incoming.order.priority.aged(received_at.elapsed()),
+                        Reverse(*index),
+                    )
+                })?;
+        Some(self.queued.remove(index).0)
+    }
+
+    /// Stops taking orders from the source, and returns the ones that were delivered but not
+    /// taken yet.
+    pub fn close(&mut self) -> Vec<IncomingOrder> {
+        self.orders.close();
+        let mut pending: Vec<IncomingOrder> = self
+            .queued
+            .drain(..)
+            .map(|(incoming, _)| incoming)
+            .collect();
+        while let Ok(incoming) = self.orders.try_recv() {
+            pending.push(incoming);
+        }
+        self.closed = true;
+        pending
+    }
+
+    /// True if the source finished and every order was taken.
+    pub fn is_exhausted(&self) -> bool {
+        self.closed && self.queued.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::modules::{order_json::OrderJSON, priority::Priority};
+
+    fn incoming(id: usize, priority: Priority) -> IncomingOrder {
+        IncomingOrder {
+            order: OrderJSON {
+                id,
+                size: 1,
+                flavours: vec!["Vainilla".to_string()],
+                priority,
+            },
+            reply: None,
+        }
+    }
+
+    fn queue(orders: Vec<IncomingOrder>) -> (OrderQueue, mpsc::Sender<IncomingOrder>) {
+        let (sender, receiver) = mpsc::channel(16);
+        for order in orders {
+            sender.try_send(order).unwrap();
+        }
+        (OrderQueue::new(receiver), sender)
+    }
+
+    fn next_id(queue: &mut OrderQueue) -> Option<usize> {
+        queue.next_order().map(|incoming| incoming.order.id)
+    }
+
+    #[test]
+    fn express_orders_go_first() {
+        let (mut queue, _sender) = queue(vec![
+            incoming(1, Priority::Normal),
+            incoming(2, Priority::Express),
+            incoming(3, Priority::Staff),
+        ]);
+        assert_eq!(next_id(&mut queue), Some(2));
+        assert_eq!(next_id(&mut queue), Some(1));
+        assert_eq!(next_id(&mut queue), Some(3));
+        assert_eq!(next_id(&mut queue), None);
+    }
+
+    #[test]
+    fn same_priority_keeps_the_arrival_order() {
+        let (mut queue, _sender) = queue(vec![
+            incoming(1, Priority::Normal),
+            incoming(2, Priority::Normal),
+        ]);
+        assert_eq!(next_id(&mut queue), Some(1));
+        assert_eq!(next_id(&mut queue), Some(2));
+    }
+
+    #[test]
+    fn only_the_lookahead_is_taken_ahead() {
+        let mut orders: Vec<_> = (0..LOOKAHEAD)
+            .map(|id| incoming(id, Priority::Normal))
+            .collect();
+        orders.push(incoming(LOOKAHEAD, Priority::Express));
+        let (mut queue, _sender) = queue(orders);
+        // El pedido express llegó después de llenarse la ventana
+        assert_eq!(next_id(&mut queue), Some(0));
+        assert_eq!(next_id(&mut queue), Some(LOOKAHEAD));
+    }
+
+    #[test]
+    fn closed_queue_returns_the_pending_orders() {
+        let (mut queue, sender) = queue(vec![
+            incoming(1, Priority::Normal),
+            incoming(2, Priority::Normal),
+        ]);
+        assert_eq!(next_id(&mut queue), Some(1));
+        assert!(!queue.is_exhausted());
+        let pending: Vec<_> = queue.close().iter().map(|i| i.order.id).collect();
+        assert_eq!(pending, vec![2]);
+        assert!(queue.is_exhausted());
+        assert!(sender.try_send(incoming(3, Priority::Normal)).is_err());
+    }
+
+    #[test]
+    fn queue_is_exhausted_once_the_source_finished() {
+        let (mut queue, sender) = queue(vec![incoming(1, Priority::Normal)]);
+        drop(sender);
+        assert_eq!(next_id(&mut queue), Some(1));
+        assert_eq!(next_id(&mut queue), None);
+        assert!(queue.is_exhausted());
+    }
+}
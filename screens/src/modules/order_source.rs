@@ -0,0 +1,240 @@
+use std::{path::PathBuf, sync::Arc};
+
+use actix::Addr;
+use serde_json::from_str;
+use tokio::{
+    fs::File,
+    io::{split, AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+    sync::{mpsc, oneshot, Mutex},
+};
+
+use common::modules::order_json::OrderJSON;
+
+use crate::modules::http_source::HttpSource;
+use crate::modules::order_status::{OrderOutcome, OrderStatus};
+use crate::modules::screen::{ReceiveOrder, Screen};
+use crate::modules::utils::perror;
+
+/// IP donde escuchan las fuentes de pedidos por red
+pub const SOURCE_IP: &str = "127.0.0.1";
+
+/// An order received from a source. If `reply` is given, the final status of the order is sent
+/// through it.
+pub struct IncomingOrder {
+    pub order: OrderJSON,
+    pub reply: Option<oneshot::Sender<OrderOutcome>>,
+}
+
+/// Where a source delivers its orders. The channel is bounded, so a source waits while the
+/// Screen has enough orders queued.
+#[derive(Clone)]
+pub struct OrderSink {
+    screen: Addr<Screen>,
+    orders: mpsc::Sender<IncomingOrder>,
+}
+
+impl OrderSink {
+    pub fn new(screen: Addr<Screen>, orders: mpsc::Sender<IncomingOrder>) -> Self {
+        OrderSink { screen, orders }
+    }
+
+    /// Parses an order and delivers it to the Screen. Returns the channel where its final status
+    /// is sent, or the outcome right away if the order couldn't be delivered.
+    pub async fn push_line(
+        &self,
+        line: &str,
+    ) -> Result<oneshot::Receiver<OrderOutcome>, OrderOutcome> {
+        let order = from_str::<OrderJSON>(line).map_err(|e| {
+            OrderOutcome::new(0, OrderStatus::Rejected, &format!("Invalid order: {}", e))
+        })?;
+        let id = order.id;
+        let (reply, outcome) = oneshot::channel();
+        let incoming = IncomingOrder {
+            order,
+            reply: Some(reply),
+        };
+        if self.orders.send(incoming).await.is_err() {
+            return Err(OrderOutcome::new(
+                id,
+                OrderStatus::Rejected,
+                "The screen is not receiving orders",
+            ));
+        }
+        // Despierto a la pantalla por si no tenía pedidos
+        self.screen.do_send(ReceiveOrder());
+        Ok(outcome)
+    }
+
+    /// True if the Screen stopped taking orders.
+    pub fn is_closed(&self) -> bool {
+        self.orders.is_closed()
+    }
+
+    /// Delivers an order and waits for its final status.
+    pub async fn process_line(&self, line: &str) -> OrderOutcome {
+        match self.push_line(line).await {
+            Ok(outcome) => outcome.await.unwrap_or_else(|_| {
+                OrderOutcome::new(0, OrderStatus::Rejected, "The screen dropped the order")
+            }),
+            Err(outcome) => outcome,
+        }
+    }
+}
+
+/// Source of the orders processed by a Screen.
+pub trait OrderSource {
+    /// Starts delivering orders to the sink in the background.
+    fn start(self: Box<Self>, sink: OrderSink);
+
+    /// Short description of the source, for the logs.
+    fn describe(&self) -> String;
+}
+
+/// Reads one order per line from a `.jsonl` file. The outcome of each order is printed by the
+/// Screen.
+pub struct FileSource {
+    pub path: PathBuf,
+}
+
+impl OrderSource for FileSource {
+    fn start(self: Box<Self>, sink: OrderSink) {
+        actix::spawn(async move {
+            let file = match File::open(&self.path).await {
+                Ok(file) => file,
+                Err(e) => {
+                    perror("Couldn't open the orders file", Some(Box::new(e)));
+                    return;
+                }
+            };
+            let mut lines = BufReader::new(file).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                // La pantalla se está cerrando, no tiene sentido seguir leyendo
+                if sink.is_closed() {
+                    break;
+                }
+                if let Err(outcome) = sink.push_line(&line).await {
+                    perror(outcome.detail.as_str(), None);
+                }
+            }
+            // Al soltar el sink se cierra el canal, y la pantalla sabe que no hay más pedidos
+        });
+    }
+
+    fn describe(&self) -> String {
+        format!("file {}", self.path.display())
+    }
+}
+
+/// Reads orders typed on the terminal, one JSON per line. The other lines are commands, and are
+/// forwarded to `commands`.
+pub struct StdinSource {
+    pub commands: mpsc::UnboundedSender<String>,
+}
+
+impl OrderSource for StdinSource {
+    fn start(self: Box<Self>, sink: OrderSink) {
+        actix::spawn(read_stdin(self.commands, Some(sink)));
+    }
+
+    fn describe(&self) -> String {
+        "stdin".to_string()
+    }
+}
+
+/// Reads the terminal, forwarding commands to `commands`. If `orders` is given, lines that look
+/// like a JSON are delivered as orders, and their final status is printed.
+pub async fn read_stdin(commands: mpsc::UnboundedSender<String>, orders: Option<OrderSink>) {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        match &orders {
+            Some(sink) if line.trim_start().starts_with('{') => {
+                let sink = sink.clone();
+                actix::spawn(async move {
+                    let outcome = sink.process_line(&line).await;
+                    println!(
+                        "[Stdin] Order {} finished: {:?}",
+                        outcome.id, outcome.status
+                    );
+                });
+            }
+            _ => {
+                if commands.send(line).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Accepts TCP connections where each line is an order. The final status of every order is
+/// written back on the same connection as a JSON line, in the order they finish.
+pub struct TcpSource {
+    pub port: u16,
+}
+
+impl OrderSource for TcpSource {
+    fn start(self: Box<Self>, sink: OrderSink) {
+        actix::spawn(async move {
+            let listener = match TcpListener::bind(format!("{}:{}", SOURCE_IP, self.port)).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    perror("Couldn't listen for orders", Some(Box::new(e)));
+                    return;
+                }
+            };
+            while let Ok((stream, client)) = listener.accept().await {
+                println!("[TCP] Receiving orders from {}", client);
+                let sink = sink.clone();
+                actix::spawn(async move {
+                    let (read_half, write_half) = split(stream);
+                    let write_half = Arc::new(Mutex::new(write_half));
+                    let mut lines = BufReader::new(read_half).lines();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let (sink, write_half) = (sink.clone(), write_half.clone());
+                        actix::spawn(async move {
+                            let outcome = sink.process_line(&line).await;
+                            let reply = serde_json::to_string(&outcome).unwrap() + "\n";
+                            let _ = write_half.lock().await.write_all(reply.as_bytes()).await;
+                        });
+                    }
+                });
+            }
+        });
+    }
+
+    fn describe(&self) -> String {
+        format!("tcp {}:{}", SOURCE_IP, self.port)
+    }
+}
+
+/// Order source chosen in the command line arguments.
+pub enum SourceConfig {
+    File(PathBuf),
+    Stdin,
+    Tcp(u16),
+    Http(u16),
+}
+
+/// Starts the order source. Terminal lines that are not orders are forwarded to `commands`.
+pub fn start_source(
+    config: SourceConfig,
+    sink: OrderSink,
+    commands: mpsc::UnboundedSender<String>,
+) {
+    let reads_stdin = matches!(config, SourceConfig::Stdin);
+    let source: Box<dyn OrderSource> = match config {
+        SourceConfig::File(path) => Box::new(FileSource { path }),
+        SourceConfig::Stdin => Box::new(StdinSource {
+            commands: commands.clone(),
+        }),
+        SourceConfig::Tcp(port) => Box::new(TcpSource { port }),
+        SourceConfig::Http(port) => Box::new(HttpSource { port }),
+    };
+    // Si la fuente no es la terminal, la terminal sólo recibe comandos
+    if !reads_stdin {
+        actix::spawn(read_stdin(commands, None));
+    }
+    println!("Receiving orders from {}", source.describe());
+    source.start(sink);
+}
@@ -0,0 +1,31 @@
+use serde::Serialize;
+
+/// Final status of an order.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderStatus {
+    /// Prepared and paid.
+    Completed,
+    /// Accepted, but it couldn't be prepared.
+    Failed,
+    /// Never started: invalid, duplicated, sold out or its payment couldn't be captured.
+    Rejected,
+}
+
+/// Final status of an order, returned to whoever sent it.
+#[derive(Serialize, Debug, Clone)]
+pub struct OrderOutcome {
+    pub id: usize,
+    pub status: OrderStatus,
+    pub detail: String,
+}
+
+impl OrderOutcome {
+    pub fn new(id: usize, status: OrderStatus, detail: &str) -> Self {
+        OrderOutcome {
+            id,
+            status,
+            detail: detail.to_string(),
+        }
+    }
+}
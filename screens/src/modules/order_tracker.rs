@@ -0,0 +1,105 @@
+use std::{collections::BTreeMap, fmt};
+
+use chrono::Local;
+use serde::Serialize;
+
+/// A step in the lifecycle of an order.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum OrderState {
+    Received,
+    PaymentCaptured,
+    PaymentDeclined,
+    Assigned { robot: String },
+    Preparing { robot: String },
+    TimedOut { attempt: u32 },
+    Completed,
+    Failed { reason: String },
+    Rejected { reason: String },
+}
+
+impl OrderState {
+    /// True if the order can't change state anymore.
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            OrderState::PaymentDeclined
+                | OrderState::Completed
+                | OrderState::Failed { .. }
+                | OrderState::Rejected { .. }
+        )
+    }
+}
+
+impl fmt::Display for OrderState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderState::Received => write!(f, "received"),
+            OrderState::PaymentCaptured => write!(f, "payment captured"),
+            OrderState::PaymentDeclined => write!(f, "payment declined"),
+            OrderState::Assigned { robot } => write!(f, "assigned to robot {}", robot),
+            OrderState::Preparing { robot } => write!(f, "preparing in robot {}", robot),
+            OrderState::TimedOut { attempt } => write!(f, "timed out (attempt {})", attempt),
+            OrderState::Completed => write!(f, "completed"),
+            OrderState::Failed { reason } => write!(f, "failed: {}", reason),
+            OrderState::Rejected { reason } => write!(f, "rejected: {}", reason),
+        }
+    }
+}
+
+/// Every state an order went through, with the time it entered each one.
+#[derive(Serialize, Debug, Clone)]
+pub struct OrderHistory {
+    pub id: usize,
+    pub events: Vec<(String, OrderState)>,
+}
+
+impl OrderHistory {
+    /// Current state of the order.
+    pub fn state(&self) -> Option<&OrderState> {
+        self.events.last().map(|(_, state)| state)
+    }
+}
+
+impl fmt::Display for OrderHistory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.state() {
+            Some(state) => writeln!(f, "Order {}: {}", self.id, state)?,
+            None => writeln!(f, "Order {}", self.id)?,
+        }
+        for (time, state) in &self.events {
+            writeln!(f, "  {}  {}", time, state)?;
+        }
+        Ok(())
+    }
+}
+
+/// Lifecycle of every order received by the Screen.
+#[derive(Default)]
+pub struct OrderTracker {
+    orders: BTreeMap<usize, OrderHistory>,
+}
+
+impl OrderTracker {
+    /// Records a new state of an order. A new order with the id of a finished one replaces its
+    /// history.
+    pub fn record(&mut self, id: usize, state: OrderState) {
+        let time = Local::now().format("%Y-%m-%d %H:%M:%S%.3f").to_string();
+        let history = self.orders.entry(id).or_insert(OrderHistory {
+            id,
+            events: Vec::new(),
+        });
+        if state == OrderState::Received && history.state().is_some_and(OrderState::is_final) {
+            history.events.clear();
+        }
+        history.events.push((time, state));
+    }
+
+    /// History of one order, or of every order if `id` is None.
+    pub fn query(&self, id: Option<usize>) -> Vec<OrderHistory> {
+        match id {
+            Some(id) => self.orders.get(&id).cloned().into_iter().collect(),
+            None => self.orders.values().cloned().collect(),
+        }
+    }
+}
@@ -1,18 +1,31 @@
-use std::fs::File;
-use std::io::BufReader;
 use std::path::Path;
 
+use crate::modules::order_source::SourceConfig;
+use crate::modules::selection::{selector_from_name, RobotSelector};
 use crate::modules::utils::perror;
 
-/// Panics if errors are found
-pub fn parse_args() -> (u8, BufReader<File>) {
+/// Cantidad de órdenes que procesa la pantalla a la vez si no se indica otra
+const DEFAULT_MAX_ORDERS: usize = 2;
+/// Estrategia de selección de robots si no se indica otra
+const DEFAULT_STRATEGY: &str = "least-loaded";
+
+const USAGE: &str = "Usage: cargo run <screen id> <orders.jsonl|stdin|tcp:<port>|http:<port>> [max orders] [first-reply|least-loaded|round-robin]";
+
+/// Configuration of the Screen given by the command line arguments
+pub struct ScreenConfig {
+    pub id: u8,
+    pub max_orders: usize,
+    pub selector: Box<dyn RobotSelector>,
+}
+
+/// Returns the configuration of the Screen and its order source. Exits process if errors are
+/// found
+pub fn parse_args() -> (ScreenConfig, SourceConfig) {
     let args: Vec<String> = std::env::args().collect();
 
-    if args.len() != 3 {
-        perror(
-            "Error parsing arguments. Usage: cargo run <screen id> <orders.jsonl>",
-            None,
-        );
+    if args.len() < 3 || args.len() > 5 {
+        perror(format!("Error parsing arguments. {}", USAGE).as_str(), None);
+        std::process::exit(1)
     }
 
     let screen_id: u8 = match args[1].parse() {
@@ -24,10 +37,60 @@ pub fn parse_args() -> (u8, BufReader<File>) {
         }
     };
 
+    let source = parse_source(&args[2]);
+
+    let max_orders: usize = match args.get(3).map(|arg| arg.parse()) {
+        None => DEFAULT_MAX_ORDERS,
+        Some(Ok(max_orders)) if max_orders > 0 => max_orders,
+        Some(_) => {
+            perror(
+                "Error parsing arguments. Max orders must be a positive number.",
+                None,
+            );
+            std::process::exit(1)
+        }
+    };
+
+    let strategy = args.get(4).map(String::as_str).unwrap_or(DEFAULT_STRATEGY);
+    let Some(selector) = selector_from_name(strategy) else {
+        perror(
+            format!("Error parsing arguments. Unknown strategy. {}", USAGE).as_str(),
+            None,
+        );
+        std::process::exit(1)
+    };
+
+    let config = ScreenConfig {
+        id: screen_id,
+        max_orders,
+        selector,
+    };
+
+    (config, source)
+}
+
+/// Parses the order source: "stdin", "tcp:<port>", "http:<port>" or the name of a .jsonl file
+/// in the orders directory. Exits process if it's not valid.
+fn parse_source(arg: &str) -> SourceConfig {
+    if arg == "stdin" {
+        return SourceConfig::Stdin;
+    }
+    if let Some((kind @ ("tcp" | "http"), port)) = arg.split_once(':') {
+        let Ok(port) = port.parse() else {
+            perror("Error parsing arguments. Port must be an u16.", None);
+            std::process::exit(1)
+        };
+        return if kind == "tcp" {
+            SourceConfig::Tcp(port)
+        } else {
+            SourceConfig::Http(port)
+        };
+    }
+
     // Reviso el path
     let orders_path = Path::new(std::env!("CARGO_MANIFEST_DIR"))
         .join("orders")
-        .join(&args[2]);
+        .join(arg);
 
     if !orders_path.exists() {
         perror("Error parsing arguments. Orders file doesn't exist", None);
@@ -39,8 +102,5 @@ pub fn parse_args() -> (u8, BufReader<File>) {
         );
         std::process::exit(1)
     }
-
-    let file = File::open(orders_path).unwrap();
-
-    (screen_id, BufReader::new(file))
+    SourceConfig::File(orders_path)
 }
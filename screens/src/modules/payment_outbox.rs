@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+
+use common::modules::{
+    order_prep::OrderPrep, payment_capture::PaymentCapture,
+    payment_confirmation::PaymentConfirmation, payment_void::PaymentVoid,
+};
+
+/// A payment message for the gateway.
+#[derive(Debug, Clone)]
+pub enum PaymentRequest {
+    Capture(usize),
+    Confirm(OrderPrep),
+    Void(usize),
+}
+
+impl PaymentRequest {
+    pub fn order_id(&self) -> usize {
+        match self {
+            PaymentRequest::Capture(order_id) | PaymentRequest::Void(order_id) => *order_id,
+            PaymentRequest::Confirm(order) => order.id,
+        }
+    }
+
+    /// Name of the message, the same the gateway uses to acknowledge it.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PaymentRequest::Capture(_) => "PaymentCapture",
+            PaymentRequest::Confirm(_) => "PaymentConfirmation",
+            PaymentRequest::Void(_) => "PaymentVoid",
+        }
+    }
+
+    /// Serializes the message of the payment `payment_id`, sent from `local_ip` by the Screen
+    /// `screen_id`.
+    pub fn to_message(&self, local_ip: String, screen_id: u8, payment_id: String) -> String {
+        let id = screen_id.to_string();
+        match self {
+            PaymentRequest::Capture(order_id) => serde_json::to_string(&PaymentCapture::new(
+                local_ip, id, *order_id, payment_id, true,
+            )),
+            PaymentRequest::Confirm(order) => serde_json::to_string(&PaymentConfirmation::new(
+                local_ip,
+                id,
+                order.clone(),
+                payment_id,
+            )),
+            PaymentRequest::Void(order_id) => {
+                serde_json::to_string(&PaymentVoid::new(local_ip, id, *order_id, payment_id))
+            }
+        }
+        .unwrap()
+    }
+}
+
+/// Payment messages sent to the gateway that weren't answered yet. If the connection with the
+/// gateway is lost they are sent again, and the gateway answers a repeated one without charging
+/// or logging it twice.
+/// Every capture starts a payment with a new id, made of a random session of the Screen and a
+/// counter. The gateway tells payments apart by that id, so an order id used again, for example
+/// after the Screen restarts, is never taken for a payment it already processed.
+pub struct PaymentOutbox {
+    // Por id de orden y tipo de mensaje
+    pending: HashMap<(usize, &'static str), PaymentRequest>,
+    // Id del pago en curso de cada orden
+    payment_ids: HashMap<usize, String>,
+    session: u64,
+    captures: u64,
+}
+
+impl PaymentOutbox {
+    pub fn new() -> Self {
+        PaymentOutbox {
+            pending: HashMap::new(),
+            payment_ids: HashMap::new(),
+            session: rand::random(),
+            captures: 0,
+        }
+    }
+
+    /// Keeps the request until the gateway answers it, and returns the id of the payment it
+    /// belongs to. A capture starts a new payment.
+    pub fn push(&mut self, request: PaymentRequest) -> String {
+        let order_id = request.order_id();
+        if let PaymentRequest::Capture(_) = request {
+            self.captures += 1;
+            let payment_id = format!("{:016x}-{}", self.session, self.captures);
+            self.payment_ids.insert(order_id, payment_id);
+        }
+        self.pending.insert((order_id, request.kind()), request);
+        self.payment_id(order_id)
+    }
+
+    /// Id of the payment of an order.
+    pub fn payment_id(&self, order_id: usize) -> String {
+        self.payment_ids.get(&order_id).cloned().unwrap_or_default()
+    }
+
+    /// The gateway answered the `kind` message of an order. Once a payment is confirmed or
+    /// voided, its id is no longer needed.
+    pub fn ack(&mut self, order_id: usize, kind: &str) {
+        self.pending
+            .retain(|(id, pending_kind), _| *id != order_id || *pending_kind != kind);
+        if kind != "PaymentCapture" {
+            self.close(order_id);
+        }
+    }
+
+    /// The payment of the order is finished, for example because it couldn't be captured.
+    pub fn close(&mut self, order_id: usize) {
+        self.payment_ids.remove(&order_id);
+    }
+
+    /// Every request still waiting for an answer.
+    pub fn pending(&self) -> Vec<PaymentRequest> {
+        self.pending.values().cloned().collect()
+    }
+}
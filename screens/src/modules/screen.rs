@@ -1,7 +1,6 @@
 use std::{
-    collections::HashMap,
-    fs::File,
-    io::{BufRead, BufReader, Error},
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
     net::SocketAddr,
     sync::Arc,
 };
@@ -11,130 +10,361 @@ use actix::prelude::*;
 
 use serde_json::from_str;
 use tokio::{
-    io::{AsyncWriteExt, WriteHalf},
-    net::TcpStream,
-    sync::Mutex,
+    io::WriteHalf,
+    sync::{mpsc, oneshot, Mutex},
 };
 
 use common::modules::{
     disconnect::Disconnect,
-    order_json::OrderJSON,
-    order_prep::{OrderPrep, ORDER_FAILED, ORDER_SUCCESS, ROBOT_OCCUPIED},
+    framing::{FrameWriter, FramedStream},
+    inventory::{InventoryQuery, InventoryReport},
+    logical_clock::LogicalClock,
+    order_prep::{OrderPrep, ORDER_ACCEPTED, ORDER_FAILED, ORDER_SUCCESS, ROBOT_OCCUPIED},
+    order_release::OrderRelease,
     order_request::OrderRequest,
+    payment_ack::PaymentAck,
     payment_capture::PaymentCapture,
-    payment_confirmation::PaymentConfirmation,
+    stock_update::{StockUpdate, STOCK_LOW, STOCK_SOLD_OUT},
+    system_shutdown::SystemShutdown,
+    tls::Transport,
 };
 
+use crate::modules::connection_manager::{spawn_reconnect, Peer, PeerLine};
+use crate::modules::init::set_peer_stream;
+use crate::modules::order_in_flight::{OrderInFlight, PaymentState};
+use crate::modules::order_queue::OrderQueue;
+use crate::modules::order_source::IncomingOrder;
+use crate::modules::order_status::{OrderOutcome, OrderStatus};
+use crate::modules::order_tracker::{OrderHistory, OrderState, OrderTracker};
+use crate::modules::payment_outbox::{PaymentOutbox, PaymentRequest};
+use crate::modules::selection::RobotSelector;
+use crate::modules::timeouts::{OrderTimeouts, ACK_TIMEOUT, DRAIN_TIMEOUT, MAX_ATTEMPTS};
 use crate::modules::utils::perror;
 
-pub type WriteArcMutex = Arc<Mutex<WriteHalf<TcpStream>>>;
+pub type WriteArcMutex = Arc<Mutex<FrameWriter<WriteHalf<Transport>>>>;
 
 pub struct Screen {
     id: u8,
-    reader: BufReader<File>,
+    queue: OrderQueue,
     // (puerto donde escucho, donde escribo)
     gateway_write: (SocketAddr, WriteArcMutex),
     // (puerto destino robot), (puerto donde escucho, donde escribo)
     robots_write: HashMap<SocketAddr, (SocketAddr, WriteArcMutex)>,
-    current_order: Option<OrderPrep>,
-    order_in_process: bool,
-    finished_orders: Vec<usize>, // Contiene ids de ordenes finalizadas
+    // Órdenes en proceso, por id de orden
+    orders: HashMap<usize, OrderInFlight>,
+    max_orders: usize,
+    selector: Box<dyn RobotSelector>,
+    timeouts: OrderTimeouts,
+    tracker: OrderTracker,      // Historial de estados de cada orden
+    stock: HashMap<String, u8>, // Último nivel de stock informado por los robots para cada gusto
+    clock: LogicalClock,
+    // La pantalla se está cerrando y no toma pedidos nuevos
+    draining: bool,
+    // Robots y gateway que todavía no respondieron el Disconnect, una vez enviado
+    pending_acks: Option<HashSet<Peer>>,
+    // Mensajes de pago que el gateway todavía no respondió
+    outbox: PaymentOutbox,
+    // Avisa a main que ya se puede salir
+    exit: Option<oneshot::Sender<()>>,
 }
 
 impl Screen {
     pub fn new(
         id: u8,
-        reader: BufReader<File>,
+        orders: mpsc::Receiver<IncomingOrder>,
         gateway_write: (SocketAddr, WriteArcMutex),
         robots_write: HashMap<SocketAddr, (SocketAddr, WriteArcMutex)>,
+        max_orders: usize,
+        selector: Box<dyn RobotSelector>,
+        exit: oneshot::Sender<()>,
     ) -> Self {
         Screen {
             id,
-            reader,
+            queue: OrderQueue::new(orders),
             gateway_write,
             robots_write,
-            current_order: None,
-            order_in_process: false,
-            finished_orders: Vec::new(),
+            orders: HashMap::new(),
+            max_orders,
+            selector,
+            timeouts: OrderTimeouts::new(),
+            tracker: OrderTracker::default(),
+            stock: HashMap::new(),
+            clock: LogicalClock::new(format!("screen_{}", id)),
+            draining: false,
+            pending_acks: None,
+            outbox: PaymentOutbox::new(),
+            exit: Some(exit),
         }
     }
 
-    /// Sends the message through the provided WriteArcMutex
+    /// Stamps the message with the logical clock and sends it through the provided WriteArcMutex
     fn send_message(&mut self, ctx: &mut Context<Self>, msg: String, stream_arc: WriteArcMutex) {
+        let msg = self.clock.stamp(&msg);
         wrap_future::<_, Self>(async move {
             // Podrías hacer un logger y tener prints de debug
             // println!("Sending: {:?}", msg);
-            stream_arc
-                .lock()
-                .await
-                .write_all((msg + "\n").as_bytes())
-                .await
-                .expect("Should have sent message");
+            // Si la conexión se cayó, el connection manager se encarga de reconectar
+            if let Err(e) = stream_arc.lock().await.send(&msg).await {
+                perror("Couldn't send message", Some(Box::new(e)));
+            }
         })
         .spawn(ctx);
     }
 
-    /// Sends the request for the current order to all connected robots.
-    fn broadcast_request(&mut self, ctx: &mut Context<Self>) {
-        let order_id = self.current_order.clone().unwrap().id;
+    /// Sends the request for an order to all connected robots, except the ones that already
+    /// refused it. Returns the amount of robots the request was sent to.
+    fn broadcast_request(&mut self, ctx: &mut Context<Self>, order_id: usize) -> usize {
         println!("Broadcasting order request {}.", order_id);
         // Para evitar error de manejo de self al hacer self.robots_write.iter()
         let robots_write = self.robots_write.clone();
+        let refused_by = self
+            .orders
+            .get(&order_id)
+            .map(|order| order.refused_by.clone())
+            .unwrap_or_default();
 
-        for (_, (ip, write)) in robots_write.iter() {
-            let request = OrderRequest::new(ip.to_string(), order_id);
+        let mut sent = 0;
+        for (robot_addr, (ip, write)) in robots_write.iter() {
+            if refused_by.contains(robot_addr) {
+                continue;
+            }
+            sent += 1;
+            let request = OrderRequest::new(ip.to_string(), self.id as usize, order_id);
             let msg = serde_json::to_string(&request).unwrap();
             self.send_message(ctx, msg, write.clone());
         }
+        sent
     }
 
-    /// Sends a PaymentConfirmation message to the gateway
-    fn confirm_payment(&mut self, ctx: &mut Context<Self>, order_data: OrderPrep) {
-        // Confirmar el pago con el gateway
+    /// Sends a payment message to the gateway, and keeps it until the gateway answers it
+    fn send_payment(&mut self, ctx: &mut Context<Self>, request: PaymentRequest) {
         let local_ip = self.gateway_write.0.to_string();
-        let confirmation = PaymentConfirmation::new(local_ip, self.id.to_string(), order_data);
-        let msg = serde_json::to_string(&confirmation).unwrap();
+        let payment_id = self.outbox.push(request.clone());
+        let msg = request.to_message(local_ip, self.id, payment_id);
         let stream_arc: WriteArcMutex = self.gateway_write.1.clone();
 
         self.send_message(ctx, msg, stream_arc);
     }
 
-    /// Starts a 30 second async timer that sends a ReBroadCastOrder message to the screen
-    /// when finished
-    fn start_order_timer(&mut self, ctx: &mut Context<Self>) {
+    /// Sends a PaymentConfirmation message to the gateway
+    fn confirm_payment(&mut self, ctx: &mut Context<Self>, order_data: OrderPrep) {
+        // Confirmar el pago con el gateway
+        self.send_payment(ctx, PaymentRequest::Confirm(order_data));
+    }
+
+    /// Sends a PaymentVoid message to the gateway, to release the payment captured for an order
+    /// that couldn't be prepared.
+    fn void_payment(&mut self, ctx: &mut Context<Self>, order_id: usize) {
+        self.send_payment(ctx, PaymentRequest::Void(order_id));
+    }
+
+    /// Starts an async timer for an order that sends a ReBroadCastOrder message to the screen
+    /// when finished. Its duration depends on the size of the order and the ring round-trip
+    /// time, plus a backoff that grows with every attempt. Once a robot accepted the order, it is
+    /// the deadline to prepare it instead, which also covers the orders queued before it. If the
+    /// order already had a timer, it is replaced.
+    fn start_order_timer(&mut self, ctx: &mut Context<Self>, id: usize) {
+        let Some(order) = self.orders.get(&id) else {
+            return;
+        };
         let screen_address: Addr<Screen> = ctx.address();
-        let id = self.current_order.clone().unwrap().id;
+        let duration = if order.preparing {
+            self.timeouts
+                .preparation_timeout(order.order.size, order.queued)
+        } else {
+            self.timeouts.order_timeout(order.order.size) + self.timeouts.backoff(order.attempts)
+        };
 
-        wrap_future::<_, Self>(async move {
-            println!("Starting timer");
-            tokio::time::sleep(tokio::time::Duration::from_secs(30)).await;
+        let timer = ctx.spawn(wrap_future::<_, Self>(async move {
+            println!(
+                "Starting timer for order {} ({:.1} seconds)",
+                id,
+                duration.as_secs_f64()
+            );
+            tokio::time::sleep(duration).await;
             let _ = screen_address.send(ReBroadcastOrder { id }).await;
+        }));
+        if let Some(order) = self.orders.get_mut(&id) {
+            if let Some(previous_timer) = order.timer.replace(timer) {
+                ctx.cancel_future(previous_timer);
+            }
+        }
+    }
+
+    /// Removes a finished order, stops its timer, records its final state and returns its final
+    /// status to whoever sent it. Returns the order, if it was in process.
+    fn finish_order(
+        &mut self,
+        ctx: &mut Context<Self>,
+        id: usize,
+        status: OrderStatus,
+        detail: &str,
+    ) -> Option<OrderInFlight> {
+        let mut order = self.orders.remove(&id)?;
+        if let Some(timer) = order.timer.take() {
+            ctx.cancel_future(timer);
+        }
+        if let Some(reply) = order.reply.take() {
+            let _ = reply.send(OrderOutcome::new(id, status, detail));
+        }
+        let state = match status {
+            OrderStatus::Completed => OrderState::Completed,
+            OrderStatus::Failed => OrderState::Failed {
+                reason: detail.to_string(),
+            },
+            OrderStatus::Rejected => OrderState::Rejected {
+                reason: detail.to_string(),
+            },
+        };
+        self.tracker.record(id, state);
+        Some(order)
+    }
+
+    /// Returns the final status of an order that was never put in process.
+    fn reject_order(&mut self, ctx: &mut Context<Self>, incoming: IncomingOrder, detail: &str) {
+        if let Some(reply) = incoming.reply {
+            let _ = reply.send(OrderOutcome::new(
+                incoming.order.id,
+                OrderStatus::Rejected,
+                detail,
+            ));
+        }
+        ctx.address().do_send(ReceiveOrder());
+    }
+
+    /// Stops taking orders and rejects the ones that were queued, waits up to `DRAIN_TIMEOUT` for
+    /// the orders in process, and then sends a Disconnect message to the gateway and every robot.
+    /// Main is notified once they all acknowledged it.
+    fn start_drain(&mut self, ctx: &mut Context<Self>) {
+        if self.draining {
+            return;
+        }
+        self.draining = true;
+
+        for incoming in self.queue.close() {
+            let id = incoming.order.id;
+            self.tracker.record(
+                id,
+                OrderState::Rejected {
+                    reason: "The screen is shutting down".to_string(),
+                },
+            );
+            if let Some(reply) = incoming.reply {
+                let _ = reply.send(OrderOutcome::new(
+                    id,
+                    OrderStatus::Rejected,
+                    "The screen is shutting down",
+                ));
+            }
+        }
+
+        if self.orders.is_empty() {
+            self.disconnect_all(ctx);
+            return;
+        }
+        println!(
+            "Shutting down: waiting for {} orders in process (up to {} seconds).",
+            self.orders.len(),
+            DRAIN_TIMEOUT.as_secs()
+        );
+        let screen_address: Addr<Screen> = ctx.address();
+        wrap_future::<_, Self>(async move {
+            tokio::time::sleep(DRAIN_TIMEOUT).await;
+            let _ = screen_address.send(DrainDeadline()).await;
+        })
+        .spawn(ctx);
+    }
+
+    /// Sends a Disconnect message to the gateway and every robot, and waits for them to answer
+    /// with their own Disconnect. If some of them don't answer in `ACK_TIMEOUT`, the Screen
+    /// exits anyway.
+    fn disconnect_all(&mut self, ctx: &mut Context<Self>) {
+        if self.pending_acks.is_some() {
+            return;
+        }
+        println!("Disconnecting from gateway and robots.");
+        let mut pending_acks = HashSet::new();
+
+        let local_ip = self.gateway_write.0.to_string();
+        let disconnect = Disconnect::new(local_ip, self.id.to_string());
+        let msg = serde_json::to_string(&disconnect).unwrap();
+        let stream_arc = self.gateway_write.1.clone();
+        self.send_message(ctx, msg, stream_arc);
+        pending_acks.insert(Peer::Gateway);
+
+        let robots_write = self.robots_write.clone();
+        for (robot_addr, (local_ip, write)) in robots_write {
+            let disconnect = Disconnect::new(local_ip.to_string(), self.id.to_string());
+            let msg = serde_json::to_string(&disconnect).unwrap();
+            self.send_message(ctx, msg, write);
+            pending_acks.insert(Peer::Robot(robot_addr));
+        }
+        self.pending_acks = Some(pending_acks);
+
+        let screen_address: Addr<Screen> = ctx.address();
+        wrap_future::<_, Self>(async move {
+            tokio::time::sleep(ACK_TIMEOUT).await;
+            let _ = screen_address.send(AckDeadline()).await;
         })
         .spawn(ctx);
     }
 
+    /// Lets main know the Screen can exit.
+    fn exit(&mut self) {
+        if let Some(exit) = self.exit.take() {
+            let _ = exit.send(());
+        }
+    }
+
     // MESSAGE HANDLERS ----------------------------------------------------------------------------
 
-    /// Stores the Order on current_order and sends a PaymentCapture message to the Gateway
-    fn handle_new_order(&mut self, ctx: &mut Context<Self>, line: String) {
-        match from_str::<OrderJSON>(&line) {
-            Ok(order_json) => {
-                println!("\x1b[1m\x1b[4mReceived order\x1b[0m: {:?}", order_json);
-                // Guardo el pedido
-                self.current_order = Some(OrderPrep::from(order_json));
-
-                // Capturo el pago
-                let payment = PaymentCapture::new(
-                    self.gateway_write.0.to_string(),
-                    self.id.to_string(),
-                    true,
-                );
-                let msg = serde_json::to_string(&payment).unwrap();
-                let stream_arc: WriteArcMutex = self.gateway_write.1.clone();
-                self.send_message(ctx, msg, stream_arc)
-            }
-            Err(e) => eprintln!("Failed to deserialize order: {}", e),
+    /// Stores the Order as in process and sends a PaymentCapture message to the Gateway
+    fn handle_new_order(&mut self, ctx: &mut Context<Self>, incoming: IncomingOrder) {
+        let order_json = &incoming.order;
+        println!("\x1b[1m\x1b[4mReceived order\x1b[0m: {:?}", order_json);
+        if self.orders.contains_key(&order_json.id) {
+            perror(
+                format!("Order {} is already in process, ignored.", order_json.id).as_str(),
+                None,
+            );
+            self.reject_order(
+                ctx,
+                incoming,
+                "An order with the same id is already in process",
+            );
+            return;
+        }
+        self.tracker.record(order_json.id, OrderState::Received);
+        // Si algún gusto está agotado, rechazo el pedido sin contactar al gateway
+        if let Some(flavour) = order_json
+            .flavours
+            .iter()
+            .find(|flavour| self.stock.get(*flavour) == Some(&STOCK_SOLD_OUT))
+        {
+            println!(
+                "\x1b[31m✘\x1b[0m {} is sold out, order is cancelled.\n",
+                flavour
+            );
+            let detail = format!("{} is sold out", flavour);
+            self.tracker.record(
+                order_json.id,
+                OrderState::Rejected {
+                    reason: detail.to_string(),
+                },
+            );
+            self.reject_order(ctx, incoming, &detail);
+            return;
         }
+        // Guardo el pedido
+        let order_id = order_json.id;
+        let mut in_flight = OrderInFlight::new(OrderPrep::from(incoming.order));
+        in_flight.reply = incoming.reply;
+        self.orders.insert(order_id, in_flight);
+
+        // Capturo el pago
+        self.send_payment(ctx, PaymentRequest::Capture(order_id));
+        // Si queda lugar, arranco el siguiente pedido sin esperar a que termine este
+        ctx.address().do_send(ReceiveOrder());
     }
 
     /// If the capture was successful, it broadcasts the order request. If not, it cancels the
@@ -142,11 +372,34 @@ impl Screen {
     fn handle_payment_capture(&mut self, ctx: &mut Context<Self>, msg: String) {
         match from_str::<PaymentCapture>(&msg) {
             Ok(capture) => {
+                self.outbox.ack(capture.order_id, "PaymentCapture");
+                let Some(order) = self.orders.get_mut(&capture.order_id) else {
+                    return;
+                };
+                // Respuesta repetida, por ejemplo de un reenvío al cambiar de gateway
+                if order.payment == PaymentState::Captured {
+                    return;
+                }
                 if capture.valid {
-                    self.broadcast_request(ctx);
+                    order.payment = PaymentState::Captured;
+                    self.tracker
+                        .record(capture.order_id, OrderState::PaymentCaptured);
+                    self.broadcast_request(ctx, capture.order_id);
+                    // Si ningún robot responde, el timer vuelve a enviar el pedido
+                    self.start_order_timer(ctx, capture.order_id);
                 } else {
                     println!(
-                        "\x1b[31m✘\x1b[0m Payment couldn't be captured, order is cancelled.\n"
+                        "\x1b[31m✘\x1b[0m Payment for order {} couldn't be captured, order is cancelled.\n",
+                        capture.order_id
+                    );
+                    self.tracker
+                        .record(capture.order_id, OrderState::PaymentDeclined);
+                    self.outbox.close(capture.order_id);
+                    self.finish_order(
+                        ctx,
+                        capture.order_id,
+                        OrderStatus::Rejected,
+                        "The payment couldn't be captured",
                     );
                     ctx.address()
                         .try_send(ReceiveOrder())
@@ -158,8 +411,9 @@ impl Screen {
         }
     }
 
-    /// If the order was not being processed by another robot, send an OrderPrep message to the
-    /// robot to begin preparation. Else, the message is ignored.
+    /// If the order was not assigned to a robot yet, store the reply as a candidate. The first
+    /// reply opens the selection window, after which a robot is chosen with the selection
+    /// strategy. Else, the message is ignored.
     fn handle_order_request(&mut self, ctx: &mut Context<Self>, msg: String) {
         let request = match from_str::<OrderRequest>(&msg) {
             Ok(request) => request,
@@ -169,28 +423,113 @@ impl Screen {
             }
         };
 
-        let robot_addr: SocketAddr = request
+        if let Some(rtt_ms) = request.ring_rtt_ms {
+            self.timeouts.observe_rtt(rtt_ms);
+        }
+        let order_id = request.order_id;
+        let Some(in_flight) = self.orders.get_mut(&order_id) else {
+            println!(
+                "\x1b[34m[DEBUG]\x1b[0m Ignored {}. Order {} already finished.",
+                request.ip, order_id
+            );
+            return;
+        };
+        // Si ya hay algún robot procesando el pedido, ignoro el mensaje
+        if in_flight.robot.is_some() || in_flight.payment != PaymentState::Captured {
+            println!(
+                "\x1b[34m[DEBUG]\x1b[0m Ignored {}. Order {} already taken.",
+                request.ip, order_id
+            );
+            return;
+        }
+
+        in_flight.candidates.push(request);
+        if in_flight.candidates.len() > 1 {
+            return; // Ya hay una ventana de selección abierta
+        }
+        let window = self.selector.window();
+        if window.is_zero() {
+            self.select_robot(ctx, order_id);
+        } else {
+            let screen_address: Addr<Screen> = ctx.address();
+            wrap_future::<_, Self>(async move {
+                tokio::time::sleep(window).await;
+                let _ = screen_address.send(SelectRobot { id: order_id }).await;
+            })
+            .spawn(ctx);
+        }
+    }
+
+    /// Chooses one of the robots that answered the OrderRequest with the selection strategy, and
+    /// sends it an OrderPrep message to begin preparation.
+    fn select_robot(&mut self, ctx: &mut Context<Self>, order_id: usize) {
+        let Some(in_flight) = self.orders.get_mut(&order_id) else {
+            return;
+        };
+        let candidates = std::mem::take(&mut in_flight.candidates);
+        if candidates.is_empty() || in_flight.robot.is_some() {
+            return;
+        }
+
+        let chosen = &candidates[self.selector.select(&candidates)];
+        let robot_addr: SocketAddr = chosen
             .ip
             .parse()
-            .expect("Couldn't parse SocketAddr at handle_order_request.");
-        let mut order = self.current_order.clone().unwrap();
-        // Si ya hay algún robot procesando el pedido, ignoro el mensaje
-        if !self.order_in_process && self.current_order.is_some() {
-            if let Some((local_ip, write)) = self.robots_write.get(&robot_addr) {
-                order.ip = local_ip.to_string();
-                let msg = serde_json::to_string(&order).unwrap();
+            .expect("Couldn't parse SocketAddr at select_robot.");
+        if let Some((local_ip, write)) = self.robots_write.get(&robot_addr) {
+            let mut order = in_flight.order.clone();
+            order.ip = local_ip.to_string();
+            let msg = serde_json::to_string(&order).unwrap();
+            in_flight.robot = Some(robot_addr);
+            in_flight.queued = chosen.queue_len;
+            self.tracker.record(
+                order_id,
+                OrderState::Assigned {
+                    robot: robot_addr.to_string(),
+                },
+            );
 
-                self.send_message(ctx, msg, write.clone());
+            self.send_message(ctx, msg, write.clone());
+            self.release_candidates(ctx, order_id, &candidates, robot_addr);
 
-                println!("Sent order to robot at {}.\nWaiting...", robot_addr);
-                self.order_in_process = true;
-                self.start_order_timer(ctx);
-            }
-        } else {
             println!(
-                "\x1b[34m[DEBUG]\x1b[0m Ignored {}. Order {} already taken.",
-                robot_addr, order.id
+                "Sent order {} to robot at {} (chosen among {} replies, queue: {}, free capacity: {}).\nWaiting...",
+                order_id,
+                robot_addr,
+                candidates.len(),
+                chosen.queue_len,
+                chosen.capacity
             );
+            self.start_order_timer(ctx, order_id);
+        }
+    }
+
+    /// Sends an OrderRelease message to the robots that answered the OrderRequest but were not
+    /// chosen, so they drop the place they reserved for the order.
+    fn release_candidates(
+        &mut self,
+        ctx: &mut Context<Self>,
+        order_id: usize,
+        candidates: &[OrderRequest],
+        chosen: SocketAddr,
+    ) {
+        for candidate in candidates {
+            let Ok(robot_addr) = candidate.ip.parse::<SocketAddr>() else {
+                continue;
+            };
+            if robot_addr != chosen {
+                self.release_robot(ctx, order_id, robot_addr);
+            }
+        }
+    }
+
+    /// Sends an OrderRelease message to a robot, so it drops its reservation for the order, or
+    /// cancels the order if it had accepted it.
+    fn release_robot(&mut self, ctx: &mut Context<Self>, order_id: usize, robot_addr: SocketAddr) {
+        if let Some((local_ip, write)) = self.robots_write.get(&robot_addr) {
+            let release = OrderRelease::new(local_ip.to_string(), self.id as usize, order_id);
+            let msg = serde_json::to_string(&release).unwrap();
+            self.send_message(ctx, msg, write.clone());
         }
     }
 
@@ -198,7 +537,8 @@ impl Screen {
     /// to the gateway, and continues to the next order.
     /// If the order failed, finished the order, but do not send a payment confirmation and
     /// receive a new order.
-    /// If the robot was occupied, do nothing (this is handled by the timer)
+    /// If the robot was occupied, re-broadcast the request to the robots that didn't refuse it. If
+    /// all of them did, wait for the timer.
     fn handle_order_result(&mut self, ctx: &mut Context<Self>, msg: String) {
         let result = match from_str::<OrderPrep>(&msg) {
             Ok(request) => request,
@@ -208,28 +548,196 @@ impl Screen {
             }
         };
 
-        if result.fail_flag as u8 == ORDER_SUCCESS {
+        if !self.orders.contains_key(&result.id) {
+            println!(
+                "\x1b[34m[DEBUG]\x1b[0m Ignored result of order {}, it was already finished.",
+                result.id
+            );
+            return;
+        }
+
+        if result.fail_flag as u8 == ORDER_ACCEPTED {
+            let robot_addr = result.ip.parse::<SocketAddr>().ok();
+            let Some(order) = self
+                .orders
+                .get_mut(&result.id)
+                .filter(|order| order.robot.is_some() && order.robot == robot_addr)
+            else {
+                // Lo aceptó un robot al que ya no se le asignaba: que no lo prepare
+                if let Some(robot_addr) = robot_addr {
+                    self.release_robot(ctx, result.id, robot_addr);
+                }
+                return;
+            };
+            println!("Order {} is being prepared by {}.", result.id, result.ip);
+            order.preparing = true;
+            self.tracker
+                .record(result.id, OrderState::Preparing { robot: result.ip });
+            self.start_order_timer(ctx, result.id);
+            return;
+        } else if result.fail_flag as u8 == ORDER_SUCCESS {
             println!(
                 "\x1b[32m✔\x1b[0m Order {} completed, sending payment confirmation to gateway.\n",
                 result.id
             );
-            self.finished_orders.push(result.id);
+            self.finish_order(ctx, result.id, OrderStatus::Completed, "Order delivered");
             self.confirm_payment(ctx, result);
         } else if result.fail_flag as u8 == ORDER_FAILED {
-            println!("\x1b[31m✘\x1b[0m Not enough ice cream, order is cancelled.\n");
-            self.finished_orders.push(result.id);
+            println!(
+                "\x1b[31m✘\x1b[0m Not enough ice cream, order {} is cancelled.\n",
+                result.id
+            );
+            self.finish_order(
+                ctx,
+                result.id,
+                OrderStatus::Failed,
+                "Not enough ice cream, the payment was voided",
+            );
+            self.void_payment(ctx, result.id);
         } else if result.fail_flag as u8 == ROBOT_OCCUPIED {
-            println!("Received ROBOT_OCCUPIED.");
-            self.order_in_process = false;
-            // self.broadcast_request(ctx);
-            return; // Espero al timer
-        }
-        // Dejo todo listo para el siguiente pedido
-        self.order_in_process = false;
-        self.current_order = None;
+            println!("Received ROBOT_OCCUPIED for order {}.", result.id);
+            if let Some(order) = self.orders.get_mut(&result.id) {
+                order.robot = None;
+                if let Ok(robot_addr) = result.ip.parse() {
+                    order.refused_by.insert(robot_addr);
+                }
+            }
+            if self.broadcast_request(ctx, result.id) == 0 {
+                println!("All robots refused order {}, waiting for timer.", result.id);
+            }
+            return;
+        }
         // Arranco el siguiente pedido
         ctx.address().do_send(ReceiveOrder());
     }
+
+    /// If the Screen is disconnecting, a Disconnect from the gateway or a robot acknowledges its
+    /// own. Once everyone answered, the Screen exits.
+    /// Otherwise, a robot is leaving the ring and no more requests are sent to it.
+    fn handle_disconnect(&mut self, peer: Peer, msg: String) {
+        let disconnect = match from_str::<Disconnect>(&msg) {
+            Ok(disconnect) => disconnect,
+            Err(e) => {
+                perror("", Some(Box::new(e)));
+                return;
+            }
+        };
+
+        let Some(pending_acks) = self.pending_acks.as_mut() else {
+            if let Peer::Robot(robot_addr) = peer {
+                if self.robots_write.remove(&robot_addr).is_some() {
+                    println!("[Disconnect] Robot at {} left the ring.", robot_addr);
+                    return;
+                }
+            }
+            println!("[Disconnect] {} has disconnected.", disconnect.ip);
+            return;
+        };
+        if pending_acks.remove(&peer) {
+            println!(
+                "[Disconnect] {} acknowledged the disconnection ({} left).",
+                disconnect.ip,
+                pending_acks.len()
+            );
+        }
+        if pending_acks.is_empty() {
+            self.exit();
+        }
+    }
+
+    /// The gateway stored a confirmed or voided payment, so it isn't sent again.
+    fn handle_payment_ack(&mut self, msg: String) {
+        match from_str::<PaymentAck>(&msg) {
+            Ok(ack) => self.outbox.ack(ack.order_id, &ack.payment),
+            Err(e) => perror("", Some(Box::new(e))),
+        }
+    }
+
+    /// The connection with a robot or the gateway ended. A lost robot gets no more requests, and
+    /// both are reconnected in the background, unless the Screen is shutting down.
+    fn handle_connection_lost(&mut self, ctx: &mut Context<Self>, peer: Peer) {
+        if self.draining {
+            return;
+        }
+        if let Peer::Robot(robot_addr) = peer {
+            self.robots_write.remove(&robot_addr);
+        }
+        println!(
+            "\x1b[33m[Connection]\x1b[0m Lost the connection with the {}, reconnecting.",
+            peer
+        );
+        spawn_reconnect(ctx.address(), peer, self.id);
+    }
+
+    /// Forwards a system shutdown to the gateway and every robot, and starts draining.
+    fn handle_system_shutdown(&mut self, ctx: &mut Context<Self>, msg: String) {
+        if self.draining {
+            return;
+        }
+        match from_str::<SystemShutdown>(&msg) {
+            Ok(shutdown) => println!(
+                "\x1b[33m[Shutdown]\x1b[0m {} asked to shut down the system.",
+                shutdown.id
+            ),
+            Err(e) => perror("", Some(Box::new(e))),
+        }
+
+        let local_ip = self.gateway_write.0.to_string();
+        let shutdown = SystemShutdown::new(local_ip, self.id.to_string());
+        let msg = serde_json::to_string(&shutdown).unwrap();
+        let stream_arc = self.gateway_write.1.clone();
+        self.send_message(ctx, msg, stream_arc);
+
+        let robots_write = self.robots_write.clone();
+        for (local_ip, write) in robots_write.into_values() {
+            let shutdown = SystemShutdown::new(local_ip.to_string(), self.id.to_string());
+            let msg = serde_json::to_string(&shutdown).unwrap();
+            self.send_message(ctx, msg, write);
+        }
+        self.start_drain(ctx);
+    }
+
+    /// Updates the local availability view with the stock level informed by a robot.
+    fn handle_stock_update(&mut self, msg: String) {
+        let update = match from_str::<StockUpdate>(&msg) {
+            Ok(update) => update,
+            Err(e) => {
+                perror("", Some(Box::new(e)));
+                return;
+            }
+        };
+
+        if update.level == STOCK_SOLD_OUT {
+            println!("\x1b[33m[Stock]\x1b[0m {} is sold out.", update.flavour);
+        } else if update.level == STOCK_LOW {
+            println!(
+                "\x1b[33m[Stock]\x1b[0m {} is running low ({} left).",
+                update.flavour, update.amount
+            );
+        } else {
+            println!("\x1b[33m[Stock]\x1b[0m {} is available.", update.flavour);
+        }
+        self.stock.insert(update.flavour, update.level);
+    }
+
+    /// Prints the inventory informed by a robot.
+    fn handle_inventory_report(&mut self, msg: String) {
+        match from_str::<InventoryReport>(&msg) {
+            Ok(report) => {
+                println!(
+                    "\x1b[33m[Inventory]\x1b[0m Reported by robot {}:",
+                    report.id
+                );
+                for flavour in report.flavours {
+                    println!(
+                        "  {}: {} (epoch {})",
+                        flavour.name, flavour.amount, flavour.epoch
+                    );
+                }
+            }
+            Err(e) => perror("", Some(Box::new(e))),
+        }
+    }
 }
 
 impl Actor for Screen {
@@ -237,16 +745,38 @@ impl Actor for Screen {
 }
 
 // Messages --------------------------------------------------------------------
-impl StreamHandler<Result<String, Error>> for Screen {
-    fn handle(&mut self, msg: Result<String, Error>, ctx: &mut Self::Context) {
+impl StreamHandler<PeerLine> for Screen {
+    fn handle(&mut self, msg: PeerLine, ctx: &mut Self::Context) {
+        let peer = msg.peer;
+        let Some(msg) = msg.line else {
+            self.handle_connection_lost(ctx, peer);
+            return;
+        };
         match msg {
             Ok(msg) => {
-                if msg.contains("\"PaymentCapture\"") {
+                if let Some(sent_at) = self.clock.receive(&msg) {
+                    println!(
+                        "\x1b[34m[Clock]\x1b[0m Received message sent at {}, clock {}",
+                        sent_at,
+                        self.clock.now()
+                    );
+                }
+                if msg.contains("\"PaymentAck\"") {
+                    self.handle_payment_ack(msg);
+                } else if msg.contains("\"PaymentCapture\"") {
                     self.handle_payment_capture(ctx, msg);
                 } else if msg.contains("\"OrderRequest\"") {
                     self.handle_order_request(ctx, msg);
                 } else if msg.contains("\"OrderPrep\"") {
                     self.handle_order_result(ctx, msg);
+                } else if msg.contains("\"InventoryReport\"") {
+                    self.handle_inventory_report(msg);
+                } else if msg.contains("\"StockUpdate\"") {
+                    self.handle_stock_update(msg);
+                } else if msg.contains("\"SystemShutdown\"") {
+                    self.handle_system_shutdown(ctx, msg);
+                } else if msg.contains("\"Disconnect\"") {
+                    self.handle_disconnect(peer, msg);
                 } else {
                     perror(
                         format!("Received unknown message at StreamHandler: {}", msg).as_str(),
@@ -255,30 +785,78 @@ impl StreamHandler<Result<String, Error>> for Screen {
                 }
             }
 
+            // El par salió sin cerrar la sesión TLS: la conexión se cortó
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => perror(
+                format!("The connection with {:?} was cut", peer).as_str(),
+                Some(Box::new(e)),
+            ),
             Err(e) => perror(
                 "Received invalid message at StreamHandler",
                 Some(Box::new(e)),
             ),
         }
     }
+
+    /// A connection was closed. The end marker of each stream already told which peer was lost,
+    /// so the Screen keeps running with the other connections.
+    fn finished(&mut self, _ctx: &mut Self::Context) {
+        println!(
+            "\x1b[33m[Connection]\x1b[0m A connection was closed, {} robot(s) still reachable.",
+            self.robots_write.len()
+        );
+    }
 }
 
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct ReceiveOrder();
 
-/// Makes the Screen read a line from the orders file and process it.
+/// Makes the Screen take the next order from its source, by priority, and process it, if it has
+/// less than `max_orders` orders in process. While draining, it disconnects once the last order
+/// in process finishes.
 impl Handler<ReceiveOrder> for Screen {
     type Result = ();
 
     fn handle(&mut self, _msg: ReceiveOrder, ctx: &mut Context<Self>) -> Self::Result {
-        let mut line = String::new();
-        match self.reader.read_line(&mut line) {
-            Ok(0) => println!("No more orders left. Waiting for shutdown."),
+        if self.draining {
+            if self.orders.is_empty() {
+                self.disconnect_all(ctx);
+            }
+            return;
+        }
+        if self.orders.len() >= self.max_orders {
+            return;
+        }
+        match self.queue.next_order() {
+            Some(incoming) => self.handle_new_order(ctx, incoming),
+
+            None if self.queue.is_exhausted() => {
+                println!("No more orders left. Waiting for shutdown.")
+            }
+
+            None => {} // La fuente avisa cuando llega otro pedido
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct QueryInventory();
 
-            Ok(_) => self.handle_new_order(ctx, line),
+/// Asks one of the robots for the inventory of every flavour. The report arrives as an
+/// InventoryReport message.
+impl Handler<QueryInventory> for Screen {
+    type Result = ();
 
-            Err(e) => eprintln!("Error reading order : {}", e),
+    fn handle(&mut self, _msg: QueryInventory, ctx: &mut Context<Self>) -> Self::Result {
+        let robot = self.robots_write.values().next().cloned();
+        match robot {
+            Some((local_ip, write)) => {
+                let query = InventoryQuery::new(local_ip.to_string(), self.id as usize);
+                let msg = serde_json::to_string(&query).unwrap();
+                self.send_message(ctx, msg, write);
+            }
+            None => perror("No robots connected to query the inventory.", None),
         }
     }
 }
@@ -287,20 +865,82 @@ impl Handler<ReceiveOrder> for Screen {
 #[rtype(result = "()")]
 pub struct Shutdown();
 
-/// Finishes execution in an ordered manner, sending a Disconnect message to the gateway.
+/// Finishes execution in an ordered manner. See `Screen::start_drain`.
 impl Handler<Shutdown> for Screen {
     type Result = ();
 
     fn handle(&mut self, _msg: Shutdown, ctx: &mut Context<Self>) -> Self::Result {
-        // Envío disconnect al gateway
-        println!("Disconnecting from gateway.");
-        let local_ip = self.gateway_write.0.to_string();
-        let disconnect = Disconnect::new(local_ip, self.id.to_string());
-        let msg = serde_json::to_string(&disconnect).unwrap();
-        let stream_arc = self.gateway_write.1.clone();
-        self.send_message(ctx, msg, stream_arc);
-        // TODO: envío disconnect a los robots (todavía no handlean el mensaje disconnect)
-        // ctx.stop();
+        self.start_drain(ctx);
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct DrainDeadline();
+
+/// Cancels the orders that didn't finish while draining, voiding their payments, and disconnects.
+impl Handler<DrainDeadline> for Screen {
+    type Result = ();
+
+    fn handle(&mut self, _msg: DrainDeadline, ctx: &mut Context<Self>) -> Self::Result {
+        if self.pending_acks.is_some() {
+            return;
+        }
+        let ids: Vec<usize> = self.orders.keys().copied().collect();
+        for id in ids {
+            println!(
+                "\x1b[31m✘\x1b[0m Order {} didn't finish before shutdown, order is cancelled and its payment voided.",
+                id
+            );
+            self.finish_order(
+                ctx,
+                id,
+                OrderStatus::Failed,
+                "The screen shut down before the order finished, the payment was voided",
+            );
+            self.void_payment(ctx, id);
+        }
+        self.disconnect_all(ctx);
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct AckDeadline();
+
+/// Exits even if some robot or the gateway didn't acknowledge the Disconnect.
+impl Handler<AckDeadline> for Screen {
+    type Result = ();
+
+    fn handle(&mut self, _msg: AckDeadline, _ctx: &mut Context<Self>) -> Self::Result {
+        if let Some(pending_acks) = &self.pending_acks {
+            if !pending_acks.is_empty() {
+                perror(
+                    format!(
+                        "No acknowledgement from {:?}, exiting anyway.",
+                        pending_acks
+                    )
+                    .as_str(),
+                    None,
+                );
+            }
+        }
+        self.exit();
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SelectRobot {
+    id: usize,
+}
+
+/// Closes the selection window of an order and chooses the robot that prepares it.
+impl Handler<SelectRobot> for Screen {
+    type Result = ();
+
+    fn handle(&mut self, msg: SelectRobot, ctx: &mut Context<Self>) -> Self::Result {
+        self.select_robot(ctx, msg.id);
     }
 }
 
@@ -311,16 +951,110 @@ pub struct ReBroadcastOrder {
 }
 
 // Tengo que hacer esta movida para tener acceso a self cuando se dispara el timeout
+/// Retries an order whose timer expired. The robot it was sent to, if any, is told to cancel it
+/// first. After `MAX_ATTEMPTS` attempts the order fails and its payment is voided.
 impl Handler<ReBroadcastOrder> for Screen {
     type Result = ();
 
     fn handle(&mut self, msg: ReBroadcastOrder, ctx: &mut Context<Self>) -> Self::Result {
-        if !self.finished_orders.contains(&msg.id) {
-            println!("\x1b[31m[Timeout]\x1b[0m Re-broadcasting order {}.", msg.id);
-            self.order_in_process = false;
-            self.broadcast_request(ctx);
+        if let Some(order) = self.orders.get_mut(&msg.id) {
+            order.timer = None;
+            order.attempts += 1;
+            order.preparing = false;
+            let attempt = order.attempts;
+            if let Some(robot_addr) = order.robot.take() {
+                self.release_robot(ctx, msg.id, robot_addr);
+            }
+            self.tracker
+                .record(msg.id, OrderState::TimedOut { attempt });
+            let Some(order) = self.orders.get_mut(&msg.id) else {
+                return;
+            };
+            if order.attempts >= MAX_ATTEMPTS {
+                println!(
+                    "\x1b[31m✘\x1b[0m Order {} timed out {} times, order is cancelled and its payment voided.\n",
+                    msg.id, order.attempts
+                );
+                self.finish_order(
+                    ctx,
+                    msg.id,
+                    OrderStatus::Failed,
+                    "No robot could prepare the order, the payment was voided",
+                );
+                self.void_payment(ctx, msg.id);
+                ctx.address().do_send(ReceiveOrder());
+                return;
+            }
+            println!(
+                "\x1b[31m[Timeout]\x1b[0m Re-broadcasting order {} (attempt {} of {}).",
+                msg.id,
+                order.attempts + 1,
+                MAX_ATTEMPTS
+            );
+            order.robot = None;
+            order.candidates.clear();
+            order.refused_by.clear();
+            self.broadcast_request(ctx, msg.id);
+            self.start_order_timer(ctx, msg.id);
         } else {
             println!("\x1b[34m[DEBUG]\x1b[0m Iba a hacer un re-broadcast de la orden {} pero se completó el pedido antes.", msg.id);
         }
     }
 }
+
+#[derive(Message)]
+#[rtype(result = "Vec<OrderHistory>")]
+pub struct QueryStatus {
+    pub id: Option<usize>,
+}
+
+/// Returns the history of one order, or of every order the Screen received.
+impl Handler<QueryStatus> for Screen {
+    type Result = MessageResult<QueryStatus>;
+
+    fn handle(&mut self, msg: QueryStatus, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.tracker.query(msg.id))
+    }
+}
+
+/// A new connection with a robot or the gateway, made by the connection manager.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PeerConnected {
+    pub peer: Peer,
+    pub stream: FramedStream<Transport>,
+}
+
+/// Starts reading from the new connection and writes to it from now on.
+impl Handler<PeerConnected> for Screen {
+    type Result = ();
+
+    fn handle(&mut self, msg: PeerConnected, ctx: &mut Context<Self>) -> Self::Result {
+        if self.draining {
+            return;
+        }
+        let write = set_peer_stream(msg.peer, msg.stream, ctx);
+        println!("\x1b[32m[Connection]\x1b[0m Connected to the {}.", msg.peer);
+        match msg.peer {
+            Peer::Robot(robot_addr) => {
+                self.robots_write.insert(robot_addr, write);
+            }
+            Peer::Gateway => {
+                self.gateway_write = write;
+                // Reenvío los pagos sin respuesta: el gateway no repite los que ya procesó
+                for request in self.outbox.pending() {
+                    println!(
+                        "Sending again the {} of order {} to the gateway.",
+                        request.kind(),
+                        request.order_id()
+                    );
+                    let payment_id = self.outbox.payment_id(request.order_id());
+                    let msg =
+                        request.to_message(self.gateway_write.0.to_string(), self.id, payment_id);
+                    let stream_arc = self.gateway_write.1.clone();
+                    self.send_message(ctx, msg, stream_arc);
+                }
+            }
+        }
+    }
+}
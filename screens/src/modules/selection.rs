@@ -0,0 +1,146 @@
+use std::{cmp::Reverse, net::SocketAddr, time::Duration};
+
+use common::modules::order_request::OrderRequest;
+
+/// Tiempo durante el cual se juntan respuestas de robots antes de elegir uno
+const SELECTION_WINDOW: Duration = Duration::from_millis(500);
+
+/// Strategy used by the Screen to choose which robot prepares an order, among the robots that
+/// answered its OrderRequest.
+pub trait RobotSelector {
+    /// How long the Screen waits for replies before choosing a robot.
+    fn window(&self) -> Duration {
+        SELECTION_WINDOW
+    }
+
+    /// Returns the index of the chosen reply. `replies` is never empty and is in arrival order.
+    fn select(&mut self, replies: &[OrderRequest]) -> usize;
+}
+
+/// Chooses the first robot that answered. It doesn't wait for other replies.
+pub struct FirstReply;
+
+impl RobotSelector for FirstReply {
+    fn window(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    fn select(&mut self, _replies: &[OrderRequest]) -> usize {
+        0
+    }
+}
+
+/// Chooses the robot with the fewest queued orders. Ties are broken by the amount of flavours
+/// it is waiting on and then by its free capacity.
+pub struct LeastLoaded;
+
+impl RobotSelector for LeastLoaded {
+    fn select(&mut self, replies: &[OrderRequest]) -> usize {
+        replies
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, reply)| {
+                (
+                    reply.queue_len,
+                    reply.waiting_flavours.len(),
+                    Reverse(reply.capacity),
+                )
+            })
+            .map(|(index, _)| index)
+            .unwrap_or(0)
+    }
+}
+
+/// Chooses the robots in turns, by address, skipping the ones that didn't answer.
+#[derive(Default)]
+pub struct RoundRobin {
+    last: Option<SocketAddr>,
+}
+
+impl RobotSelector for RoundRobin {
+    fn select(&mut self, replies: &[OrderRequest]) -> usize {
+        let mut robots: Vec<(usize, SocketAddr)> = replies
+            .iter()
+            .enumerate()
+            .filter_map(|(index, reply)| reply.ip.parse().ok().map(|addr| (index, addr)))
+            .collect();
+        robots.sort_by_key(|(_, addr)| *addr);
+
+        let next = robots
+            .iter()
+            .find(|(_, addr)| Some(*addr) > self.last)
+            .or(robots.first())
+            .copied();
+        match next {
+            Some((index, addr)) => {
+                self.last = Some(addr);
+                index
+            }
+            None => 0,
+        }
+    }
+}
+
+/// Builds the strategy with the given name: "first-reply", "least-loaded" or "round-robin".
+pub fn selector_from_name(name: &str) -> Option<Box<dyn RobotSelector>> {
+    match name {
+        "first-reply" => Some(Box::new(FirstReply)),
+        "least-loaded" => Some(Box::new(LeastLoaded)),
+        "round-robin" => Some(Box::new(RoundRobin::default())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(port: u16, queue_len: usize, waiting: usize, capacity: usize) -> OrderRequest {
+        OrderRequest {
+            queue_len,
+            waiting_flavours: vec!["Vainilla".to_string(); waiting],
+            capacity,
+            ..OrderRequest::new(format!("127.0.0.1:{}", port), 0, 1)
+        }
+    }
+
+    #[test]
+    fn first_reply_doesnt_wait() {
+        let mut selector = FirstReply;
+        assert_eq!(selector.window(), Duration::ZERO);
+        assert_eq!(selector.select(&[reply(1, 5, 0, 0), reply(2, 0, 0, 0)]), 0);
+    }
+
+    #[test]
+    fn least_loaded_breaks_ties_by_waiting_flavours_and_capacity() {
+        let mut selector = LeastLoaded;
+        assert_eq!(selector.select(&[reply(1, 2, 0, 5), reply(2, 1, 3, 0)]), 1);
+        assert_eq!(selector.select(&[reply(1, 1, 2, 5), reply(2, 1, 1, 0)]), 1);
+        assert_eq!(selector.select(&[reply(1, 1, 1, 2), reply(2, 1, 1, 5)]), 1);
+    }
+
+    #[test]
+    fn round_robin_takes_turns_by_address() {
+        let mut selector = RoundRobin::default();
+        let replies = [reply(3, 0, 0, 0), reply(1, 0, 0, 0), reply(2, 0, 0, 0)];
+        let chosen: Vec<_> = (0..4).map(|_| selector.select(&replies)).collect();
+        assert_eq!(chosen, vec![1, 2, 0, 1]);
+    }
+
+    #[test]
+    fn round_robin_skips_the_robots_that_didnt_answer() {
+        let mut selector = RoundRobin::default();
+        assert_eq!(selector.select(&[reply(1, 0, 0, 0), reply(2, 0, 0, 0)]), 0);
+        // El robot 2 no respondió: le toca al 3
+        assert_eq!(selector.select(&[reply(1, 0, 0, 0), reply(3, 0, 0, 0)]), 1);
+        assert_eq!(selector.select(&[reply(1, 0, 0, 0), reply(3, 0, 0, 0)]), 0);
+    }
+
+    #[test]
+    fn selectors_are_built_by_name() {
+        assert!(selector_from_name("least-loaded").is_some());
+        assert!(selector_from_name("round-robin").is_some());
+        assert!(selector_from_name("first-reply").is_some());
+        assert!(selector_from_name("random").is_none());
+    }
+}
@@ -0,0 +1,139 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Tiempo mínimo de espera de un pedido, sin contar la preparación
+const BASE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Tiempo de preparación estimado por unidad de tamaño del pedido
+const TIME_PER_UNIT: Duration = Duration::from_secs(2);
+/// Vueltas al anillo que se esperan, para que lleguen los tokens de todos los gustos
+const RING_ROUNDS: u32 = 2;
+/// Vuelta al anillo que se asume hasta que algún robot informe una
+const DEFAULT_RING_RTT: Duration = Duration::from_secs(10);
+/// Peso de cada nueva medición en el promedio móvil de la vuelta al anillo
+const RTT_WEIGHT: f64 = 0.25;
+
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Amount of attempts after which an order fails.
+pub const MAX_ATTEMPTS: u32 = 5;
+/// Maximum time a shutting down Screen waits for its orders in process to finish.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(60);
+/// Maximum time a shutting down Screen waits for the robots and the gateway to acknowledge its
+/// Disconnect. Robots take 2 seconds per message, so it leaves some slack.
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Computes how long the Screen waits for an order before retrying it, from the size of the
+/// order and the ring round-trip time reported by the robots.
+pub struct OrderTimeouts {
+    ring_rtt: Option<Duration>,
+}
+
+impl OrderTimeouts {
+    pub fn new() -> Self {
+        OrderTimeouts { ring_rtt: None }
+    }
+
+    /// Adds a ring round-trip time reported by a robot to the moving average.
+    pub fn observe_rtt(&mut self, rtt_ms: u64) {
+        let sample = Duration::from_millis(rtt_ms);
+        self.ring_rtt = Some(match self.ring_rtt {
+            Some(average) => average.mul_f64(1.0 - RTT_WEIGHT) + sample.mul_f64(RTT_WEIGHT),
+            None => sample,
+        });
+    }
+
+    /// Timeout for a single attempt of an order of the given size.
+    pub fn order_timeout(&self, size: usize) -> Duration {
+        let ring_rtt = self.ring_rtt.unwrap_or(DEFAULT_RING_RTT);
+        BASE_TIMEOUT + TIME_PER_UNIT * size as u32 + ring_rtt * RING_ROUNDS
+    }
+
+    /// Deadline for a robot that accepted an order of the given size to prepare it, when it had
+    /// `queued` orders ahead of it.
+    pub fn preparation_timeout(&self, size: usize, queued: usize) -> Duration {
+        self.order_timeout(size) * (queued as u32 + 1)
+    }
+
+    /// Extra wait before the given retry of an order.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        backoff(attempt)
+    }
+}
+
+/// Extra wait before the given retry. It doubles on every attempt, up to a maximum, and adds a
+/// random jitter of up to half the delay so the screens don't retry at the same time.
+pub fn backoff(attempt: u32) -> Duration {
+    if attempt == 0 {
+        return Duration::ZERO;
+    }
+    let delay = BACKOFF_BASE
+        .saturating_mul(2u32.saturating_pow(attempt - 1))
+        .min(MAX_BACKOFF);
+    let jitter = rand::thread_rng().gen_range(0.0..=0.5);
+    delay + delay.mul_f64(jitter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_between(delay: Duration, min: Duration, max: Duration) {
+        assert!(
+            min <= delay && delay <= max,
+            "{:?} not in {:?}..={:?}",
+            delay,
+            min,
+            max
+        );
+    }
+
+    #[test]
+    fn first_attempt_doesnt_wait() {
+        assert_eq!(backoff(0), Duration::ZERO);
+    }
+
+    #[test]
+    fn backoff_doubles_with_up_to_half_of_jitter() {
+        for attempt in 1..=4 {
+            let delay = BACKOFF_BASE * 2u32.pow(attempt - 1);
+            assert_between(backoff(attempt), delay, delay.mul_f64(1.5));
+        }
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        for attempt in [6, 20, u32::MAX] {
+            assert_between(backoff(attempt), MAX_BACKOFF, MAX_BACKOFF.mul_f64(1.5));
+        }
+    }
+
+    #[test]
+    fn order_timeout_follows_the_ring_round_trip() {
+        let mut timeouts = OrderTimeouts::new();
+        assert_eq!(
+            timeouts.order_timeout(2),
+            BASE_TIMEOUT + TIME_PER_UNIT * 2 + DEFAULT_RING_RTT * RING_ROUNDS
+        );
+        timeouts.observe_rtt(2000);
+        timeouts.observe_rtt(6000);
+        // 2 s y luego 6 s con peso 0.25: 3 s
+        assert_eq!(
+            timeouts.order_timeout(0),
+            BASE_TIMEOUT + Duration::from_secs(3) * RING_ROUNDS
+        );
+    }
+
+    #[test]
+    fn preparation_timeout_covers_the_orders_queued_before() {
+        let timeouts = OrderTimeouts::new();
+        assert_eq!(
+            timeouts.preparation_timeout(3, 0),
+            timeouts.order_timeout(3)
+        );
+        assert_eq!(
+            timeouts.preparation_timeout(3, 2),
+            timeouts.order_timeout(3) * 3
+        );
+    }
+}
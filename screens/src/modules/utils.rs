@@ -1,6 +1,9 @@
 use std::error::Error;
 
-use tokio::io::{AsyncBufReadExt, BufReader};
+use actix::Addr;
+use tokio::sync::mpsc;
+
+use crate::modules::screen::{QueryInventory, QueryStatus, Screen};
 
 /// Prints an error message on stderr with the prefix [Error] in red.
 /// `message` is a custom message, if "" is given, only e is printed
@@ -20,14 +23,38 @@ pub fn perror(message: &str, e: Option<Box<dyn Error>>) {
     }
 }
 
-/// Awaits for user input until 'q' is pressed
-pub async fn listen_user_input() {
-    let mut async_stdin = BufReader::new(tokio::io::stdin());
-    loop {
-        let mut input = String::new();
-        async_stdin.read_line(&mut input).await.unwrap();
-        if input.trim() == "q" {
-            break;
+/// Awaits for user commands until 'q' is pressed. 'inventory' asks the robots for the stock of
+/// every flavour, and 'status [order id]' prints the history of one order or of all of them.
+pub async fn listen_user_input(
+    screen: &Addr<Screen>,
+    mut commands: mpsc::UnboundedReceiver<String>,
+) {
+    while let Some(input) = commands.recv().await {
+        let mut words = input.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("q"), None) => break,
+            (Some("inventory"), None) => screen.do_send(QueryInventory()),
+            (Some("status"), id) => match id.map(str::parse).transpose() {
+                Ok(id) => print_status(screen, id).await,
+                Err(_) => println!("Order id must be a number. Usage: status [order id]"),
+            },
+            _ => println!("Unknown command. Usage: inventory | status [order id] | q"),
+        }
+    }
+}
+
+/// Prints the history of one order, or of every order if `id` is None.
+async fn print_status(screen: &Addr<Screen>, id: Option<usize>) {
+    match screen.send(QueryStatus { id }).await {
+        Ok(histories) if histories.is_empty() => match id {
+            Some(id) => println!("Order {} wasn't received.", id),
+            None => println!("No orders received yet."),
+        },
+        Ok(histories) => {
+            for history in histories {
+                print!("{}", history);
+            }
         }
+        Err(e) => perror("Couldn't query the status of the orders", Some(Box::new(e))),
     }
 }